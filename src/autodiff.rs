@@ -0,0 +1,418 @@
+use std::f32::consts::TAU;
+
+use smallvec::SmallVec;
+
+use crate::{
+    eval::{apply, call, PROTECTED_EPSILON},
+    expr::{Expr, Program, MAX_ARITY},
+    inputs::{set_derived_inputs, FIRST_FEATURE, X, Y},
+    myimage::GrayScaleImage,
+    pixapprox::sampled_features,
+    state::State,
+    tune::constants,
+};
+
+/// Marks an unused operand slot in the tape
+const NO_NODE: usize = usize::MAX;
+
+/// Reverse-mode automatic differentiation of programs.
+///
+/// Every instruction that pushes a value is a node on the tape,
+/// identified by its index in the code. Dup pushes the node of its
+/// operand twice so both uses add to the same adjoint.
+pub struct Gradient {
+    /// For every node: the nodes it was computed from and the partial derivatives
+    partials: Vec<[(usize, f32); MAX_ARITY]>,
+    /// For every node: d(output) / d(node value)
+    adjoints: Vec<f32>,
+    /// The evaluation stack holding (value, node)
+    stack: Vec<(f32, usize)>,
+}
+
+impl Gradient {
+    pub fn new() -> Self {
+        Self {
+            partials: Vec::new(),
+            adjoints: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    /// Evaluates the program like eval does and computes the derivative of
+    /// the output with respect to the value pushed by each instruction.
+    /// Max and Min pass the whole gradient on to the selected operand.
+    pub fn eval(&mut self, prg: &Program, state: &State) -> f32 {
+        let n = prg.code.len();
+        self.partials.clear();
+        self.partials.resize(n, [(NO_NODE, 0.0); MAX_ARITY]);
+        self.stack.clear();
+
+        for (i, expr) in prg.code.iter().enumerate() {
+            match *expr {
+                Expr::Const(x) => self.stack.push((x, i)),
+                Expr::Var(v) => self.stack.push((state.vars[v], i)),
+                Expr::ConstRef(c) => self.stack.push((prg.consts[c], i)),
+                Expr::Add => {
+                    let (a, ia) = self.pop();
+                    let (b, ib) = self.pop();
+                    self.push(i, a + b, &[(ia, 1.0), (ib, 1.0)]);
+                }
+                Expr::Sub => {
+                    let (a, ia) = self.pop();
+                    let (b, ib) = self.pop();
+                    self.push(i, b - a, &[(ia, -1.0), (ib, 1.0)]);
+                }
+                Expr::Mul => {
+                    let (a, ia) = self.pop();
+                    let (b, ib) = self.pop();
+                    self.push(i, a * b, &[(ia, b), (ib, a)]);
+                }
+                Expr::Max => {
+                    let (a, ia) = self.pop();
+                    let (b, ib) = self.pop();
+                    let (da, db) = if a >= b { (1.0, 0.0) } else { (0.0, 1.0) };
+                    self.push(i, a.max(b), &[(ia, da), (ib, db)]);
+                }
+                Expr::Min => {
+                    let (a, ia) = self.pop();
+                    let (b, ib) = self.pop();
+                    let (da, db) = if a <= b { (1.0, 0.0) } else { (0.0, 1.0) };
+                    self.push(i, a.min(b), &[(ia, da), (ib, db)]);
+                }
+                Expr::Cos => {
+                    let (a, ia) = self.pop();
+                    let a = a * TAU;
+                    self.push(i, a.cos(), &[(ia, -TAU * a.sin())]);
+                }
+                Expr::Sin => {
+                    let (a, ia) = self.pop();
+                    let a = a * TAU;
+                    self.push(i, a.sin(), &[(ia, TAU * a.cos())]);
+                }
+                Expr::Atan => {
+                    let (a, ia) = self.pop();
+                    self.push(i, a.atan(), &[(ia, 1.0 / (1.0 + a * a))]);
+                }
+                Expr::Drop => {
+                    panic!("Drop not done");
+                }
+                Expr::Dup => {
+                    let top = self.pop();
+                    self.stack.push(top);
+                    self.stack.push(top);
+                }
+                op => {
+                    let arity = op.arity();
+                    let mut args = [0.0; MAX_ARITY];
+                    let mut nodes = [NO_NODE; MAX_ARITY];
+                    for k in (0..arity).rev() {
+                        (args[k], nodes[k]) = self.pop();
+                    }
+                    let (value, partials) = match op {
+                        Expr::Call(function, _) => {
+                            let function = &prg.functions[function as usize];
+                            let f = |args: &[f32; MAX_ARITY]| call(function, &args[..arity]);
+                            (f(&args), numeric_derivatives(arity, &args, f))
+                        }
+                        op => (apply(op, &args), derivatives(op, &args)),
+                    };
+                    let pairs: SmallVec<[(usize, f32); MAX_ARITY]> =
+                        nodes.into_iter().zip(partials).collect();
+                    self.push(i, value, &pairs);
+                }
+            }
+        }
+
+        assert!(
+            self.stack.len() == 1,
+            "Stack should contain exactly 1 item but had {} items",
+            self.stack.len()
+        );
+        let (value, output) = self.pop();
+
+        // Nodes only depend on nodes with a lower index,
+        // so a single backwards sweep propagates everything
+        self.adjoints.clear();
+        self.adjoints.resize(n, 0.0);
+        self.adjoints[output] = 1.0;
+        for i in (0..n).rev() {
+            let adjoint = self.adjoints[i];
+            if adjoint == 0.0 {
+                continue;
+            }
+            for (node, partial) in self.partials[i] {
+                if node != NO_NODE {
+                    self.adjoints[node] += adjoint * partial;
+                }
+            }
+        }
+
+        value
+    }
+
+    /// d(output) / d(value pushed by instruction i), valid after eval
+    pub fn adjoint(&self, i: usize) -> f32 {
+        self.adjoints[i]
+    }
+
+    /// Adds `factor` times the derivative of the output with respect to
+    /// each constant, in the order of tune::constants, to `gradient`.
+    /// A pooled constant gets the sum over the instructions referring to it.
+    pub fn add_constants_gradient(&self, prg: &Program, factor: f32, gradient: &mut [f32]) {
+        let pool_start = gradient.len() - prg.consts.len();
+        let mut k = 0;
+
+        for (i, expr) in prg.code.iter().enumerate() {
+            match *expr {
+                Expr::Const(_) => {
+                    gradient[k] += factor * self.adjoints[i];
+                    k += 1;
+                }
+                Expr::ConstRef(c) => gradient[pool_start + c] += factor * self.adjoints[i],
+                _ => {}
+            }
+        }
+    }
+
+    fn pop(&mut self) -> (f32, usize) {
+        self.stack.pop().expect("Stack underflow")
+    }
+
+    fn push(&mut self, i: usize, value: f32, partials: &[(usize, f32)]) {
+        self.partials[i][..partials.len()].copy_from_slice(partials);
+        self.stack.push((value, i));
+    }
+}
+
+/// The partial derivatives of apply(op, args) with respect to each argument.
+/// Protected cases, and the steps of Step, Floor and Fract, count as flat.
+fn derivatives(op: Expr, args: &[f32; MAX_ARITY]) -> SmallVec<[f32; MAX_ARITY]> {
+    let [a, b, ..] = *args;
+    match op {
+        Expr::Div if b.abs() < PROTECTED_EPSILON => smallvec![0.0, 0.0],
+        Expr::Div => smallvec![1.0 / b, -a / (b * b)],
+        Expr::Mod if b.abs() < PROTECTED_EPSILON => smallvec![0.0, 0.0],
+        Expr::Mod => smallvec![1.0, -(a / b).floor()],
+        Expr::Atan2 if a == 0.0 && b == 0.0 => smallvec![0.0, 0.0],
+        Expr::Atan2 => {
+            let square = a * a + b * b;
+            smallvec![b / square, -a / square]
+        }
+        Expr::Abs => smallvec![a.signum()],
+        Expr::Sqrt if a == 0.0 => smallvec![0.0],
+        Expr::Sqrt => smallvec![a.signum() / (2.0 * a.abs().sqrt())],
+        Expr::Exp => smallvec![a.exp()],
+        Expr::Log if a.abs() < PROTECTED_EPSILON => smallvec![0.0],
+        Expr::Log => smallvec![1.0 / a],
+        Expr::Tanh => smallvec![1.0 - a.tanh() * a.tanh()],
+        Expr::Step | Expr::Floor => smallvec![0.0],
+        Expr::Fract => smallvec![1.0],
+        Expr::Select if a > 0.0 => smallvec![0.0, 1.0, 0.0],
+        Expr::Select => smallvec![0.0, 0.0, 1.0],
+        op if op.is_shape() || op.is_noise() => {
+            numeric_derivatives(op.arity(), args, |args| apply(op, args))
+        }
+        _ => panic!("derivatives: {} is not an operator", op),
+    }
+}
+
+/// Central differences of f, for the shapes, noise and functions whose
+/// exact derivatives are not worth the code. The noise seeds are flat.
+fn numeric_derivatives(
+    arity: usize,
+    args: &[f32; MAX_ARITY],
+    f: impl Fn(&[f32; MAX_ARITY]) -> f32,
+) -> SmallVec<[f32; MAX_ARITY]> {
+    const H: f32 = 1e-3;
+
+    (0..arity)
+        .map(|k| {
+            let mut plus = *args;
+            plus[k] += H;
+            let mut minus = *args;
+            minus[k] -= H;
+            (f(&plus) - f(&minus)) / (2.0 * H)
+        })
+        .collect()
+}
+
+/// Returns the squared error of the program output against the goal image
+/// and its gradient with respect to each constant, in the order of
+/// `tune::constants`.
+///
+/// Truncating to u8 makes calc_image_error piecewise constant in the
+/// constants, so the smooth stand-in sum((pixel - goal)^2) is used with
+/// pixel = level(output) * 127 + 128 left unrounded.
+pub fn image_error_gradient(goal_image: &GrayScaleImage, prg: &Program) -> (f32, Vec<f32>) {
+    let mut gradient = vec![0.0; constants(prg).len()];
+    let mut error = 0.0;
+
+    let features = sampled_features(goal_image.width, goal_image.height);
+    let features: Vec<&[f32]> = features.iter().map(Vec::as_slice).collect();
+
+    let mut tape = Gradient::new();
+    let mut state = State::new(FIRST_FEATURE + features.len());
+    let mut goal_pixels = goal_image.data.iter();
+    let (xs, ys) = prg.coords.axes(goal_image.width, goal_image.height);
+
+    for (row, &y) in ys.iter().enumerate() {
+        for (column, &x) in xs.iter().enumerate() {
+            state.vars[X] = x;
+            state.vars[Y] = y;
+            set_derived_inputs(&mut state.vars, &features, row * xs.len() + column);
+
+            let output = tape.eval(prg, &state);
+
+            let pixel = prg.transfer.level(output) * 127.0 + 128.0;
+            let slope = prg.transfer.derivative(output) * 127.0;
+
+            let goal = *goal_pixels.next().unwrap() as f32;
+            let diff = pixel - goal;
+            error += diff * diff;
+
+            if slope == 0.0 {
+                continue;
+            }
+            tape.add_constants_gradient(prg, 2.0 * diff * slope, &mut gradient);
+        }
+    }
+
+    (error, gradient)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval::eval, tune::set_constants};
+
+    /// Compares the tape gradient of every constant with central differences
+    fn check_gradient(prg: &Program, state: &State) {
+        let mut tape = Gradient::new();
+        let value = tape.eval(prg, state);
+        assert_eq!(eval(prg, state), value);
+
+        let values = constants(prg);
+        let mut gradient = vec![0.0; values.len()];
+        tape.add_constants_gradient(prg, 1.0, &mut gradient);
+
+        const H: f32 = 1e-3;
+        for i in 0..values.len() {
+            let mut plus = prg.clone();
+            let mut shifted = values.clone();
+            shifted[i] += H;
+            set_constants(&mut plus, &shifted);
+            let mut minus = prg.clone();
+            shifted[i] -= 2.0 * H;
+            set_constants(&mut minus, &shifted);
+
+            let numeric = (eval(&plus, state) - eval(&minus, state)) / (2.0 * H);
+            let exact = gradient[i];
+            assert!(
+                (numeric - exact).abs() < 1e-2 * (1.0 + exact.abs()),
+                "constant {}: numeric {} exact {}",
+                i,
+                numeric,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    pub fn gradient_add_mul() {
+        // x 2 * 3 +
+        let prg = Program {
+            code: vec![
+                Expr::Var(0),
+                Expr::Const(2.0),
+                Expr::Mul,
+                Expr::Const(3.0),
+                Expr::Add,
+            ],
+            ..Default::default()
+        };
+        let state = State {
+            vars: vec![0.5, 0.0],
+        };
+
+        let mut tape = Gradient::new();
+        assert_eq!(4.0, tape.eval(&prg, &state));
+        assert_eq!(0.5, tape.adjoint(1));
+        assert_eq!(1.0, tape.adjoint(3));
+    }
+
+    #[test]
+    pub fn gradient_sin_cos_atan() {
+        // x 0.3 * sin 0.7 - cos 1.5 * atan
+        let prg = Program {
+            code: vec![
+                Expr::Var(0),
+                Expr::Const(0.3),
+                Expr::Mul,
+                Expr::Sin,
+                Expr::Const(0.7),
+                Expr::Sub,
+                Expr::Cos,
+                Expr::Const(1.5),
+                Expr::Mul,
+                Expr::Atan,
+            ],
+            ..Default::default()
+        };
+        let state = State {
+            vars: vec![0.4, 0.0],
+        };
+
+        check_gradient(&prg, &state);
+    }
+
+    #[test]
+    pub fn gradient_extended_ops() {
+        let state = State {
+            vars: vec![0.4, -0.3],
+        };
+
+        for text in [
+            "x 1.3 / 0.7 y * /",
+            "x 0.6 - abs sqrt 2.1 *",
+            "y 0.9 * exp 1.7 + log",
+            "x 2.5 * tanh 0.35 mod",
+            "y 0.2 + x 1.5 * atan2",
+            "x 0.1 - x 3 * y 2 * select fract",
+            "x 0.3 + y 0.25 0.5 rect x y 0.7 disc *",
+            "x y 0.8 -0.3 linedist -0.2 0.9 y smoothstep +",
+            "x 3 * y 3 * 5 vnoise x 2 * y 7 perlin +",
+            "x 4 * y 4 * 2 worley 0.3 x 1 fbm *",
+            "@consts 0.7 -1.2\nc0 x * c1 + sin c0 y * *",
+            "@fn 1 a0 3 * sin\n@fn 2 a0 a1 * a1 +\nx fn0 0.6 * y 0.4 fn1 +",
+        ] {
+            check_gradient(&text.parse().unwrap(), &state);
+        }
+    }
+
+    #[test]
+    pub fn gradient_max_min_dup() {
+        // 0.2 dup * y max 0.9 min
+        let prg = Program {
+            code: vec![
+                Expr::Const(0.2),
+                Expr::Dup,
+                Expr::Mul,
+                Expr::Var(1),
+                Expr::Max,
+                Expr::Const(0.9),
+                Expr::Min,
+            ],
+            ..Default::default()
+        };
+        let state = State {
+            vars: vec![0.0, -0.5],
+        };
+
+        let mut tape = Gradient::new();
+        tape.eval(&prg, &state);
+        assert!((0.4 - tape.adjoint(0)).abs() < 1e-6);
+        assert_eq!(0.0, tape.adjoint(5));
+
+        check_gradient(&prg, &state);
+    }
+}
@@ -0,0 +1,330 @@
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+
+use crate::{
+    expr::{Expr, Program, MAX_ARITY},
+    mutate::{random_op, OpSet},
+};
+
+/// Genomes decoding to more instructions than this are not evaluated.
+/// Nodes used by several others are repeated in the code, so the length
+/// can double with every column.
+const MAX_DECODED_LEN: usize = 10_000;
+
+/// Step size when a constant gene is tweaked instead of drawn anew
+const CONST_SIGMA: f32 = 0.5;
+
+/// The shape of a Cartesian GP genome
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    pub rows: usize,
+    pub columns: usize,
+    /// How many columns back a node can connect to, the inputs can always
+    /// be connected to
+    pub levels_back: usize,
+    /// The number of evolved constants, inputs besides the variables
+    pub constants: usize,
+}
+
+/// A node computing an operator of the outputs of earlier nodes or inputs.
+/// Only the first `op.arity()` connection genes are used.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Node {
+    pub op: Expr,
+    pub inputs: [usize; MAX_ARITY],
+}
+
+/// A Cartesian GP genome: a grid of nodes with integer connection genes.
+///
+/// Addresses number the variables first, then the constants, then the
+/// nodes column by column. Nodes only connect to lower addresses, so the
+/// genome is a DAG, and only the nodes the output depends on are active.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Genome {
+    pub grid: Grid,
+    /// The Var numbers of the inputs
+    pub vars: Vec<usize>,
+    pub consts: Vec<f32>,
+    pub nodes: Vec<Node>,
+    /// The address of the program output
+    pub output: usize,
+}
+
+impl Genome {
+    pub fn random(rng: &mut StdRng, grid: Grid, vars: &[usize], ops: &OpSet) -> Self {
+        let operators = node_ops(ops);
+        let mut genome = Genome {
+            grid,
+            vars: vars.to_vec(),
+            consts: (0..grid.constants)
+                .map(|_| ops.constants.sample(rng))
+                .collect(),
+            nodes: Vec::with_capacity(grid.rows * grid.columns),
+            output: 0,
+        };
+
+        for k in 0..grid.rows * grid.columns {
+            let op = random_op(rng, &operators).expect("Genome::random: no operators");
+            let inputs = std::array::from_fn(|_| genome.random_input(rng, k));
+            genome.nodes.push(Node { op, inputs });
+        }
+        genome.output = rng.gen_range(0..genome.ninputs() + genome.nodes.len());
+
+        genome
+    }
+
+    /// The number of variables and constants
+    pub fn ninputs(&self) -> usize {
+        self.vars.len() + self.consts.len()
+    }
+
+    /// Returns for each address whether the output depends on it
+    pub fn active(&self) -> Vec<bool> {
+        let n = self.ninputs();
+        let mut active = vec![false; n + self.nodes.len()];
+        active[self.output] = true;
+
+        for (k, node) in self.nodes.iter().enumerate().rev() {
+            if active[n + k] {
+                for &input in node.inputs[..node.op.arity()].iter() {
+                    active[input] = true;
+                }
+            }
+        }
+
+        active
+    }
+
+    /// Returns the postfix code of the active nodes, or None if it would
+    /// be longer than MAX_DECODED_LEN
+    pub fn to_code(&self) -> Option<Vec<Expr>> {
+        let n = self.ninputs();
+        let mut lengths = vec![1usize; n + self.nodes.len()];
+        for (k, node) in self.nodes.iter().enumerate() {
+            lengths[n + k] = node.inputs[..node.op.arity()]
+                .iter()
+                .fold(1usize, |sum, &input| sum.saturating_add(lengths[input]));
+        }
+        if lengths[self.output] > MAX_DECODED_LEN {
+            return None;
+        }
+
+        let mut code = Vec::with_capacity(lengths[self.output]);
+        self.push_code(self.output, &mut code);
+        Some(code)
+    }
+
+    /// Returns a program with the decoded code and the other settings of
+    /// `prg`, or None if the code would be too long
+    pub fn to_program(&self, prg: &Program) -> Option<Program> {
+        self.to_code().map(|code| prg.with_code(code))
+    }
+
+    fn push_code(&self, address: usize, code: &mut Vec<Expr>) {
+        let nvars = self.vars.len();
+        let n = self.ninputs();
+
+        if address < nvars {
+            code.push(Expr::Var(self.vars[address]));
+        } else if address < n {
+            code.push(Expr::Const(self.consts[address - nvars]));
+        } else {
+            let node = self.nodes[address - n];
+            for &input in node.inputs[..node.op.arity()].iter() {
+                self.push_code(input, code);
+            }
+            code.push(node.op);
+        }
+    }
+
+    /// Mutates random genes until one of the active genes has changed, so
+    /// no offspring is a copy of its parent. The inactive genes mutated on
+    /// the way drift freely.
+    pub fn mutate(&mut self, rng: &mut StdRng, ops: &OpSet) {
+        let operators = node_ops(ops);
+        let active = self.active();
+        let n = self.ninputs();
+        let nvars = self.vars.len();
+        let node_genes = self.nodes.len() * (1 + MAX_ARITY);
+
+        loop {
+            let gene = rng.gen_range(0..node_genes + 1 + self.consts.len());
+
+            if gene < node_genes {
+                let k = gene / (1 + MAX_ARITY);
+                let node = self.nodes[k];
+                let j = gene % (1 + MAX_ARITY);
+                if j == 0 {
+                    self.nodes[k].op = random_op(rng, &operators).unwrap();
+                } else {
+                    self.nodes[k].inputs[j - 1] = self.random_input(rng, k);
+                }
+                if active[n + k] && j <= node.op.arity() && self.nodes[k] != node {
+                    return;
+                }
+            } else if gene == node_genes {
+                let output = self.output;
+                self.output = rng.gen_range(0..n + self.nodes.len());
+                if self.output != output {
+                    return;
+                }
+            } else {
+                let c = gene - node_genes - 1;
+                if rng.gen() {
+                    self.consts[c] = ops.constants.sample(rng);
+                } else {
+                    let step: f32 = rng.sample(StandardNormal);
+                    self.consts[c] += CONST_SIGMA * step;
+                }
+                if active[nvars + c] {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// A random address node `k` can connect to, an input or a node in one
+    /// of the levels_back columns before it
+    fn random_input(&self, rng: &mut StdRng, k: usize) -> usize {
+        let rows = self.grid.rows;
+        let column = k / rows;
+        let first = column.saturating_sub(self.grid.levels_back) * rows;
+        let nnodes = column * rows - first;
+
+        let n = self.ninputs();
+        let i = rng.gen_range(0..n + nnodes);
+        if i < n {
+            i
+        } else {
+            n + first + (i - n)
+        }
+    }
+}
+
+/// The operators nodes can have, all of the OpSet's but its functions
+pub fn node_ops(ops: &OpSet) -> Vec<Expr> {
+    [ops.unary, ops.binary, ops.ternary, ops.shapes, ops.noise].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{
+        eval::eval,
+        inputs::{X, Y},
+        population::one_plus_lambda,
+        state::State,
+    };
+
+    const GRID: Grid = Grid {
+        rows: 2,
+        columns: 10,
+        levels_back: 3,
+        constants: 2,
+    };
+
+    #[test]
+    pub fn decodes_the_active_nodes() {
+        let op = |op, a, b| Node {
+            op,
+            inputs: [a, b, 0, 0],
+        };
+        // Inputs x, y and 0.5, then x * 0.5, an unused cos(y) and sin(x * 0.5)
+        let genome = Genome {
+            grid: Grid {
+                rows: 1,
+                columns: 3,
+                levels_back: 3,
+                constants: 1,
+            },
+            vars: vec![X, Y],
+            consts: vec![0.5],
+            nodes: vec![
+                op(Expr::Mul, 0, 2),
+                op(Expr::Cos, 1, 1),
+                op(Expr::Sin, 3, 3),
+            ],
+            output: 5,
+        };
+
+        let prg = genome.to_program(&Program::new(vec![])).unwrap();
+        assert_eq!("x 0.5 * sin", prg.to_string());
+        assert_eq!(vec![true, false, true, true, false, true], genome.active());
+    }
+
+    #[test]
+    pub fn connections_stay_within_levels_back() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut genome = Genome::random(&mut rng, GRID, &[X, Y], &OpSet::ALL);
+
+        for _ in 0..500 {
+            genome.mutate(&mut rng, &OpSet::ALL);
+
+            let n = genome.ninputs();
+            for (k, node) in genome.nodes.iter().enumerate() {
+                let column = k / GRID.rows;
+                for &input in node.inputs.iter() {
+                    let input_column = input.checked_sub(n).map(|i| i / GRID.rows);
+                    assert!(
+                        input_column.is_none_or(|c| c < column && c + GRID.levels_back >= column),
+                        "node {} connects to {}",
+                        k,
+                        input
+                    );
+                }
+            }
+            if let Some(code) = genome.to_code() {
+                assert!(Program::new(code).is_well_formed());
+            }
+        }
+    }
+
+    #[test]
+    pub fn mutation_changes_the_program() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let genome = Genome::random(&mut rng, GRID, &[X, Y], &OpSet::DEFAULT);
+
+        for _ in 0..100 {
+            let mut child = genome.clone();
+            child.mutate(&mut rng, &OpSet::DEFAULT);
+            assert_ne!(genome, child);
+        }
+    }
+
+    #[test]
+    pub fn one_plus_lambda_finds_a_target() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let grid = Grid {
+            rows: 1,
+            columns: 20,
+            levels_back: 20,
+            constants: 0,
+        };
+        // x * y + x on a few points
+        let points = [(0.5, -1.0), (0.25, 0.75), (-0.5, 0.5), (1.0, 1.0)];
+        let error_fn = |genome: &Genome| match genome.to_program(&Program::new(vec![])) {
+            Some(prg) => points
+                .iter()
+                .map(|&(x, y)| (eval(&prg, &State { vars: vec![x, y] }) - (x * y + x)).abs())
+                .sum(),
+            None => f32::MAX,
+        };
+
+        let mut parent = Genome::random(&mut rng, grid, &[X, Y], &OpSet::DEFAULT);
+        let mut parent_error = error_fn(&parent);
+        for _ in 0..2000 {
+            (parent, parent_error) = one_plus_lambda(
+                &mut rng,
+                parent,
+                parent_error,
+                4,
+                |rng, genome| genome.mutate(rng, &OpSet::DEFAULT),
+                error_fn,
+            );
+        }
+
+        assert!(parent_error < 1e-5, "error was {}", parent_error);
+    }
+}
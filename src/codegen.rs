@@ -0,0 +1,1136 @@
+use crate::{
+    coords::{Coords, Mapping, Origin, Sampling},
+    dag::Dag,
+    eval::PROTECTED_EPSILON,
+    expr::{gen_var_str, Expr, Program},
+    inputs::{FIRST_FEATURE, R, THETA},
+    shapes::SHAPE_EDGE,
+    transfer::{Transfer, TransferFn},
+};
+
+/// The parts of the generated source that differ between languages
+trait Language {
+    /// A float literal
+    fn number(&self, x: f32) -> String {
+        float_literal(x)
+    }
+
+    /// A local variable definition
+    fn assign(&self, name: &str, value: &str) -> String;
+
+    /// The statement returning the result of the function
+    fn ret(&self, value: &str) -> String {
+        format!("return {};", value)
+    }
+
+    /// The expression for an input, r and θ are computed from x and y and
+    /// the features are parameters
+    fn input(&self, i: usize) -> String {
+        match i {
+            R => "sqrt(x * x + y * y)".to_string(),
+            THETA => "atan(y, x)".to_string(),
+            _ => gen_var_str(i),
+        }
+    }
+
+    /// A call of the math function with the GLSL name, atan2 for atan(y, x)
+    fn call(&self, name: &str, args: &[String]) -> String {
+        let name = if name == "atan2" { "atan" } else { name };
+        format!("{}({})", name, args.join(", "))
+    }
+
+    /// `then` if `condition` holds, otherwise `otherwise`
+    fn choose(&self, condition: &str, then: &str, otherwise: &str) -> String {
+        format!("({} ? {} : {})", condition, then, otherwise)
+    }
+
+    /// The expression applying an operator to already computed operands,
+    /// in the order they were pushed
+    fn op(&self, op: Expr, args: &[String]) -> String {
+        match op {
+            Expr::Max => format!("max({}, {})", args[0], args[1]),
+            Expr::Min => format!("min({}, {})", args[0], args[1]),
+            Expr::Cos => format!("cos({} * TAU)", args[0]),
+            Expr::Sin => format!("sin({} * TAU)", args[0]),
+            Expr::Atan => format!("atan({})", args[0]),
+            op => common_op(self, op, args),
+        }
+    }
+}
+
+/// The operators written the same way in every language apart from the
+/// function names and conditionals, with the protection eval uses
+fn common_op<L: Language + ?Sized>(lang: &L, op: Expr, args: &[String]) -> String {
+    let call = |name: &str, args: &[String]| lang.call(name, args);
+    let abs = |a: &String| call("abs", std::slice::from_ref(a));
+    let near_zero = |a: &String| format!("{} < {}", abs(a), lang.number(PROTECTED_EPSILON));
+    let zero = lang.number(0.0);
+    let one = lang.number(1.0);
+
+    match op {
+        Expr::Add => format!("{} + {}", args[0], args[1]),
+        Expr::Sub => format!("{} - {}", args[0], args[1]),
+        Expr::Mul => format!("{} * {}", args[0], args[1]),
+        Expr::Div => lang.choose(
+            &near_zero(&args[1]),
+            &one,
+            &format!("{} / {}", args[0], args[1]),
+        ),
+        Expr::Mod => {
+            let quotient = call("floor", &[format!("{} / {}", args[0], args[1])]);
+            lang.choose(
+                &near_zero(&args[1]),
+                &zero,
+                &format!("{} - {} * {}", args[0], args[1], quotient),
+            )
+        }
+        Expr::Atan2 => call("atan2", args),
+        Expr::Abs => abs(&args[0]),
+        Expr::Sqrt => call("sqrt", &[abs(&args[0])]),
+        Expr::Exp | Expr::Tanh | Expr::Floor => call(&op.to_string(), args),
+        Expr::Log => lang.choose(&near_zero(&args[0]), &zero, &call("log", &[abs(&args[0])])),
+        Expr::Step => lang.choose(&format!("{} < {}", args[0], zero), &zero, &one),
+        Expr::Fract => format!("{} - {}", args[0], call("floor", args)),
+        Expr::Select => lang.choose(&format!("{} > {}", args[0], zero), &args[1], &args[2]),
+        // Plain calls of the helpers from templates/, in every language
+        Expr::ValueNoise => format!("noise_value({})", args.join(", ")),
+        Expr::Perlin => format!("noise_perlin({})", args.join(", ")),
+        Expr::Worley => format!("noise_worley({})", args.join(", ")),
+        Expr::Fbm => format!("noise_fbm({})", args.join(", ")),
+        _ => panic!("op: {} is not an operator", op),
+    }
+}
+
+struct Rust;
+
+impl Language for Rust {
+    fn number(&self, x: f32) -> String {
+        format!("{:?}_f32", finite(x))
+    }
+
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("let {} = {};", name, value)
+    }
+
+    fn ret(&self, value: &str) -> String {
+        value.to_string()
+    }
+
+    fn input(&self, i: usize) -> String {
+        match i {
+            R => "(x * x + y * y).sqrt()".to_string(),
+            THETA => "y.atan2(x)".to_string(),
+            _ => gen_var_str(i),
+        }
+    }
+
+    /// Methods on the first argument
+    fn call(&self, name: &str, args: &[String]) -> String {
+        let name = if name == "log" { "ln" } else { name };
+        let receiver = if top_level_space(&args[0]) {
+            format!("({})", args[0])
+        } else {
+            args[0].clone()
+        };
+        format!("{}.{}({})", receiver, name, args[1..].join(", "))
+    }
+
+    fn choose(&self, condition: &str, then: &str, otherwise: &str) -> String {
+        format!("if {} {{ {} }} else {{ {} }}", condition, then, otherwise)
+    }
+
+    fn op(&self, op: Expr, args: &[String]) -> String {
+        // Same operand order as apply, so NaN and -0.0 come out the same
+        match op {
+            Expr::Add => format!("{} + {}", args[1], args[0]),
+            Expr::Mul => format!("{} * {}", args[1], args[0]),
+            Expr::Max => format!("{}.max({})", args[1], args[0]),
+            Expr::Min => format!("{}.min({})", args[1], args[0]),
+            Expr::Cos => format!("({} * TAU).cos()", args[0]),
+            Expr::Sin => format!("({} * TAU).sin()", args[0]),
+            Expr::Atan => format!("{}.atan()", args[0]),
+            op => common_op(self, op, args),
+        }
+    }
+}
+
+struct C;
+
+impl Language for C {
+    fn number(&self, x: f32) -> String {
+        let text = format!("{:?}f", finite(x));
+        if x.is_sign_negative() {
+            format!("({})", text)
+        } else {
+            text
+        }
+    }
+
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("float {} = {};", name, value)
+    }
+
+    fn input(&self, i: usize) -> String {
+        match i {
+            R => "sqrtf(x * x + y * y)".to_string(),
+            THETA => "atan2f(y, x)".to_string(),
+            _ => gen_var_str(i),
+        }
+    }
+
+    fn call(&self, name: &str, args: &[String]) -> String {
+        let name = match name {
+            "abs" => "fabs",
+            "min" => "fmin",
+            "max" => "fmax",
+            name => name,
+        };
+        format!("{}f({})", name, args.join(", "))
+    }
+
+    fn op(&self, op: Expr, args: &[String]) -> String {
+        match op {
+            Expr::Max => format!("fmaxf({}, {})", args[1], args[0]),
+            Expr::Min => format!("fminf({}, {})", args[1], args[0]),
+            Expr::Cos => format!("cosf({} * TAU)", args[0]),
+            Expr::Sin => format!("sinf({} * TAU)", args[0]),
+            Expr::Atan => format!("atanf({})", args[0]),
+            op => common_op(self, op, args),
+        }
+    }
+}
+
+struct Python;
+
+impl Language for Python {
+    fn number(&self, x: f32) -> String {
+        format!("np.float32({:?})", finite(x))
+    }
+
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("{} = {}", name, value)
+    }
+
+    fn ret(&self, value: &str) -> String {
+        format!("return {}", value)
+    }
+
+    fn input(&self, i: usize) -> String {
+        match i {
+            R => "np.sqrt(x * x + y * y)".to_string(),
+            THETA => "np.arctan2(y, x)".to_string(),
+            _ => gen_var_str(i),
+        }
+    }
+
+    fn call(&self, name: &str, args: &[String]) -> String {
+        // fmax and fmin ignore NaN like f32::max and f32::min
+        let name = match name {
+            "atan2" => "arctan2",
+            "min" => "fmin",
+            "max" => "fmax",
+            name => name,
+        };
+        format!("np.{}({})", name, args.join(", "))
+    }
+
+    /// Both branches are computed, so protected operators may warn about
+    /// dividing by zero in the branch that is not taken
+    fn choose(&self, condition: &str, then: &str, otherwise: &str) -> String {
+        format!("np.where({}, {}, {})", condition, then, otherwise)
+    }
+
+    fn op(&self, op: Expr, args: &[String]) -> String {
+        // fmax and fmin ignore NaN like f32::max and f32::min
+        match op {
+            Expr::Max => format!("np.fmax({}, {})", args[1], args[0]),
+            Expr::Min => format!("np.fmin({}, {})", args[1], args[0]),
+            Expr::Cos => format!("np.cos({} * TAU)", args[0]),
+            Expr::Sin => format!("np.sin({} * TAU)", args[0]),
+            Expr::Atan => format!("np.arctan({})", args[0]),
+            op => common_op(self, op, args),
+        }
+    }
+}
+
+struct Glsl;
+
+impl Language for Glsl {
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("float {} = {};", name, value)
+    }
+}
+
+struct Wgsl;
+
+impl Language for Wgsl {
+    fn assign(&self, name: &str, value: &str) -> String {
+        format!("let {} = {};", name, value)
+    }
+
+    fn input(&self, i: usize) -> String {
+        match i {
+            THETA => "atan2(y, x)".to_string(),
+            _ => Glsl.input(i),
+        }
+    }
+
+    fn call(&self, name: &str, args: &[String]) -> String {
+        format!("{}({})", name, args.join(", "))
+    }
+
+    fn choose(&self, condition: &str, then: &str, otherwise: &str) -> String {
+        format!("select({}, {}, {})", otherwise, then, condition)
+    }
+}
+
+/// Generates a standalone Rust function `pixapprox(x, y)` computing the
+/// same value as eval.
+pub fn to_rust(prg: &Program) -> String {
+    let body = indent(&lower(prg, &Rust));
+    let helpers = noise_helpers(prg, include_str!("../templates/noise.rs"));
+    let params: String = feature_names(prg)
+        .iter()
+        .map(|name| format!(", {}: f32", name))
+        .collect();
+
+    format!(
+        "\
+// Generated by pixapprox from: {prg}
+{helpers}pub fn pixapprox(x: f32, y: f32{params}) -> f32 {{
+    use std::f32::consts::TAU;
+
+{body}
+}}
+",
+        prg = source_comment(prg),
+    )
+}
+
+/// Generates a standalone C function `pixapprox(x, y)` computing the same
+/// value as eval.
+pub fn to_c(prg: &Program) -> String {
+    let body = indent(&lower(prg, &C));
+    let helpers = noise_helpers(prg, include_str!("../templates/noise.c"));
+    let includes = if helpers.is_empty() {
+        ""
+    } else {
+        "#include <stdint.h>\n"
+    };
+    let params: String = feature_names(prg)
+        .iter()
+        .map(|name| format!(", float {}", name))
+        .collect();
+
+    format!(
+        "\
+/* Generated by pixapprox from: {prg} */
+#include <math.h>
+{includes}
+{helpers}float pixapprox(float x, float y{params})
+{{
+    const float TAU = 6.2831855f;
+
+{body}
+}}
+",
+        prg = source_comment(prg),
+    )
+}
+
+/// Generates a Python function `pixapprox(x, y)` computing the same value as
+/// eval, in float32 with NumPy, so it works on whole coordinate arrays.
+pub fn to_python(prg: &Program) -> String {
+    let body = indent(&lower(prg, &Python));
+    // Two blank lines between top level functions
+    let mut helpers = noise_helpers(prg, include_str!("../templates/noise.py"));
+    if !helpers.is_empty() {
+        helpers.push('\n');
+    }
+    let features = feature_names(prg);
+    let params: String = features.iter().map(|name| format!(", {}", name)).collect();
+    let conversions: String = features
+        .iter()
+        .map(|name| format!("    {name} = np.asarray({name}, dtype=np.float32)\n"))
+        .collect();
+
+    format!(
+        "\
+# Generated by pixapprox from: {prg}
+import numpy as np
+
+TAU = np.float32(6.2831855)
+
+
+{helpers}def pixapprox(x, y{params}):
+    x = np.asarray(x, dtype=np.float32)
+    y = np.asarray(y, dtype=np.float32)
+{conversions}{body}
+",
+        prg = source_comment(prg),
+    )
+}
+
+/// Generates a GLSL (ES 3.00, WebGL2) fragment shader rendering the program
+/// like eval_into_image does, at the resolution given by `u_resolution`.
+pub fn to_glsl(prg: &Program) -> String {
+    let lang = Glsl;
+    let body = indent(&lower(prg, &lang));
+    let helpers = noise_helpers(prg, include_str!("../templates/noise.glsl"));
+    let transfer = indent(&transfer_statements(&prg.transfer, &lang));
+    let coords = coords_expression(&prg.coords, "u_resolution");
+
+    // Features are read from textures with the nearest texel, like
+    // Features::sampled, and passed on as parameters
+    let features = feature_names(prg);
+    let params: String = features
+        .iter()
+        .map(|name| format!(", float {}", name))
+        .collect();
+    let args: String = features.iter().map(|name| format!(", {}", name)).collect();
+    let samplers: String = features
+        .iter()
+        .map(|name| format!("uniform sampler2D u_{};\n", name))
+        .collect();
+    let fetches: String = features
+        .iter()
+        .map(|name| {
+            format!(
+                "    float {name} = texelFetch(u_{name}, ivec2(pixel * vec2(textureSize(u_{name}, 0)) / u_resolution), 0).r * 2.0 - 1.0;\n"
+            )
+        })
+        .collect();
+
+    format!(
+        "\
+#version 300 es
+// Generated by pixapprox from: {prg}
+precision highp float;
+
+uniform vec2 u_resolution;
+{samplers}out vec4 fragColor;
+
+const float TAU = 6.2831855;
+
+{helpers}float pixapprox(float x, float y{params}) {{
+{body}
+}}
+
+void main() {{
+    // Pixel indices with the origin in the top left corner, like the images
+    vec2 pixel = floor(vec2(gl_FragCoord.x, u_resolution.y - gl_FragCoord.y));
+    vec2 p = {coords};
+{fetches}    float v = pixapprox(p.x, p.y{args});
+{transfer}
+    fragColor = vec4(vec3(floor(level * 127.0 + 128.0) / 255.0), 1.0);
+}}
+",
+        prg = source_comment(prg),
+    )
+}
+
+/// Generates a WGSL fragment shader rendering the program like
+/// eval_into_image does, at the resolution given in the uniform buffer.
+pub fn to_wgsl(prg: &Program) -> String {
+    let lang = Wgsl;
+    let body = indent(&lower(prg, &lang));
+    let helpers = noise_helpers(prg, include_str!("../templates/noise.wgsl"));
+    let transfer = indent(&transfer_statements(&prg.transfer, &lang));
+    let coords = coords_expression(&prg.coords, "u.resolution");
+
+    // Feature fN is the texture at binding N + 1
+    let features = feature_names(prg);
+    let params: String = features
+        .iter()
+        .map(|name| format!(", {}: f32", name))
+        .collect();
+    let args: String = features.iter().map(|name| format!(", {}", name)).collect();
+    let textures: String = feature_vars(prg)
+        .iter()
+        .map(|&i| {
+            format!(
+                "@group(0) @binding({}) var t_{}: texture_2d<f32>;\n",
+                i - FIRST_FEATURE + 1,
+                gen_var_str(i)
+            )
+        })
+        .collect();
+    let fetches: String = features
+        .iter()
+        .map(|name| {
+            format!(
+                "    let {name} = textureLoad(t_{name}, vec2<i32>(pixel * vec2<f32>(textureDimensions(t_{name})) / u.resolution), 0).r * 2.0 - 1.0;\n"
+            )
+        })
+        .collect();
+
+    format!(
+        "\
+// Generated by pixapprox from: {prg}
+struct Uniforms {{
+    resolution: vec2<f32>,
+}}
+
+@group(0) @binding(0) var<uniform> u: Uniforms;
+{textures}
+const TAU: f32 = 6.2831855;
+
+{helpers}fn pixapprox(x: f32, y: f32{params}) -> f32 {{
+{body}
+}}
+
+@fragment
+fn main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {{
+    // The position has the origin in the top left corner, like the images
+    let pixel = floor(position.xy);
+    let p = {coords};
+{fetches}    let v = pixapprox(p.x, p.y{args});
+{transfer}
+    let c = floor(level * 127.0 + 128.0) / 255.0;
+    return vec4<f32>(c, c, c, 1.0);
+}}
+",
+        prg = source_comment(prg),
+    )
+}
+
+/// The noise functions in the target language followed by a blank line,
+/// or nothing if the program does not use noise
+fn noise_helpers(prg: &Program, template: &str) -> String {
+    if used_code(prg).any(Expr::is_noise) {
+        format!("{}\n", template)
+    } else {
+        String::new()
+    }
+}
+
+/// The Var numbers of the features the program reads, in order
+fn feature_vars(prg: &Program) -> Vec<usize> {
+    let mut vars: Vec<usize> = used_code(prg)
+        .filter_map(|expr| match expr {
+            Expr::Var(i) if *i >= FIRST_FEATURE => Some(*i),
+            _ => None,
+        })
+        .collect();
+    vars.sort_unstable();
+    vars.dedup();
+    vars
+}
+
+/// The instructions of the code and of the functions it calls, which the
+/// exported code has inlined
+fn used_code(prg: &Program) -> impl Iterator<Item = &Expr> {
+    let called = |n: usize| {
+        prg.code
+            .contains(&Expr::Call(n as u16, prg.functions[n].arity as u8))
+    };
+    let bodies = (0..prg.functions.len())
+        .filter(move |&n| called(n))
+        .flat_map(move |n| prg.functions[n].code.iter());
+    prg.code.iter().chain(bodies)
+}
+
+fn feature_names(prg: &Program) -> Vec<String> {
+    feature_vars(prg).into_iter().map(gen_var_str).collect()
+}
+
+/// The program on a single line
+fn source_comment(prg: &Program) -> String {
+    prg.to_string().replace('\n', "; ")
+}
+
+/// One statement per DAG node, so common subexpressions are computed once.
+/// The root node is returned directly.
+fn lower(prg: &Program, lang: &dyn Language) -> Vec<String> {
+    let dag = Dag::from_program(prg);
+    let mut lines = Vec::with_capacity(dag.nodes.len() + 1);
+
+    for (id, node) in dag.nodes.iter().enumerate() {
+        let value = match node.op {
+            Expr::Const(x) => lang.number(x),
+            Expr::Var(i) => lang.input(i),
+            op => {
+                let args: Vec<String> = node.args.iter().map(|arg| format!("v{}", arg)).collect();
+                if op.is_shape() {
+                    shape(lang, op, &args, &format!("v{}", id), &mut lines)
+                } else {
+                    lang.op(op, &args)
+                }
+            }
+        };
+        if id == dag.root {
+            lines.push(lang.ret(&value));
+        } else {
+            lines.push(lang.assign(&format!("v{}", id), &value));
+        }
+    }
+
+    lines
+}
+
+/// The value of a shape, with the same operations as shapes.rs. The
+/// intermediate values are added to `lines` as locals named after `name`.
+fn shape(
+    lang: &dyn Language,
+    op: Expr,
+    args: &[String],
+    name: &str,
+    lines: &mut Vec<String>,
+) -> String {
+    let local = |suffix: &str| format!("{}_{}", name, suffix);
+    let abs = |a: &String| lang.call("abs", std::slice::from_ref(a));
+    let clamped = |value: String| {
+        let low = lang.call("max", &[value, lang.number(0.0)]);
+        lang.call("min", &[low, lang.number(1.0)])
+    };
+    let edge = |inside: &str| {
+        clamped(format!(
+            "{} / {} + {}",
+            inside,
+            lang.number(SHAPE_EDGE),
+            lang.number(0.5)
+        ))
+    };
+
+    match op {
+        Expr::Rect => {
+            let inside = local("inside");
+            let sides = [
+                format!("{} - {}", abs(&args[2]), abs(&args[0])),
+                format!("{} - {}", abs(&args[3]), abs(&args[1])),
+            ];
+            lines.push(lang.assign(&inside, &lang.call("min", &sides)));
+            edge(&inside)
+        }
+        Expr::Disc => {
+            let inside = local("inside");
+            let radius = lang.call(
+                "sqrt",
+                &[format!("{0} * {0} + {1} * {1}", args[0], args[1])],
+            );
+            lines.push(lang.assign(&inside, &format!("{} - {}", abs(&args[2]), radius)));
+            edge(&inside)
+        }
+        Expr::LineDist => {
+            let (length, t, u, v) = (local("length"), local("t"), local("u"), local("v"));
+            lines.push(lang.assign(&length, &format!("{0} * {0} + {1} * {1}", args[2], args[3])));
+            let projected = format!(
+                "({} * {} + {} * {}) / {}",
+                args[0], args[2], args[1], args[3], length
+            );
+            let value = lang.choose(
+                &format!("{} < {}", length, lang.number(PROTECTED_EPSILON)),
+                &lang.number(0.0),
+                &clamped(projected),
+            );
+            lines.push(lang.assign(&t, &value));
+            lines.push(lang.assign(&u, &format!("{} - {} * {}", args[0], t, args[2])));
+            lines.push(lang.assign(&v, &format!("{} - {} * {}", args[1], t, args[3])));
+            lang.call("sqrt", &[format!("{0} * {0} + {1} * {1}", u, v)])
+        }
+        Expr::Smoothstep => {
+            let (d, t) = (local("d"), local("t"));
+            lines.push(lang.assign(&d, &format!("{} - {}", args[1], args[0])));
+            let step = lang.choose(
+                &format!("{} < {}", args[2], args[0]),
+                &lang.number(0.0),
+                &lang.number(1.0),
+            );
+            let value = lang.choose(
+                &format!("{} < {}", abs(&d), lang.number(PROTECTED_EPSILON)),
+                &step,
+                &clamped(format!("({} - {}) / {}", args[2], args[0], d)),
+            );
+            lines.push(lang.assign(&t, &value));
+            format!(
+                "{0} * {0} * ({1} - {2} * {0})",
+                t,
+                lang.number(3.0),
+                lang.number(2.0)
+            )
+        }
+        _ => panic!("shape: {} is not a shape", op),
+    }
+}
+
+/// True if the expression has a space outside parentheses, so it needs
+/// parentheses before a method call
+fn top_level_space(expr: &str) -> bool {
+    let mut depth = 0;
+    for c in expr.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ' ' if depth == 0 => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// The coordinates of `pixel`, the same in GLSL and WGSL
+fn coords_expression(coords: &Coords, resolution: &str) -> String {
+    let position = match coords.sampling {
+        Sampling::Corner => "pixel".to_string(),
+        Sampling::Center => "(pixel + 0.5)".to_string(),
+    };
+    let t = format!("{} / {}", position, resolution);
+
+    let p = match coords.origin {
+        Origin::Center => format!("{} * 2.0 - 1.0", t),
+        Origin::TopLeft => t,
+    };
+
+    match coords.mapping {
+        Mapping::Stretch => p,
+        Mapping::Aspect => format!(
+            "({}) * ({res} / max({res}.x, {res}.y))",
+            p,
+            res = resolution
+        ),
+    }
+}
+
+/// Statements mapping the output `v` to the gamma corrected `level`
+fn transfer_statements(transfer: &Transfer, lang: &dyn Language) -> Vec<String> {
+    let value = match transfer.function {
+        TransferFn::Clamp => "v".to_string(),
+        TransferFn::Sigmoid => "2.0 / (1.0 + exp(-v)) - 1.0".to_string(),
+        TransferFn::Tanh => "tanh(v)".to_string(),
+        TransferFn::SineWrap => format!("sin(v * {})", lang.number(std::f32::consts::FRAC_PI_2)),
+        TransferFn::Modulo => "v + 1.0 - 2.0 * floor((v + 1.0) / 2.0) - 1.0".to_string(),
+    };
+
+    let mut lines = vec![
+        lang.assign("mapped", &value),
+        lang.assign("limited", "clamp(mapped, -1.0, 1.0)"),
+    ];
+
+    if transfer.gamma == 1.0 {
+        lines.push(lang.assign("level", "limited"));
+    } else {
+        let gamma = lang.number(transfer.gamma);
+        let corrected = format!("pow((limited + 1.0) * 0.5, {}) * 2.0 - 1.0", gamma);
+        lines.push(lang.assign("level", &corrected));
+    }
+
+    lines
+}
+
+/// A float literal valid in all the generated languages.
+/// Negative numbers are in parentheses so `a - -1.0` can not happen.
+fn float_literal(x: f32) -> String {
+    let x = finite(x);
+
+    // Debug always includes a decimal point or an exponent
+    let text = format!("{:?}", x);
+    if x.is_sign_negative() {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+/// Infinity and NaN have no literals, use the closest finite values
+fn finite(x: f32) -> f32 {
+    if x.is_nan() {
+        0.0
+    } else {
+        x.clamp(f32::MIN, f32::MAX)
+    }
+}
+
+fn indent(lines: &[String]) -> String {
+    lines
+        .iter()
+        .map(|line| format!("    {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval::eval, state::State};
+
+    /// The program in testdata/pixapprox.rs, generated by to_rust
+    const RUST_FIXTURE_PROGRAM: &str =
+        "x 3.2 * sin y -0.5 * + y x max atan * x y min cos - x 3.2 * sin +";
+
+    /// The program in testdata/extended_ops.rs, using every extended operator
+    const EXTENDED_FIXTURE_PROGRAM: &str = "x y / abs sqrt y exp log x sin tanh - * \
+        x step y floor + x fract 0.3 mod * x y atan2 y -0.5 + select +";
+
+    /// The program in testdata/shapes.rs, using every shape
+    const SHAPES_FIXTURE_PROGRAM: &str = "x 0.3 - y 0.1 + 0.4 0.2 rect x y 0.5 disc + \
+        x 0.2 + y 0.7 -0.4 linedist -0.3 0.6 y smoothstep * + x sin +";
+
+    /// The program in testdata/noise.rs, using every kind of noise
+    const NOISE_FIXTURE_PROGRAM: &str = "x 3 * y 3 * 7 vnoise x 2 * y 2 * 1 perlin + \
+        x 4 * y 4 * 3 worley x y 5.5 fbm * - x sin +";
+
+    mod generated {
+        include!("../testdata/pixapprox.rs");
+    }
+
+    mod extended {
+        include!("../testdata/extended_ops.rs");
+    }
+
+    // max then min, so NaN becomes 0.0 like in the other languages
+    #[allow(clippy::manual_clamp)]
+    mod shapes {
+        include!("../testdata/shapes.rs");
+    }
+
+    #[allow(clippy::manual_clamp)]
+    mod noise {
+        include!("../testdata/noise.rs");
+    }
+
+    /// Checks that to_rust still gives the fixture, and that the compiled
+    /// fixture matches eval bit for bit, also across noise cell borders and
+    /// for negative cells
+    fn check_fixture(program: &str, fixture: &str, generated: fn(f32, f32) -> f32) {
+        let prg: Program = program.parse().unwrap();
+        assert_eq!(fixture, to_rust(&prg));
+
+        for i in 0..=40 {
+            for j in 0..=40 {
+                let x = i as f32 / 20.0 - 1.0;
+                let y = j as f32 / 20.0 - 1.0;
+                let state = State { vars: vec![x, y] };
+
+                assert_eq!(
+                    eval(&prg, &state).to_bits(),
+                    generated(x, y).to_bits(),
+                    "{} at ({}, {})",
+                    program,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn fixtures_round_trip() {
+        check_fixture(
+            RUST_FIXTURE_PROGRAM,
+            include_str!("../testdata/pixapprox.rs"),
+            generated::pixapprox,
+        );
+        check_fixture(
+            EXTENDED_FIXTURE_PROGRAM,
+            include_str!("../testdata/extended_ops.rs"),
+            extended::pixapprox,
+        );
+        check_fixture(
+            SHAPES_FIXTURE_PROGRAM,
+            include_str!("../testdata/shapes.rs"),
+            shapes::pixapprox,
+        );
+        check_fixture(
+            NOISE_FIXTURE_PROGRAM,
+            include_str!("../testdata/noise.rs"),
+            noise::pixapprox,
+        );
+    }
+
+    #[test]
+    pub fn noise_in_each_language() {
+        let prg: Program = "x y 3 perlin".parse().unwrap();
+
+        assert!(to_rust(&prg).contains("fn noise_perlin(x: f32, y: f32, s: f32) -> f32 {"));
+        assert!(to_rust(&prg).contains("noise_perlin(v0, v1, v2)"));
+        assert!(to_c(&prg).contains("#include <stdint.h>\n\n/* The noise matches"));
+        assert!(
+            to_c(&prg).contains("#pragma STDC FP_CONTRACT OFF\n\nstatic int32_t noise_lattice(")
+        );
+        assert!(to_c(&prg).contains("return noise_perlin(v0, v1, v2);"));
+        assert!(to_python(&prg).contains("def noise_perlin(x, y, s):"));
+        assert!(to_glsl(&prg).contains("float noise_perlin(float x, float y, float s) {"));
+        assert!(to_wgsl(&prg).contains("fn noise_perlin(x: f32, y: f32, s: f32) -> f32 {"));
+
+        // Only programs using noise get the helpers
+        let prg: Program = "x y +".parse().unwrap();
+        assert!(!to_c(&prg).contains("noise"));
+        assert!(!to_glsl(&prg).contains("noise"));
+    }
+
+    #[test]
+    pub fn shapes_in_each_language() {
+        let prg: Program = "x y 0.5 disc".parse().unwrap();
+
+        assert!(to_glsl(&prg).contains(
+            "float v3_inside = abs(v2) - sqrt(v0 * v0 + v1 * v1);\n    \
+             return min(max(v3_inside / 0.01 + 0.5, 0.0), 1.0);"
+        ));
+        assert!(to_wgsl(&prg).contains(
+            "let v3_inside = abs(v2) - sqrt(v0 * v0 + v1 * v1);\n    \
+             return min(max(v3_inside / 0.01 + 0.5, 0.0), 1.0);"
+        ));
+        assert!(to_c(&prg).contains(
+            "float v3_inside = fabsf(v2) - sqrtf(v0 * v0 + v1 * v1);\n    \
+             return fminf(fmaxf(v3_inside / 0.01f + 0.5f, 0.0f), 1.0f);"
+        ));
+        assert!(to_python(&prg).contains(
+            "v3_inside = np.abs(v2) - np.sqrt(v0 * v0 + v1 * v1)\n    \
+             return np.fmin(np.fmax(v3_inside / np.float32(0.01) + np.float32(0.5), np.float32(0.0)), np.float32(1.0))"
+        ));
+    }
+
+    #[test]
+    pub fn functions_are_inlined() {
+        let prg: Program = "@fn 2 a0 a1 * a1 +\nx 2 y fn0 *".parse().unwrap();
+        let inlined: Program = "x 2 y * y + *".parse().unwrap();
+
+        // All but the comment with the program
+        let body = |source: String| {
+            source
+                .lines()
+                .filter(|line| !line.contains("Generated by pixapprox from:"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        assert_eq!(body(to_rust(&inlined)), body(to_rust(&prg)));
+        assert_eq!(body(to_glsl(&inlined)), body(to_glsl(&prg)));
+
+        // Noise in a function needs the helpers as well
+        let prg: Program = "@fn 2 a0 a1 3 perlin\nx y fn0".parse().unwrap();
+        let inlined: Program = "x y 3 perlin".parse().unwrap();
+        assert_eq!(body(to_c(&inlined)), body(to_c(&prg)));
+        assert_eq!(body(to_wgsl(&inlined)), body(to_wgsl(&prg)));
+    }
+
+    #[test]
+    pub fn extended_ops_in_each_language() {
+        let lines = |source: String| -> Vec<String> {
+            source
+                .lines()
+                .filter(|line| {
+                    line.contains("v2 =") || line.contains("v3 =") || line.contains("return")
+                })
+                .map(|line| line.trim().to_string())
+                .collect()
+        };
+        let prg: Program = "x y / y atan2".parse().unwrap();
+
+        assert_eq!(
+            vec![
+                "float v2 = (abs(v1) < 1e-6 ? 1.0 : v0 / v1);",
+                "return atan(v2, v1);"
+            ],
+            lines(to_glsl(&prg))
+        );
+        assert_eq!(
+            vec![
+                "let v2 = select(v0 / v1, 1.0, abs(v1) < 1e-6);",
+                "return atan2(v2, v1);",
+                "return vec4<f32>(c, c, c, 1.0);"
+            ],
+            lines(to_wgsl(&prg))
+        );
+        assert_eq!(
+            vec![
+                "float v2 = (fabsf(v1) < 1e-6f ? 1.0f : v0 / v1);",
+                "return atan2f(v2, v1);"
+            ],
+            lines(to_c(&prg))
+        );
+        assert_eq!(
+            vec![
+                "v2 = np.where(np.abs(v1) < np.float32(1e-6), np.float32(1.0), v0 / v1)",
+                "return np.arctan2(v2, v1)"
+            ],
+            lines(to_python(&prg))
+        );
+    }
+
+    #[test]
+    pub fn c_source() {
+        let prg: Program = "x -2 * y min sin".parse().unwrap();
+
+        let expected = "\
+/* Generated by pixapprox from: x -2 * y min sin */
+#include <math.h>
+
+float pixapprox(float x, float y)
+{
+    const float TAU = 6.2831855f;
+
+    float v0 = x;
+    float v1 = (-2.0f);
+    float v2 = v0 * v1;
+    float v3 = y;
+    float v4 = fminf(v3, v2);
+    return sinf(v4 * TAU);
+}
+";
+        assert_eq!(expected, to_c(&prg));
+    }
+
+    #[test]
+    pub fn python_source() {
+        let prg: Program = "x y max atan 0.5 -".parse().unwrap();
+
+        let expected = "\
+# Generated by pixapprox from: x y max atan 0.5 -
+import numpy as np
+
+TAU = np.float32(6.2831855)
+
+
+def pixapprox(x, y):
+    x = np.asarray(x, dtype=np.float32)
+    y = np.asarray(y, dtype=np.float32)
+    v0 = x
+    v1 = y
+    v2 = np.fmax(v1, v0)
+    v3 = np.arctan(v2)
+    v4 = np.float32(0.5)
+    return v3 - v4
+";
+        assert_eq!(expected, to_python(&prg));
+    }
+
+    #[test]
+    pub fn glsl_source() {
+        let prg: Program = "x 3.2 * sin y -0.5 * +".parse().unwrap();
+
+        let expected = "\
+#version 300 es
+// Generated by pixapprox from: x 3.2 * sin y -0.5 * +
+precision highp float;
+
+uniform vec2 u_resolution;
+out vec4 fragColor;
+
+const float TAU = 6.2831855;
+
+float pixapprox(float x, float y) {
+    float v0 = x;
+    float v1 = 3.2;
+    float v2 = v0 * v1;
+    float v3 = sin(v2 * TAU);
+    float v4 = y;
+    float v5 = (-0.5);
+    float v6 = v4 * v5;
+    return v3 + v6;
+}
+
+void main() {
+    // Pixel indices with the origin in the top left corner, like the images
+    vec2 pixel = floor(vec2(gl_FragCoord.x, u_resolution.y - gl_FragCoord.y));
+    vec2 p = pixel / u_resolution * 2.0 - 1.0;
+    float v = pixapprox(p.x, p.y);
+    float mapped = v;
+    float limited = clamp(mapped, -1.0, 1.0);
+    float level = limited;
+    fragColor = vec4(vec3(floor(level * 127.0 + 128.0) / 255.0), 1.0);
+}
+";
+        assert_eq!(expected, to_glsl(&prg));
+    }
+
+    #[test]
+    pub fn wgsl_source() {
+        let prg: Program = "x cos y max".parse().unwrap();
+
+        let expected = "\
+// Generated by pixapprox from: x cos y max
+struct Uniforms {
+    resolution: vec2<f32>,
+}
+
+@group(0) @binding(0) var<uniform> u: Uniforms;
+
+const TAU: f32 = 6.2831855;
+
+fn pixapprox(x: f32, y: f32) -> f32 {
+    let v0 = x;
+    let v1 = cos(v0 * TAU);
+    let v2 = y;
+    return max(v1, v2);
+}
+
+@fragment
+fn main(@builtin(position) position: vec4<f32>) -> @location(0) vec4<f32> {
+    // The position has the origin in the top left corner, like the images
+    let pixel = floor(position.xy);
+    let p = pixel / u.resolution * 2.0 - 1.0;
+    let v = pixapprox(p.x, p.y);
+    let mapped = v;
+    let limited = clamp(mapped, -1.0, 1.0);
+    let level = limited;
+    let c = floor(level * 127.0 + 128.0) / 255.0;
+    return vec4<f32>(c, c, c, 1.0);
+}
+";
+        assert_eq!(expected, to_wgsl(&prg));
+    }
+
+    #[test]
+    pub fn shares_common_subexpressions() {
+        let prg: Program = "x atan x atan *".parse().unwrap();
+        let source = to_glsl(&prg);
+
+        assert_eq!(1, source.matches("atan(").count());
+        assert!(source.contains("return v1 * v1;"));
+    }
+
+    #[test]
+    pub fn transfer_and_gamma() {
+        let prg: Program = "@transfer sigmoid\n@gamma 2.2\nx".parse().unwrap();
+
+        let glsl = to_glsl(&prg);
+        assert!(glsl.contains("// Generated by pixapprox from: @transfer sigmoid; @gamma 2.2; x\n"));
+        assert!(glsl.contains("float mapped = 2.0 / (1.0 + exp(-v)) - 1.0;"));
+        assert!(glsl.contains("float level = pow((limited + 1.0) * 0.5, 2.2) * 2.0 - 1.0;"));
+
+        let wgsl = to_wgsl(&prg);
+        assert!(wgsl.contains("let level = pow((limited + 1.0) * 0.5, 2.2) * 2.0 - 1.0;"));
+    }
+
+    #[test]
+    pub fn shader_coords() {
+        let prg: Program = "@mapping aspect\n@sampling center\nx".parse().unwrap();
+        assert!(to_glsl(&prg).contains(
+            "vec2 p = ((pixel + 0.5) / u_resolution * 2.0 - 1.0) * (u_resolution / max(u_resolution.x, u_resolution.y));"
+        ));
+
+        let prg: Program = "@origin topleft\nx".parse().unwrap();
+        assert!(to_wgsl(&prg).contains("let p = pixel / u.resolution;"));
+    }
+
+    #[test]
+    pub fn extra_inputs() {
+        let prg: Program = "r theta + f1 *".parse().unwrap();
+
+        let rust = to_rust(&prg);
+        assert!(rust.contains("pub fn pixapprox(x: f32, y: f32, f1: f32) -> f32 {"));
+        assert!(rust.contains("let v0 = (x * x + y * y).sqrt();"));
+        assert!(rust.contains("let v1 = y.atan2(x);"));
+
+        let python = to_python(&prg);
+        assert!(python.contains("def pixapprox(x, y, f1):"));
+        assert!(python.contains("    f1 = np.asarray(f1, dtype=np.float32)\n"));
+        assert!(python.contains("v1 = np.arctan2(y, x)"));
+
+        let glsl = to_glsl(&prg);
+        assert!(glsl.contains("uniform sampler2D u_f1;\n"));
+        assert!(glsl.contains("float v = pixapprox(p.x, p.y, f1);"));
+
+        let wgsl = to_wgsl(&prg);
+        assert!(wgsl.contains("@group(0) @binding(2) var t_f1: texture_2d<f32>;\n"));
+        assert!(wgsl.contains("let v1 = atan2(y, x);"));
+    }
+
+    #[test]
+    pub fn float_literals() {
+        assert_eq!("1.0", float_literal(1.0));
+        assert_eq!("(-0.25)", float_literal(-0.25));
+        assert_eq!("1e-5", float_literal(0.00001));
+        assert_eq!("0.0", float_literal(f32::NAN));
+    }
+}
@@ -0,0 +1,198 @@
+use std::{fmt::Display, str::FromStr};
+
+/// How the image rectangle is mapped to the x and y ranges
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mapping {
+    /// Both axes span the full range, whatever the shape of the image
+    Stretch,
+    /// The longer axis spans the full range and the shorter one the same
+    /// length per pixel, so circles stay round on non-square images
+    Aspect,
+}
+
+/// Where in a pixel its coordinate is taken
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sampling {
+    /// The top left corner, so the last pixel ends up below +1.0
+    Corner,
+    /// The centre, symmetric around the middle of the image
+    Center,
+}
+
+/// Where x = 0, y = 0 is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// In the middle, coordinates go from -1.0 to 1.0
+    Center,
+    /// In the top left corner, coordinates go from 0.0 to 1.0
+    TopLeft,
+}
+
+/// The coordinate system the program is evaluated in.
+/// y grows downwards in all of them, like the pixel rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Coords {
+    pub mapping: Mapping,
+    pub sampling: Sampling,
+    pub origin: Origin,
+}
+
+impl Default for Coords {
+    /// x/width * 2 - 1, the mapping programs were always evolved with
+    fn default() -> Self {
+        Self {
+            mapping: Mapping::Stretch,
+            sampling: Sampling::Corner,
+            origin: Origin::Center,
+        }
+    }
+}
+
+impl Coords {
+    pub fn new(mapping: Mapping, sampling: Sampling, origin: Origin) -> Self {
+        Self {
+            mapping,
+            sampling,
+            origin,
+        }
+    }
+
+    /// Returns the x coordinate of every column and the y coordinate of
+    /// every row of an image
+    pub fn axes(&self, width: i32, height: i32) -> (Vec<f32>, Vec<f32>) {
+        let (x_scale, y_scale) = self.scales(width, height);
+
+        let xs = (0..width).map(|x| self.coord(x, width, x_scale)).collect();
+        let ys = (0..height)
+            .map(|y| self.coord(y, height, y_scale))
+            .collect();
+
+        (xs, ys)
+    }
+
+    /// The part of the full range each axis spans
+    pub fn scales(&self, width: i32, height: i32) -> (f32, f32) {
+        match self.mapping {
+            Mapping::Stretch => (1.0, 1.0),
+            Mapping::Aspect => {
+                let longest = width.max(height) as f32;
+                (width as f32 / longest, height as f32 / longest)
+            }
+        }
+    }
+
+    /// The sub-pixel position coordinates are taken at, 0.0 to 1.0
+    pub fn offset(&self) -> f32 {
+        match self.sampling {
+            Sampling::Corner => 0.0,
+            Sampling::Center => 0.5,
+        }
+    }
+
+    /// Converts a pixel index 0..size to a coordinate
+    fn coord(&self, i: i32, size: i32, scale: f32) -> f32 {
+        let t = (i as f32 + self.offset()) / size as f32;
+
+        match self.origin {
+            Origin::Center => (t * 2.0 - 1.0) * scale,
+            Origin::TopLeft => t * scale,
+        }
+    }
+}
+
+impl Display for Mapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mapping::Stretch => write!(f, "stretch"),
+            Mapping::Aspect => write!(f, "aspect"),
+        }
+    }
+}
+
+impl FromStr for Mapping {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stretch" => Ok(Mapping::Stretch),
+            "aspect" => Ok(Mapping::Aspect),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Sampling {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Sampling::Corner => write!(f, "corner"),
+            Sampling::Center => write!(f, "center"),
+        }
+    }
+}
+
+impl FromStr for Sampling {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "corner" => Ok(Sampling::Corner),
+            "center" => Ok(Sampling::Center),
+            _ => Err(()),
+        }
+    }
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Center => write!(f, "center"),
+            Origin::TopLeft => write!(f, "topleft"),
+        }
+    }
+}
+
+impl FromStr for Origin {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "center" => Ok(Origin::Center),
+            "topleft" => Ok(Origin::TopLeft),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixapprox::pixel_coord;
+
+    #[test]
+    pub fn default_is_pixel_coord() {
+        let (xs, ys) = Coords::default().axes(7, 3);
+
+        let expected: Vec<f32> = (0..7).map(|x| pixel_coord(x, 7)).collect();
+        assert_eq!(expected, xs);
+        let expected: Vec<f32> = (0..3).map(|y| pixel_coord(y, 3)).collect();
+        assert_eq!(expected, ys);
+    }
+
+    #[test]
+    pub fn aspect_keeps_pixels_square() {
+        let coords = Coords::new(Mapping::Aspect, Sampling::Center, Origin::Center);
+        let (xs, ys) = coords.axes(4, 2);
+
+        assert_eq!(vec![-0.75, -0.25, 0.25, 0.75], xs);
+        assert_eq!(vec![-0.25, 0.25], ys);
+    }
+
+    #[test]
+    pub fn top_left_origin() {
+        let coords = Coords::new(Mapping::Aspect, Sampling::Corner, Origin::TopLeft);
+        let (xs, ys) = coords.axes(2, 4);
+
+        assert_eq!(vec![0.0, 0.25], xs);
+        assert_eq!(vec![0.0, 0.25, 0.5, 0.75], ys);
+    }
+}
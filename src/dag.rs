@@ -0,0 +1,473 @@
+use std::{collections::HashMap, fmt::Display, mem::Discriminant};
+
+use smallvec::SmallVec;
+
+use crate::{
+    eval::apply,
+    expr::{Expr, Program, MAX_ARITY},
+    inputs::{set_derived_inputs, uses_derived_inputs, FIRST_FEATURE, X, Y},
+    state::State,
+};
+
+/// to_code gives up on code longer than this. Shared nodes are repeated,
+/// so the length can double with every level of sharing.
+const MAX_CODE_LEN: usize = 10_000;
+
+/// A single operation, computed from the values of earlier nodes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub op: Expr,
+    pub args: SmallVec<[usize; MAX_ARITY]>,
+}
+
+/// Identifies equal nodes, f32 is neither Eq nor Hash so constants are
+/// compared by their bits
+type NodeKey = (Discriminant<Expr>, u64, SmallVec<[usize; MAX_ARITY]>);
+
+/// A program as a directed acyclic graph in SSA form.
+///
+/// Identical subexpressions are only computed once. Every node only
+/// refers to nodes before it, so evaluating the nodes in order works.
+#[derive(Debug, Clone)]
+pub struct Dag {
+    pub nodes: Vec<Node>,
+    pub root: usize,
+}
+
+impl Dag {
+    /// Builds the DAG, sharing common subexpressions. Calls are expanded,
+    /// the nodes of the function code are computed from the nodes of the operands.
+    pub fn from_program(prg: &Program) -> Self {
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut known: HashMap<NodeKey, usize> = HashMap::new();
+        let root = add_code(prg, &prg.code, &[], &mut nodes, &mut known);
+
+        Self { nodes, root }
+    }
+
+    /// Evaluates all nodes, `values` is scratch space reused between calls
+    pub fn eval(&self, state: &State, values: &mut Vec<f32>) -> f32 {
+        values.clear();
+        values.resize(self.nodes.len(), 0.0);
+
+        for id in 0..self.nodes.len() {
+            values[id] = self.eval_node(id, values, &state.vars);
+        }
+
+        values[self.root]
+    }
+
+    /// Returns for every node whether it depends on x (bit 0) and on y (bit 1).
+    /// r, θ and the features depend on both.
+    pub fn var_masks(&self) -> Vec<u32> {
+        let mut masks: Vec<u32> = Vec::with_capacity(self.nodes.len());
+
+        for node in self.nodes.iter() {
+            let mask = match node.op {
+                Expr::Var(X) => 1 << 0,
+                Expr::Var(Y) => 1 << 1,
+                Expr::Var(_) => 1 << 0 | 1 << 1,
+                _ => node.args.iter().fold(0, |mask, &id| mask | masks[id]),
+            };
+            masks.push(mask);
+        }
+
+        masks
+    }
+
+    /// Evaluates the DAG on a grid where x takes the values in `xs` and
+    /// y the values in `ys`, returns the outputs row by row.
+    ///
+    /// Nodes that only depend on x are computed once per column and nodes
+    /// that only depend on y once per row, so only the mixed ones are
+    /// computed for every pixel. Constant nodes are computed once.
+    pub fn eval_grid(&self, xs: &[f32], ys: &[f32]) -> Vec<f32> {
+        self.eval_grid_with_features(xs, ys, &[])
+    }
+
+    /// Like eval_grid, with the values of the feature channels at every
+    /// point of the grid, row by row
+    pub fn eval_grid_with_features(&self, xs: &[f32], ys: &[f32], features: &[&[f32]]) -> Vec<f32> {
+        const X_MASK: u32 = 1 << 0;
+        const Y_MASK: u32 = 1 << 1;
+
+        let masks = self.var_masks();
+        let by_mask = |wanted: u32| -> Vec<usize> {
+            (0..self.nodes.len())
+                .filter(|&id| masks[id] == wanted)
+                .collect()
+        };
+        let const_nodes = by_mask(0);
+        let x_nodes = by_mask(X_MASK);
+        let y_nodes = by_mask(Y_MASK);
+        let mixed_nodes = by_mask(X_MASK | Y_MASK);
+        let derived = uses_derived_inputs(self.nodes.iter().map(|node| &node.op));
+
+        let mut values = vec![0.0; self.nodes.len()];
+        let mut vars = vec![0.0; FIRST_FEATURE + features.len()];
+
+        for &id in const_nodes.iter() {
+            values[id] = self.eval_node(id, &values, &vars);
+        }
+
+        // The x-only values for every column
+        let mut columns = Vec::with_capacity(xs.len() * x_nodes.len());
+        for &x in xs {
+            vars[X] = x;
+            for &id in x_nodes.iter() {
+                values[id] = self.eval_node(id, &values, &vars);
+                columns.push(values[id]);
+            }
+        }
+
+        let mut outputs = Vec::with_capacity(xs.len() * ys.len());
+        for &y in ys {
+            vars[Y] = y;
+            for &id in y_nodes.iter() {
+                values[id] = self.eval_node(id, &values, &vars);
+            }
+
+            for (i, &x) in xs.iter().enumerate() {
+                let column = &columns[i * x_nodes.len()..(i + 1) * x_nodes.len()];
+                vars[X] = x;
+                if derived {
+                    set_derived_inputs(&mut vars, features, outputs.len());
+                }
+                for (&id, &value) in x_nodes.iter().zip(column) {
+                    values[id] = value;
+                }
+                for &id in mixed_nodes.iter() {
+                    values[id] = self.eval_node(id, &values, &vars);
+                }
+
+                outputs.push(values[self.root]);
+            }
+        }
+
+        outputs
+    }
+
+    /// Computes a single node from the values of the nodes before it
+    #[inline(always)]
+    fn eval_node(&self, id: usize, values: &[f32], vars: &[f32]) -> f32 {
+        let node = &self.nodes[id];
+        match node.op {
+            Expr::Const(x) => x,
+            Expr::Var(i) => vars[i],
+            op => {
+                let mut args = [0.0; MAX_ARITY];
+                for (arg, &id) in args.iter_mut().zip(node.args.iter()) {
+                    *arg = values[id];
+                }
+                apply(op, &args[..node.args.len()])
+            }
+        }
+    }
+
+    /// Expands the DAG back to postfix code, or returns None if it would be
+    /// longer than MAX_CODE_LEN. An operand that is the same node as the one
+    /// before it becomes a Dup, other shared nodes are repeated.
+    pub fn to_code(&self) -> Option<Vec<Expr>> {
+        let mut lengths: Vec<usize> = Vec::with_capacity(self.nodes.len());
+        for node in self.nodes.iter() {
+            let length = node.args.iter().enumerate().fold(1usize, |sum, (k, &arg)| {
+                let arg_length = if k > 0 && node.args[k - 1] == arg {
+                    1
+                } else {
+                    lengths[arg]
+                };
+                sum.saturating_add(arg_length)
+            });
+            lengths.push(length);
+        }
+        if lengths[self.root] > MAX_CODE_LEN {
+            return None;
+        }
+
+        let mut code = Vec::with_capacity(lengths[self.root]);
+        self.emit(self.root, &mut code);
+        Some(code)
+    }
+
+    fn emit(&self, id: usize, code: &mut Vec<Expr>) {
+        let node = &self.nodes[id];
+        for (k, &arg) in node.args.iter().enumerate() {
+            if k > 0 && node.args[k - 1] == arg {
+                code.push(Expr::Dup);
+            } else {
+                self.emit(arg, code);
+            }
+        }
+        code.push(node.op);
+    }
+}
+
+/// Adds the nodes of `code` and returns the node of its result. `args` are
+/// the nodes of the operands when the code is that of a function.
+fn add_code(
+    prg: &Program,
+    code: &[Expr],
+    args: &[usize],
+    nodes: &mut Vec<Node>,
+    known: &mut HashMap<NodeKey, usize>,
+) -> usize {
+    let mut stack: Vec<usize> = Vec::new();
+
+    for expr in code.iter() {
+        // The nodes hold the values of pooled constants
+        let expr = match *expr {
+            Expr::ConstRef(i) => Expr::Const(prg.consts[i]),
+            expr => expr,
+        };
+        match expr {
+            Expr::Drop => {
+                panic!("Drop not done");
+            }
+            Expr::Dup => {
+                let a = *stack.last().expect("Stack underflow");
+                stack.push(a);
+            }
+            Expr::Arg(i) => stack.push(args[i]),
+            Expr::Call(function, arity) => {
+                let arity = arity as usize;
+                assert!(stack.len() >= arity, "Stack underflow");
+                let operands: SmallVec<[usize; MAX_ARITY]> =
+                    stack.drain(stack.len() - arity..).collect();
+                let function = &prg.functions[function as usize];
+                let id = add_code(prg, &function.code, &operands, nodes, known);
+                stack.push(id);
+            }
+            op => {
+                let n = op.arity();
+                assert!(stack.len() >= n, "Stack underflow");
+                let mut args: SmallVec<[usize; MAX_ARITY]> =
+                    stack.drain(stack.len() - n..).collect();
+
+                // Add and Mul give the same result in any order,
+                // so x y + and y x + are the same node
+                if matches!(op, Expr::Add | Expr::Mul) {
+                    args.sort_unstable();
+                }
+
+                let key = (std::mem::discriminant(&op), payload(&op), args.clone());
+                let id = *known.entry(key).or_insert_with(|| {
+                    nodes.push(Node { op, args });
+                    nodes.len() - 1
+                });
+                stack.push(id);
+            }
+        }
+    }
+
+    assert!(
+        stack.len() == 1,
+        "Stack should contain exactly 1 item but had {} items",
+        stack.len()
+    );
+    stack[0]
+}
+
+/// Returns the program with every call replaced by the code of the function,
+/// and the pool written back into the code. Shared subexpressions are
+/// repeated like in to_code, None if that makes the code too long.
+pub fn inline_calls(prg: &Program) -> Option<Program> {
+    if prg.functions.is_empty() {
+        return Some(prg.inlined());
+    }
+
+    let code = Dag::from_program(prg).to_code()?;
+    Some(Program {
+        consts: Vec::new(),
+        functions: Vec::new(),
+        ..prg.with_code(code)
+    })
+}
+
+/// The data carried by an instruction, as bits
+fn payload(op: &Expr) -> u64 {
+    match op {
+        Expr::Const(x) => x.to_bits() as u64,
+        Expr::Var(i) => *i as u64,
+        _ => 0,
+    }
+}
+
+/// Writes one node per line, e.g. `t2 = t0 t1 *`
+impl Display for Dag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (id, node) in self.nodes.iter().enumerate() {
+            write!(f, "t{} =", id)?;
+            for arg in node.args.iter() {
+                write!(f, " t{}", arg)?;
+            }
+            writeln!(f, " {}", node.op)?;
+        }
+        write!(f, "return t{}", self.root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        eval::eval,
+        inputs::{R, THETA},
+        mutate::{random_code, OpSet},
+    };
+
+    #[test]
+    pub fn shares_common_subexpressions() {
+        // x sin y * x sin +
+        let prg = Program::new(vec![
+            Expr::Var(0),
+            Expr::Sin,
+            Expr::Var(1),
+            Expr::Mul,
+            Expr::Var(0),
+            Expr::Sin,
+            Expr::Add,
+        ]);
+
+        let dag = Dag::from_program(&prg);
+
+        assert_eq!(5, dag.nodes.len());
+        assert_eq!(
+            "t0 = x\nt1 = t0 sin\nt2 = y\nt3 = t1 t2 *\nt4 = t1 t3 +\nreturn t4",
+            format!("{}", dag)
+        );
+    }
+
+    #[test]
+    pub fn commutative_operands_are_shared() {
+        // x y + y x + *
+        let prg = Program::new(vec![
+            Expr::Var(0),
+            Expr::Var(1),
+            Expr::Add,
+            Expr::Var(1),
+            Expr::Var(0),
+            Expr::Add,
+            Expr::Mul,
+        ]);
+
+        assert_eq!(4, Dag::from_program(&prg).nodes.len());
+    }
+
+    #[test]
+    pub fn dup_shares_node() {
+        let prg = Program::new(vec![Expr::Var(0), Expr::Dup, Expr::Mul]);
+        let dag = Dag::from_program(&prg);
+
+        assert_eq!(2, dag.nodes.len());
+        assert_eq!(prg.code, dag.to_code().unwrap());
+    }
+
+    #[test]
+    pub fn long_expansions_are_refused() {
+        // Each step squares the value before, the code stays short
+        let squares = "@fn 1 a0 a0 *\nx".to_string() + &" fn0".repeat(30);
+        let prg: Program = squares.parse().unwrap();
+        let code = Dag::from_program(&prg).to_code().unwrap();
+        assert_eq!(1 + 2 * 30, code.len());
+
+        // Each step uses the value before twice apart, doubling the code
+        let doubling = "@fn 1 a0 a0 sin *\nx".to_string() + &" fn0".repeat(30);
+        let prg: Program = doubling.parse().unwrap();
+        assert_eq!(None, Dag::from_program(&prg).to_code());
+        assert_eq!(None, inline_calls(&prg));
+    }
+
+    #[test]
+    pub fn classifies_dependencies() {
+        // x sin y * 2 3 * +
+        let prg = Program::new(vec![
+            Expr::Var(0),
+            Expr::Sin,
+            Expr::Var(1),
+            Expr::Mul,
+            Expr::Const(2.0),
+            Expr::Const(3.0),
+            Expr::Mul,
+            Expr::Add,
+        ]);
+
+        let masks = Dag::from_program(&prg).var_masks();
+
+        assert_eq!(vec![1, 1, 2, 3, 0, 0, 0, 3], masks);
+    }
+
+    #[test]
+    pub fn eval_grid_matches_stack_eval() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let mut state = State::new(2);
+        let xs = [-1.0, -0.5, 0.25, 0.75];
+        let ys = [-1.0, 0.0, 0.5];
+
+        for _ in 0..200 {
+            let prg = Program::new(random_code(&mut rng, 5, &[X, Y], &OpSet::DEFAULT));
+            let outputs = Dag::from_program(&prg).eval_grid(&xs, &ys);
+
+            let mut expected = Vec::new();
+            for &y in ys.iter() {
+                for &x in xs.iter() {
+                    state.vars[0] = x;
+                    state.vars[1] = y;
+                    expected.push(eval(&prg, &state).to_bits());
+                }
+            }
+            let actual: Vec<u32> = outputs.iter().map(|v| v.to_bits()).collect();
+            assert_eq!(expected, actual, "{}", prg);
+        }
+    }
+
+    #[test]
+    pub fn eval_grid_derived_inputs() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut state = State::new(FIRST_FEATURE + 1);
+        let xs = [-1.0, 0.0, 0.75];
+        let ys = [-0.5, 0.5];
+        let feature = [0.1, -0.2, 0.3, -0.4, 0.5, -0.6];
+
+        for _ in 0..200 {
+            let vars = [X, Y, R, THETA, FIRST_FEATURE];
+            let prg = Program::new(random_code(&mut rng, 5, &vars, &OpSet::DEFAULT));
+            let outputs = Dag::from_program(&prg).eval_grid_with_features(&xs, &ys, &[&feature]);
+
+            let mut expected = Vec::new();
+            for &y in ys.iter() {
+                for &x in xs.iter() {
+                    state.vars[X] = x;
+                    state.vars[Y] = y;
+                    set_derived_inputs(&mut state.vars, &[&feature], expected.len());
+                    expected.push(eval(&prg, &state).to_bits());
+                }
+            }
+            let actual: Vec<u32> = outputs.iter().map(|v| v.to_bits()).collect();
+            assert_eq!(expected, actual, "{}", prg);
+        }
+    }
+
+    #[test]
+    pub fn eval_matches_stack_eval() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut state = State::new(2);
+        let mut values = Vec::new();
+
+        for _ in 0..200 {
+            let prg = Program::new(random_code(&mut rng, 5, &[X, Y], &OpSet::DEFAULT));
+            let dag = Dag::from_program(&prg);
+            let expanded = prg.with_code(dag.to_code().unwrap());
+
+            for (x, y) in [(-1.0, -1.0), (-0.3, 0.5), (0.0, 0.0), (0.9, -0.7)] {
+                state.vars[0] = x;
+                state.vars[1] = y;
+
+                let expected = eval(&prg, &state);
+                assert_eq!(expected.to_bits(), dag.eval(&state, &mut values).to_bits());
+                assert_eq!(expected.to_bits(), eval(&expanded, &state).to_bits());
+            }
+        }
+    }
+}
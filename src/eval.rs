@@ -1,11 +1,15 @@
 use std::f32::consts::TAU;
 
 use crate::{
-    expr::{Expr, Program},
+    expr::{Expr, Function, Program, MAX_ARITY},
+    noise, shapes,
     stack::Stack2,
     state::State,
 };
 
+/// Divisors, and arguments to Log, closer to zero than this are protected
+pub const PROTECTED_EPSILON: f32 = 1e-6;
+
 pub fn eval(prg: &Program, state: &State) -> f32 {
     let mut stack = Stack2::new();
 
@@ -13,6 +17,16 @@ pub fn eval(prg: &Program, state: &State) -> f32 {
         match *expr {
             Expr::Const(x) => stack.push(x),
             Expr::Var(i) => stack.push(state.vars[i]),
+            Expr::ConstRef(i) => stack.push(prg.consts[i]),
+            Expr::Drop => {
+                panic!("Drop not done");
+            }
+            Expr::Dup => {
+                let a = stack.pop();
+                stack.push(a);
+                stack.push(a);
+            }
+            // The default operators directly, the others through apply
             Expr::Add => {
                 let a = stack.pop();
                 let b = stack.pop();
@@ -50,21 +64,122 @@ pub fn eval(prg: &Program, state: &State) -> f32 {
                 let a = stack.pop();
                 stack.push(a.atan())
             }
-            Expr::Drop => {
-                panic!("Drop not done");
-                stack.pop();
+            op => {
+                let n = op.arity();
+                let mut args = [0.0; MAX_ARITY];
+                for arg in args[..n].iter_mut().rev() {
+                    *arg = stack.pop();
+                }
+                let value = match op {
+                    Expr::Call(function, _) => call(&prg.functions[function as usize], &args[..n]),
+                    op => apply(op, &args[..n]),
+                };
+                stack.push(value)
             }
+        }
+    }
+
+    stack.result()
+}
+
+/// Evaluates the code of a function with `args` as its operands
+pub fn call(function: &Function, args: &[f32]) -> f32 {
+    let mut stack = Stack2::new();
+
+    for expr in function.code.iter() {
+        match *expr {
+            Expr::Const(x) => stack.push(x),
+            Expr::Arg(i) => stack.push(args[i]),
             Expr::Dup => {
                 let a = stack.pop();
                 stack.push(a);
                 stack.push(a);
             }
+            Expr::Var(_) | Expr::ConstRef(_) | Expr::Call(..) | Expr::Drop => {
+                panic!("call: {} is not allowed in a function", expr);
+            }
+            op => {
+                let n = op.arity();
+                let mut operands = [0.0; MAX_ARITY];
+                for operand in operands[..n].iter_mut().rev() {
+                    *operand = stack.pop();
+                }
+                stack.push(apply(op, &operands[..n]))
+            }
         }
     }
 
     stack.result()
 }
 
+/// Applies an operator to its operands.
+/// The operands are in the order they were pushed, so for `b a -`
+/// args is [b, a] and the result is b - a.
+#[inline(always)]
+pub fn apply(op: Expr, args: &[f32]) -> f32 {
+    match op {
+        Expr::Add => args[1] + args[0],
+        Expr::Sub => args[0] - args[1],
+        Expr::Mul => args[1] * args[0],
+        Expr::Max => args[1].max(args[0]),
+        Expr::Min => args[1].min(args[0]),
+        Expr::Cos => (args[0] * TAU).cos(),
+        Expr::Sin => (args[0] * TAU).sin(),
+        Expr::Atan => args[0].atan(),
+        Expr::Div => {
+            if args[1].abs() < PROTECTED_EPSILON {
+                1.0
+            } else {
+                args[0] / args[1]
+            }
+        }
+        Expr::Mod => {
+            if args[1].abs() < PROTECTED_EPSILON {
+                0.0
+            } else {
+                args[0] - args[1] * (args[0] / args[1]).floor()
+            }
+        }
+        Expr::Atan2 => args[0].atan2(args[1]),
+        Expr::Abs => args[0].abs(),
+        Expr::Sqrt => args[0].abs().sqrt(),
+        Expr::Exp => args[0].exp(),
+        Expr::Log => {
+            if args[0].abs() < PROTECTED_EPSILON {
+                0.0
+            } else {
+                args[0].abs().ln()
+            }
+        }
+        Expr::Tanh => args[0].tanh(),
+        Expr::Step => {
+            if args[0] < 0.0 {
+                0.0
+            } else {
+                1.0
+            }
+        }
+        Expr::Floor => args[0].floor(),
+        Expr::Fract => args[0] - args[0].floor(),
+        Expr::Select => {
+            if args[0] > 0.0 {
+                args[1]
+            } else {
+                args[2]
+            }
+        }
+        Expr::Rect => shapes::rect(args[0], args[1], args[2], args[3]),
+        Expr::Disc => shapes::disc(args[0], args[1], args[2]),
+        Expr::LineDist => shapes::line_dist(args[0], args[1], args[2], args[3]),
+        Expr::Smoothstep => shapes::smoothstep(args[0], args[1], args[2]),
+        Expr::ValueNoise => noise::value_noise(args[0], args[1], args[2]),
+        Expr::Perlin => noise::perlin(args[0], args[1], args[2]),
+        Expr::Worley => noise::worley(args[0], args[1], args[2]),
+        Expr::Fbm => noise::fbm(args[0], args[1], args[2]),
+        _ => panic!("apply: {} is not an operator", op),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,6 +189,7 @@ mod tests {
         let state = State { vars: vec![] };
         let prg = Program {
             code: vec![Expr::Const(2.0), Expr::Const(3.0), Expr::Add],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -87,6 +203,7 @@ mod tests {
         };
         let prg = Program {
             code: vec![Expr::Var(0), Expr::Var(1), Expr::Add],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -98,6 +215,7 @@ mod tests {
         let state = State { vars: vec![] };
         let prg = Program {
             code: vec![Expr::Const(2.0), Expr::Const(3.0), Expr::Sub],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -111,6 +229,7 @@ mod tests {
         };
         let prg = Program {
             code: vec![Expr::Var(0), Expr::Var(1), Expr::Sub],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -122,6 +241,7 @@ mod tests {
         let state = State { vars: vec![] };
         let prg = Program {
             code: vec![Expr::Const(2.0), Expr::Const(3.0), Expr::Mul],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -135,6 +255,7 @@ mod tests {
         };
         let prg = Program {
             code: vec![Expr::Var(0), Expr::Var(1), Expr::Mul],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -146,6 +267,7 @@ mod tests {
         let state = State { vars: vec![] };
         let prg = Program {
             code: vec![Expr::Const(0.0), Expr::Cos],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -157,6 +279,7 @@ mod tests {
         let state = State { vars: vec![0.0] };
         let prg = Program {
             code: vec![Expr::Var(0), Expr::Cos],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -168,6 +291,7 @@ mod tests {
         let state = State { vars: vec![] };
         let prg = Program {
             code: vec![Expr::Const(0.0), Expr::Atan],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -179,18 +303,51 @@ mod tests {
         let state = State { vars: vec![0.0] };
         let prg = Program {
             code: vec![Expr::Var(0), Expr::Atan],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
         assert_eq!(0.0, result);
     }
 
+    #[test]
+    pub fn protected_ops() {
+        let apply_consts = |text: &str| {
+            let prg: Program = text.parse().unwrap();
+            eval(&prg, &State { vars: vec![] })
+        };
+
+        assert_eq!(1.0, apply_consts("3 0 /"));
+        assert_eq!(-1.5, apply_consts("3 -2 /"));
+        assert_eq!(0.0, apply_consts("3 0 mod"));
+        assert_eq!(1.0, apply_consts("-3 2 mod"));
+        assert_eq!(3.0, apply_consts("-9 sqrt"));
+        assert_eq!(0.0, apply_consts("0 log"));
+        assert_eq!(0.0, apply_consts("1 log"));
+    }
+
+    #[test]
+    pub fn step_fract_select() {
+        let apply_consts = |text: &str| {
+            let prg: Program = text.parse().unwrap();
+            eval(&prg, &State { vars: vec![] })
+        };
+
+        assert_eq!(0.0, apply_consts("-0.1 step"));
+        assert_eq!(1.0, apply_consts("0 step"));
+        assert_eq!(0.25, apply_consts("-1.75 fract"));
+        assert_eq!(2.0, apply_consts("0.5 2 3 select"));
+        assert_eq!(3.0, apply_consts("0 2 3 select"));
+        assert_eq!(1.0, apply_consts("1 1 atan2") * 4.0 / std::f32::consts::PI);
+    }
+
     #[test]
     #[should_panic]
     pub fn underflow_op_1() {
         let state = State { vars: vec![] };
         let prg = Program {
             code: vec![Expr::Const(1.0), Expr::Add],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
@@ -208,6 +365,7 @@ mod tests {
                 Expr::Const(1.0),
                 Expr::Add,
             ],
+            ..Default::default()
         };
 
         let result = eval(&prg, &state);
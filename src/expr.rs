@@ -1,13 +1,79 @@
-use core::panic;
 use std::fmt::Display;
 
-#[derive(Debug, Clone)]
+use crate::{
+    coords::Coords,
+    inputs::{FIRST_FEATURE, R, THETA, X, Y},
+    transfer::{Transfer, TransferFn},
+};
+
+/// The highest number of operands any instruction takes
+pub const MAX_ARITY: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct Program {
     pub code: Vec<Expr>,
+    /// How the output is turned into pixels
+    pub transfer: Transfer,
+    /// How pixels are mapped to x and y
+    pub coords: Coords,
+    /// The width and height of the image the program was evolved for,
+    /// used to keep the aspect ratio when rendering at other sizes
+    pub size: Option<(i32, i32)>,
+    /// The constant pool ConstRef instructions refer to, empty unless the
+    /// constants are pooled
+    pub consts: Vec<f32>,
+    /// The functions Call instructions call, evolved along with the code
+    pub functions: Vec<Function>,
+}
+
+/// An automatically defined function, a subroutine of the program whose
+/// code uses its operands as Arg(0) and Arg(1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// 1 or 2
+    pub arity: usize,
+    pub code: Vec<Expr>,
 }
 
 impl Display for Program {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Settings that differ from the default are written as directives
+        // on their own lines before the code
+        if self.transfer.function != TransferFn::Clamp {
+            writeln!(f, "@transfer {}", self.transfer.function)?;
+        }
+        if self.transfer.gamma != 1.0 {
+            writeln!(f, "@gamma {}", self.transfer.gamma)?;
+        }
+        let coords = Coords::default();
+        if self.coords.mapping != coords.mapping {
+            writeln!(f, "@mapping {}", self.coords.mapping)?;
+        }
+        if self.coords.sampling != coords.sampling {
+            writeln!(f, "@sampling {}", self.coords.sampling)?;
+        }
+        if self.coords.origin != coords.origin {
+            writeln!(f, "@origin {}", self.coords.origin)?;
+        }
+        if let Some((width, height)) = self.size {
+            writeln!(f, "@size {} {}", width, height)?;
+        }
+        if !self.consts.is_empty() {
+            write!(f, "@consts")?;
+            for x in self.consts.iter() {
+                write!(f, " {}", x)?;
+            }
+            writeln!(f)?;
+        }
+        // Function n is the nth @fn, written as its arity and its code
+        for function in self.functions.iter() {
+            write!(f, "@fn {}", function.arity)?;
+            for inst in function.code.iter() {
+                write!(f, " {}", inst)?;
+            }
+            writeln!(f)?;
+        }
+
         let mut b = false;
         for inst in self.code.iter() {
             if b {
@@ -21,11 +87,138 @@ impl Display for Program {
     }
 }
 
+impl Program {
+    pub fn new(code: Vec<Expr>) -> Self {
+        Self {
+            code,
+            transfer: Transfer::default(),
+            coords: Coords::default(),
+            size: None,
+            consts: Vec::new(),
+            functions: Vec::new(),
+        }
+    }
+
+    /// Returns a program with other code but the same settings, pool and
+    /// functions
+    pub fn with_code(&self, code: Vec<Expr>) -> Self {
+        Self {
+            code,
+            transfer: self.transfer,
+            coords: self.coords,
+            size: self.size,
+            consts: self.consts.clone(),
+            functions: self.functions.clone(),
+        }
+    }
+
+    /// Returns the program with all constants moved to the pool.
+    ///
+    /// Every inline constant gets an entry of its own, instructions
+    /// referring to the same entry keep sharing it and entries nothing
+    /// refers to are dropped. The entries are in the order they are first used.
+    pub fn pooled(&self) -> Self {
+        let mut consts = Vec::new();
+        let mut new_index = vec![None; self.consts.len()];
+
+        let code = self
+            .code
+            .iter()
+            .map(|expr| match *expr {
+                Expr::Const(x) => {
+                    consts.push(x);
+                    Expr::ConstRef(consts.len() - 1)
+                }
+                Expr::ConstRef(i) => {
+                    let j = *new_index[i].get_or_insert_with(|| {
+                        consts.push(self.consts[i]);
+                        consts.len() - 1
+                    });
+                    Expr::ConstRef(j)
+                }
+                expr => expr,
+            })
+            .collect();
+
+        Self {
+            consts,
+            ..self.with_code(code)
+        }
+    }
+
+    /// Returns the program with the pool written back into the code
+    pub fn inlined(&self) -> Self {
+        let code = self
+            .code
+            .iter()
+            .map(|expr| match *expr {
+                Expr::ConstRef(i) => Expr::Const(self.consts[i]),
+                expr => expr,
+            })
+            .collect();
+
+        Self {
+            consts: Vec::new(),
+            ..self.with_code(code)
+        }
+    }
+
+    /// Returns true if the code leaves exactly one value on the stack
+    /// without underflowing on the way.
+    pub fn is_well_formed(&self) -> bool {
+        let mut depth = 0usize;
+
+        for expr in self.code.iter() {
+            let (npop, npush) = expr.stack_effect();
+            if depth < npop {
+                return false;
+            }
+            depth = depth - npop + npush;
+        }
+
+        depth == 1
+    }
+}
+
+/// Returns the index where the subtree ending at `end` begins.
+///
+/// The subtree is the shortest slice `code[start..=end]` that pushes
+/// exactly one value without consuming anything below it.
+/// None is returned if the instruction at `end` does not push a single
+/// value, or if part of the slice feeds values outside of it (e.g. Dup).
+pub fn subtree_start(code: &[Expr], end: usize) -> Option<usize> {
+    if code[end].stack_effect().1 != 1 {
+        return None;
+    }
+
+    let mut need = 1usize;
+    let mut i = end;
+    loop {
+        let (npop, npush) = code[i].stack_effect();
+        if need < npush {
+            return None;
+        }
+        need = need - npush + npop;
+
+        if need == 0 {
+            return Some(i);
+        }
+        if i == 0 {
+            return None;
+        }
+        i -= 1;
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Expr {
     // Values
     Const(f32),
     Var(usize),
+    /// `c2` is entry 2 of the program's constant pool
+    ConstRef(usize),
+    /// `a1` is the second operand, in the code of a function
+    Arg(usize),
 
     // Binary operators
     Add,
@@ -33,11 +226,53 @@ pub enum Expr {
     Mul,
     Max,
     Min,
+    /// Protected division, 1 when dividing by (almost) zero
+    Div,
+    /// Floored modulo with the sign of the divisor, 0 for a divisor of (almost) zero
+    Mod,
+    /// `y x atan2` is the angle of the point (x, y)
+    Atan2,
 
     // Unary operators
     Cos,
     Sin,
     Atan,
+    Abs,
+    /// Protected square root, sqrt(|a|)
+    Sqrt,
+    Exp,
+    /// Protected logarithm, ln(|a|) and 0 for (almost) zero
+    Log,
+    Tanh,
+    /// 0 below zero, 1 otherwise
+    Step,
+    Floor,
+    /// The fractional part, a - floor(a)
+    Fract,
+
+    // Ternary operators
+    /// `c a b select` is a if c > 0, otherwise b
+    Select,
+
+    // Shapes, see shapes.rs
+    /// `x y w h rect` is 1 inside the rectangle |x| < |w|, |y| < |h|, 0 outside
+    Rect,
+    /// `x y r disc` is 1 inside the disc with radius |r|, 0 outside
+    Disc,
+    /// `x y a b linedist` is the distance to the segment from the origin to (a, b)
+    LineDist,
+    /// `e0 e1 a smoothstep` goes smoothly from 0 at e0 to 1 at e1
+    Smoothstep,
+
+    // Noise, see noise.rs. `x y s perlin` is seeded by the integer part of s
+    ValueNoise,
+    Perlin,
+    Worley,
+    Fbm,
+
+    /// `fn2` calls function 2 of the program with as many operands as the
+    /// function takes, which is kept here too
+    Call(u16, u8),
 
     // Special operators
     /// Drop converts a binary operator into an unary one by dropping the top value
@@ -46,31 +281,136 @@ pub enum Expr {
     Dup,
 }
 
+impl Expr {
+    /// Returns (number of values popped, number of values pushed)
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Expr::Const(_) | Expr::Var(_) | Expr::ConstRef(_) | Expr::Arg(_) => (0, 1),
+            Expr::Call(_, arity) => (*arity as usize, 1),
+            Expr::Add
+            | Expr::Sub
+            | Expr::Mul
+            | Expr::Max
+            | Expr::Min
+            | Expr::Div
+            | Expr::Mod
+            | Expr::Atan2 => (2, 1),
+            Expr::Cos
+            | Expr::Sin
+            | Expr::Atan
+            | Expr::Abs
+            | Expr::Sqrt
+            | Expr::Exp
+            | Expr::Log
+            | Expr::Tanh
+            | Expr::Step
+            | Expr::Floor
+            | Expr::Fract => (1, 1),
+            Expr::Select
+            | Expr::Disc
+            | Expr::Smoothstep
+            | Expr::ValueNoise
+            | Expr::Perlin
+            | Expr::Worley
+            | Expr::Fbm => (3, 1),
+            Expr::Rect | Expr::LineDist => (4, 1),
+            Expr::Drop => (1, 0),
+            Expr::Dup => (1, 2),
+        }
+    }
+
+    /// The number of operands the instruction takes from the stack
+    pub fn arity(&self) -> usize {
+        self.stack_effect().0
+    }
+
+    pub fn is_leaf(&self) -> bool {
+        self.arity() == 0
+    }
+
+    pub fn is_shape(&self) -> bool {
+        matches!(
+            self,
+            Expr::Rect | Expr::Disc | Expr::LineDist | Expr::Smoothstep
+        )
+    }
+
+    pub fn is_noise(&self) -> bool {
+        matches!(
+            self,
+            Expr::ValueNoise | Expr::Perlin | Expr::Worley | Expr::Fbm
+        )
+    }
+}
+
 impl Display for Expr {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Expr::Const(x) => write!(f, "{}", x),
             Expr::Var(i) => write!(f, "{}", gen_var_str(*i)),
+            Expr::ConstRef(i) => write!(f, "c{}", i),
+            Expr::Arg(i) => write!(f, "a{}", i),
+            Expr::Call(n, _) => write!(f, "fn{}", n),
             Expr::Add => write!(f, "+"),
             Expr::Sub => write!(f, "-"),
             Expr::Mul => write!(f, "*"),
             Expr::Max => write!(f, "max"),
             Expr::Min => write!(f, "min"),
+            Expr::Div => write!(f, "/"),
+            Expr::Mod => write!(f, "mod"),
+            Expr::Atan2 => write!(f, "atan2"),
             Expr::Cos => write!(f, "cos"),
             Expr::Sin => write!(f, "sin"),
             Expr::Atan => write!(f, "atan"),
+            Expr::Abs => write!(f, "abs"),
+            Expr::Sqrt => write!(f, "sqrt"),
+            Expr::Exp => write!(f, "exp"),
+            Expr::Log => write!(f, "log"),
+            Expr::Tanh => write!(f, "tanh"),
+            Expr::Step => write!(f, "step"),
+            Expr::Floor => write!(f, "floor"),
+            Expr::Fract => write!(f, "fract"),
+            Expr::Select => write!(f, "select"),
+            Expr::Rect => write!(f, "rect"),
+            Expr::Disc => write!(f, "disc"),
+            Expr::LineDist => write!(f, "linedist"),
+            Expr::Smoothstep => write!(f, "smoothstep"),
+            Expr::ValueNoise => write!(f, "vnoise"),
+            Expr::Perlin => write!(f, "perlin"),
+            Expr::Worley => write!(f, "worley"),
+            Expr::Fbm => write!(f, "fbm"),
             Expr::Drop => write!(f, "drop"),
             Expr::Dup => write!(f, "dup"),
         }
     }
 }
 
-/// Converts 0 to "x", 1 to "y", static str are returned
-pub fn gen_var_str(n: usize) -> &'static str {
+/// Converts 0 to "x", 1 to "y", 2 to "r", 3 to "theta" and the
+/// features from 4 on to "f0", "f1", ...
+pub fn gen_var_str(n: usize) -> String {
     match n {
-        0 => "x",
-        1 => "y",
-        _ => panic!("gen_var_str: only 0 and 1 are handled"),
+        X => "x".to_string(),
+        Y => "y".to_string(),
+        R => "r".to_string(),
+        THETA => "theta".to_string(),
+        _ => format!("f{}", n - FIRST_FEATURE),
+    }
+}
+
+/// The reverse of gen_var_str
+pub fn parse_var_str(s: &str) -> Option<usize> {
+    match s {
+        "x" => Some(X),
+        "y" => Some(Y),
+        "r" => Some(R),
+        "theta" => Some(THETA),
+        _ => {
+            let digits = s.strip_prefix('f')?;
+            if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            Some(FIRST_FEATURE + digits.parse::<usize>().ok()?)
+        }
     }
 }
 
@@ -91,9 +431,89 @@ mod tests {
                 Expr::Add,
                 Expr::Mul,
             ],
+            ..Default::default()
         };
 
         let result = format!("{}", prg);
         assert_eq!("1 x 2 + *", result);
     }
+
+    #[test]
+    pub fn display_prg_transfer() {
+        let mut prg = Program::new(vec![Expr::Var(0), Expr::Sin]);
+        prg.transfer = Transfer::new(TransferFn::Sigmoid, 2.5);
+        prg.size = Some((320, 200));
+
+        let result = format!("{}", prg);
+        assert_eq!(
+            "@transfer sigmoid\n@gamma 2.5\n@size 320 200\nx sin",
+            result
+        );
+    }
+
+    #[test]
+    pub fn pooled_and_inlined() {
+        let mut prg = Program::new(vec![
+            Expr::ConstRef(2),
+            Expr::Const(0.5),
+            Expr::Add,
+            Expr::ConstRef(2),
+            Expr::Mul,
+        ]);
+        prg.consts = vec![1.0, 2.0, 3.0];
+
+        // The unused entries are dropped, the shared one stays shared
+        let pooled = prg.pooled();
+        assert_eq!("@consts 3 0.5\nc0 c1 + c0 *", format!("{}", pooled));
+        assert_eq!("3 0.5 + 3 *", format!("{}", pooled.inlined()));
+        assert_eq!(prg.inlined(), pooled.inlined());
+    }
+
+    #[test]
+    pub fn subtree_start_leaf() {
+        let code = vec![Expr::Const(1.0), Expr::Var(0), Expr::Add];
+        assert_eq!(Some(1), subtree_start(&code, 1));
+    }
+
+    #[test]
+    pub fn subtree_start_nested() {
+        // 1 x 2 + *
+        let code = vec![
+            Expr::Const(1.0),
+            Expr::Var(0),
+            Expr::Const(2.0),
+            Expr::Add,
+            Expr::Mul,
+        ];
+        assert_eq!(Some(1), subtree_start(&code, 3));
+        assert_eq!(Some(0), subtree_start(&code, 4));
+    }
+
+    #[test]
+    pub fn subtree_start_dup() {
+        // x dup + is a subtree, but dup on its own is not
+        let code = vec![Expr::Var(0), Expr::Dup, Expr::Add];
+        assert_eq!(Some(0), subtree_start(&code, 2));
+        assert_eq!(None, subtree_start(&code, 1));
+    }
+
+    #[test]
+    pub fn well_formed() {
+        let ok = Program {
+            code: vec![Expr::Var(0), Expr::Var(1), Expr::Add],
+            ..Default::default()
+        };
+        let underflow = Program {
+            code: vec![Expr::Var(0), Expr::Add],
+            ..Default::default()
+        };
+        let two_left = Program {
+            code: vec![Expr::Var(0), Expr::Var(1)],
+            ..Default::default()
+        };
+
+        assert!(ok.is_well_formed());
+        assert!(!underflow.is_well_formed());
+        assert!(!two_left.is_well_formed());
+    }
 }
@@ -0,0 +1,343 @@
+use crate::{
+    expr::{gen_var_str, Expr, Program},
+    inputs::{FIRST_FEATURE, THETA},
+};
+
+/// Operator precedence of a printed term, higher binds tighter
+const SUM: u8 = 1;
+/// A term with a leading minus, e.g. -3 or -2x
+const NEG: u8 = 2;
+const PRODUCT: u8 = 3;
+/// Variables, non-negative numbers and function calls
+const ATOM: u8 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Style {
+    Plain,
+    Latex,
+}
+
+#[derive(Debug, Clone)]
+struct Term {
+    text: String,
+    prec: u8,
+    /// A number, which can be written in front of a variable or a function
+    number: bool,
+}
+
+/// Converts the program to a mathematical expression with as few
+/// parentheses as possible, e.g. `x 3.2 * sin y + cos` becomes
+/// `cos(sin(3.2x) + y)`.
+///
+/// Note that sin and cos work in turns like in eval, sin(x) is sin(2 * pi * x).
+pub fn to_infix(prg: &Program) -> String {
+    format_expr(prg, Style::Plain)
+}
+
+/// Like to_infix but written as LaTeX, e.g. `\cos(\sin(3.2x) + y)`
+pub fn to_latex(prg: &Program) -> String {
+    format_expr(prg, Style::Latex)
+}
+
+fn format_expr(prg: &Program, style: Style) -> String {
+    format_code(prg, &prg.code, Vec::new(), style).text
+}
+
+/// Formats the code of the program or of one of its functions, whose
+/// operands are `args`. Calls are written out with the function inlined.
+fn format_code(prg: &Program, code: &[Expr], args: Vec<Term>, style: Style) -> Term {
+    let mut stack: Vec<Term> = Vec::new();
+
+    for expr in code.iter() {
+        let term = match *expr {
+            Expr::Const(x) => number(x),
+            Expr::Arg(i) => args[i].clone(),
+            // The formula shows the values of pooled constants
+            Expr::ConstRef(i) => number(prg.consts[i]),
+            Expr::Var(i) => atom(format_var(i, style)),
+            Expr::Drop => {
+                panic!("Drop not done");
+            }
+            Expr::Dup => {
+                let a = stack.last().expect("Stack underflow").clone();
+                stack.push(a);
+                continue;
+            }
+            Expr::Call(function, arity) => {
+                let n = arity as usize;
+                assert!(stack.len() >= n, "Stack underflow");
+                let args = stack.split_off(stack.len() - n);
+                format_code(prg, &prg.functions[function as usize].code, args, style)
+            }
+            op => {
+                let n = op.arity();
+                assert!(stack.len() >= n, "Stack underflow");
+                let args = stack.split_off(stack.len() - n);
+                format_op(op, args, style)
+            }
+        };
+        stack.push(term);
+    }
+
+    assert!(
+        stack.len() == 1,
+        "Stack should contain exactly 1 item but had {} items",
+        stack.len()
+    );
+    stack.pop().unwrap()
+}
+
+fn format_op(op: Expr, mut args: Vec<Term>, style: Style) -> Term {
+    match op {
+        Expr::Add => {
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            // x + -3 is written x - 3
+            match b.text.strip_prefix('-') {
+                Some(positive) if b.prec == NEG => sum(&a, "-", positive),
+                _ => sum(&a, "+", &b.text),
+            }
+        }
+        Expr::Sub => {
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            match b.text.strip_prefix('-') {
+                Some(positive) if b.prec == NEG => sum(&a, "+", positive),
+                _ => sum(&a, "-", &paren(&b, NEG, style)),
+            }
+        }
+        Expr::Mul => {
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            product(a, b, style)
+        }
+        Expr::Max | Expr::Min => {
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            let name = match style {
+                Style::Plain => format!("{}", op),
+                Style::Latex => format!("\\{}", op),
+            };
+            atom(format!("{}({}, {})", name, a.text, b.text))
+        }
+        Expr::Div => {
+            let b = args.pop().unwrap();
+            let a = args.pop().unwrap();
+            match style {
+                Style::Plain => Term {
+                    text: format!("{} / {}", paren(&a, NEG, style), paren(&b, ATOM, style)),
+                    prec: if a.prec == NEG { NEG } else { PRODUCT },
+                    number: false,
+                },
+                Style::Latex => atom(format!("\\frac{{{}}}{{{}}}", a.text, b.text)),
+            }
+        }
+        Expr::Abs => {
+            let a = args.pop().unwrap();
+            match style {
+                Style::Plain => atom(format!("|{}|", a.text)),
+                Style::Latex => atom(format!("\\left|{}\\right|", a.text)),
+            }
+        }
+        Expr::Floor if style == Style::Latex => {
+            let a = args.pop().unwrap();
+            atom(format!("\\lfloor {} \\rfloor", a.text))
+        }
+        Expr::Sin | Expr::Cos | Expr::Atan | Expr::Tanh | Expr::Exp | Expr::Log => {
+            let a = args.pop().unwrap();
+            let name = match (style, op) {
+                (Style::Plain, _) => format!("{}", op),
+                (Style::Latex, Expr::Atan) => "\\arctan".to_string(),
+                (Style::Latex, Expr::Log) => "\\ln".to_string(),
+                (Style::Latex, _) => format!("\\{}", op),
+            };
+            atom(format!("{}({})", name, a.text))
+        }
+        Expr::Sqrt
+        | Expr::Step
+        | Expr::Floor
+        | Expr::Fract
+        | Expr::Mod
+        | Expr::Atan2
+        | Expr::Select
+        | Expr::Rect
+        | Expr::Disc
+        | Expr::LineDist
+        | Expr::Smoothstep
+        | Expr::ValueNoise
+        | Expr::Perlin
+        | Expr::Worley
+        | Expr::Fbm => {
+            // select(c, a, b) is a if c > 0, otherwise b
+            let name = match style {
+                Style::Plain => format!("{}", op),
+                Style::Latex => format!("\\operatorname{{{}}}", op),
+            };
+            let args: Vec<String> = args.into_iter().map(|arg| arg.text).collect();
+            atom(format!("{}({})", name, args.join(", ")))
+        }
+        _ => panic!("format_op: {} is not an operator", op),
+    }
+}
+
+/// θ for theta, and subscripted feature numbers in LaTeX
+fn format_var(i: usize, style: Style) -> String {
+    match (i, style) {
+        (THETA, Style::Plain) => "θ".to_string(),
+        (THETA, Style::Latex) => "\\theta".to_string(),
+        (i, Style::Latex) if i >= FIRST_FEATURE => format!("f_{{{}}}", i - FIRST_FEATURE),
+        (i, _) => gen_var_str(i),
+    }
+}
+
+fn number(x: f32) -> Term {
+    Term {
+        text: format!("{}", x),
+        prec: if x.is_sign_negative() { NEG } else { ATOM },
+        number: true,
+    }
+}
+
+fn atom(text: String) -> Term {
+    Term {
+        text,
+        prec: ATOM,
+        number: false,
+    }
+}
+
+fn sum(a: &Term, op: &str, b: &str) -> Term {
+    Term {
+        text: format!("{} {} {}", a.text, op, b),
+        prec: SUM,
+        number: false,
+    }
+}
+
+fn product(a: Term, b: Term, style: Style) -> Term {
+    // Numbers go in front, x 3.2 * is 3.2x
+    let (a, b) = if b.number && !a.number {
+        (b, a)
+    } else {
+        (a, b)
+    };
+
+    let text = if a.number && !b.number && b.prec == ATOM {
+        format!("{}{}", a.text, b.text)
+    } else {
+        let times = match style {
+            Style::Plain => "*",
+            Style::Latex => "\\cdot",
+        };
+        format!(
+            "{} {} {}",
+            paren(&a, NEG, style),
+            times,
+            paren(&b, PRODUCT, style)
+        )
+    };
+
+    Term {
+        prec: if text.starts_with('-') { NEG } else { PRODUCT },
+        text,
+        number: false,
+    }
+}
+
+/// Returns the text of the term, in parentheses if it binds looser than `min_prec`
+fn paren(term: &Term, min_prec: u8, style: Style) -> String {
+    if term.prec >= min_prec {
+        return term.text.clone();
+    }
+
+    match style {
+        Style::Plain => format!("({})", term.text),
+        Style::Latex => format!("\\left({}\\right)", term.text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn infix(text: &str) -> String {
+        to_infix(&text.parse().unwrap())
+    }
+
+    #[test]
+    pub fn infix_example() {
+        assert_eq!("cos(sin(3.2x) + y)", infix("x 3.2 * sin y + cos"));
+    }
+
+    #[test]
+    pub fn infix_parentheses() {
+        assert_eq!("x + y + 1", infix("x y + 1 +"));
+        assert_eq!("x + y - 1", infix("x y 1 - +"));
+        assert_eq!("x - (y + 1)", infix("x y 1 + -"));
+        assert_eq!("(x + y) * (x - y)", infix("x y + x y - *"));
+        assert_eq!("2 * x * y", infix("x y * 2 *"));
+        assert_eq!("2 * (x + 1)", infix("x 1 + 2 *"));
+    }
+
+    #[test]
+    pub fn infix_negative_numbers() {
+        assert_eq!("x - 3", infix("x -3 +"));
+        assert_eq!("x + 3", infix("x -3 -"));
+        assert_eq!("-2x + y", infix("x -2 * y +"));
+        assert_eq!("y + 2x", infix("y x -2 * -"));
+        assert_eq!("y * (-2y)", infix("y -2 y * *"));
+    }
+
+    #[test]
+    pub fn infix_functions() {
+        assert_eq!("max(x, 0.5)", infix("x 0.5 max"));
+        assert_eq!("atan(x) * atan(x)", infix("x atan dup *"));
+    }
+
+    #[test]
+    pub fn infix_extended_ops() {
+        assert_eq!("(x + 1) / 2", infix("x 1 + 2 /"));
+        assert_eq!("2x / (y - 1)", infix("x 2 * y 1 - /"));
+        assert_eq!("|x| * sqrt(y)", infix("x abs y sqrt *"));
+        assert_eq!(
+            "select(x, tanh(y), mod(x, 0.5))",
+            infix("x y tanh x 0.5 mod select")
+        );
+        assert_eq!("disc(x - 0.5, y, 0.25)", infix("x 0.5 - y 0.25 disc"));
+        assert_eq!("perlin(2x, 2y, 3)", infix("x 2 * y 2 * 3 perlin"));
+
+        let prg = "x y / abs floor log".parse().unwrap();
+        assert_eq!(
+            "\\ln(\\lfloor \\left|\\frac{x}{y}\\right| \\rfloor)",
+            to_latex(&prg)
+        );
+    }
+
+    #[test]
+    pub fn latex_example() {
+        let prg = "x 3.2 * sin y x + * cos".parse().unwrap();
+        assert_eq!(
+            "\\cos(\\sin(3.2x) \\cdot \\left(y + x\\right))",
+            to_latex(&prg)
+        );
+    }
+
+    #[test]
+    pub fn extra_inputs() {
+        let prg = "r theta 3 * sin * f1 +".parse().unwrap();
+        assert_eq!("r * sin(3θ) + f1", to_infix(&prg));
+        assert_eq!("r \\cdot \\sin(3\\theta) + f_{1}", to_latex(&prg));
+    }
+
+    #[test]
+    pub fn pooled_constants() {
+        assert_eq!("2x - 0.5", infix("@consts -0.5 2\nx c1 * c0 +"));
+    }
+
+    #[test]
+    pub fn functions_are_inlined() {
+        let text = "@fn 1 a0 3 * sin\n@fn 2 a0 a1 - 2 *\nx fn0 y 1 + fn1";
+        assert_eq!("2 * (sin(3x) - (y + 1))", infix(text));
+        assert_eq!("sin(3x)", infix("@fn 1 a0 3 * sin\nx fn0"));
+    }
+}
@@ -0,0 +1,150 @@
+use std::ops::Range;
+
+use crate::{
+    expr::{gen_var_str, Expr, Program},
+    myimage::{GrayScaleImage, MyRgbImage},
+};
+
+/// The Var numbers of the inputs a program can read
+pub const X: usize = 0;
+pub const Y: usize = 1;
+/// The distance from the origin, sqrt(x² + y²)
+pub const R: usize = 2;
+/// The angle around the origin, atan2(y, x)
+pub const THETA: usize = 3;
+/// The first feature channel, f0
+pub const FIRST_FEATURE: usize = 4;
+
+/// Extra per-pixel inputs read from images, e.g. an edge map of the goal
+#[derive(Debug, Clone, Default)]
+pub struct Features {
+    pub channels: Vec<GrayScaleImage>,
+}
+
+impl Features {
+    /// Loads one channel per image, converted to gray-scale. An error
+    /// names the image that could not be loaded.
+    pub fn load(paths: &[&str]) -> Result<Self, String> {
+        let mut channels = Vec::with_capacity(paths.len());
+        for path in paths {
+            let image = MyRgbImage::load_rgb_image(path)
+                .map_err(|error| format!("feature image {}: {}", path, error))?;
+            channels.push(image.to_gray_scale_image());
+        }
+
+        Ok(Self { channels })
+    }
+
+    pub fn len(&self) -> usize {
+        self.channels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty()
+    }
+
+    /// Samples every channel on a width x height grid with the nearest
+    /// pixel, as values from -1.0 to 1.0 row by row
+    pub fn sampled(&self, width: i32, height: i32) -> Vec<Vec<f32>> {
+        self.sampled_rows(width, height, 0..height)
+    }
+
+    /// Like sampled, but only the given rows of the grid
+    pub fn sampled_rows(&self, width: i32, height: i32, rows: Range<i32>) -> Vec<Vec<f32>> {
+        self.channels
+            .iter()
+            .map(|channel| {
+                let mut values = Vec::with_capacity(width as usize * rows.len());
+                for y in rows.clone() {
+                    let channel_y = (y as i64 * channel.height as i64 / height as i64) as i32;
+                    for x in 0..width {
+                        let channel_x = (x as i64 * channel.width as i64 / width as i64) as i32;
+                        let pixel = channel.read_pixel2(channel_x, channel_y).unwrap();
+                        values.push(pixel as f32 / 255.0 * 2.0 - 1.0);
+                    }
+                }
+                values
+            })
+            .collect()
+    }
+}
+
+/// Sets r, θ and the feature values of pixel `index` from x and y, which
+/// must already be in `vars`
+#[inline(always)]
+pub fn set_derived_inputs(vars: &mut [f32], features: &[&[f32]], index: usize) {
+    let (x, y) = (vars[X], vars[Y]);
+    vars[R] = (x * x + y * y).sqrt();
+    vars[THETA] = y.atan2(x);
+
+    for (var, channel) in vars[FIRST_FEATURE..].iter_mut().zip(features) {
+        *var = channel[index];
+    }
+}
+
+/// Returns true if the code reads r, θ or a feature
+pub fn uses_derived_inputs<'a>(code: impl IntoIterator<Item = &'a Expr>) -> bool {
+    code.into_iter()
+        .any(|expr| matches!(expr, Expr::Var(i) if *i >= R))
+}
+
+/// Returns an error naming the first feature the program reads that is
+/// not among the `nfeatures` loaded channels
+pub fn check_features(prg: &Program, nfeatures: usize) -> Result<(), String> {
+    let bodies = prg.functions.iter().map(|function| &function.code);
+    for expr in std::iter::once(&prg.code).chain(bodies).flatten() {
+        if let Expr::Var(i) = *expr {
+            if i >= FIRST_FEATURE + nfeatures {
+                return Err(format!(
+                    "the program reads {} but the number of feature images is {}",
+                    gen_var_str(i),
+                    nfeatures
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn derived_inputs() {
+        let mut vars = vec![0.0; FIRST_FEATURE + 1];
+        vars[X] = 0.0;
+        vars[Y] = -2.0;
+        let channel = [0.25, 0.5];
+
+        set_derived_inputs(&mut vars, &[&channel], 1);
+
+        assert_eq!(2.0, vars[R]);
+        assert_eq!(-std::f32::consts::FRAC_PI_2, vars[THETA]);
+        assert_eq!(0.5, vars[FIRST_FEATURE]);
+    }
+
+    #[test]
+    pub fn sampled_nearest() {
+        let mut channel = GrayScaleImage::new(2, 1);
+        channel.write_pixel(1, 0, 255);
+        let features = Features {
+            channels: vec![channel],
+        };
+
+        assert_eq!(vec![vec![-1.0, -1.0, 1.0, 1.0]], features.sampled(4, 1));
+        assert_eq!(vec![vec![-1.0, 1.0]], features.sampled_rows(2, 2, 1..2));
+    }
+
+    #[test]
+    pub fn missing_features_are_reported() {
+        let prg: Program = "x f1 * f0 +".parse().unwrap();
+
+        assert!(check_features(&prg, 2).is_ok());
+        assert_eq!(
+            Err("the program reads f1 but the number of feature images is 1".to_string()),
+            check_features(&prg, 1)
+        );
+    }
+}
@@ -0,0 +1,364 @@
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+
+use crate::{
+    eval::apply,
+    expr::{Expr, Program},
+    mutate::{random_op, OpSet},
+    state::State,
+};
+
+/// Programs whose effective code expands to more instructions than this
+/// are not evaluated. A register read twice is repeated in the postfix
+/// code, so the length can double with every instruction.
+const MAX_DECODED_LEN: usize = 10_000;
+
+/// The most instructions a random program starts with
+const INITIAL_LENGTH: usize = 10;
+
+/// Step size when a constant is tweaked instead of drawn anew
+const CONST_SIGMA: f32 = 0.5;
+
+/// The register machine linear programs run on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Machine {
+    /// The number of registers. Register i starts with input i modulo the
+    /// number of inputs and register 0 holds the result.
+    pub registers: usize,
+    /// The number of evolved constants, read-only operands
+    pub constants: usize,
+    /// The most instructions a program can grow to
+    pub max_length: usize,
+}
+
+/// `r[dest] = op(r[a], r[b])`, where operands from the number of
+/// registers up are constants and unary operators only read a
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Instruction {
+    pub op: Expr,
+    pub dest: usize,
+    pub operands: [usize; 2],
+}
+
+/// A linear GP program: instructions run in order on a register machine,
+/// so a result can be read by any number of later instructions
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinearProgram {
+    pub machine: Machine,
+    /// The Var numbers of the inputs
+    pub vars: Vec<usize>,
+    pub consts: Vec<f32>,
+    pub code: Vec<Instruction>,
+}
+
+impl LinearProgram {
+    pub fn random(rng: &mut StdRng, machine: Machine, vars: &[usize], ops: &OpSet) -> Self {
+        let mut prg = LinearProgram {
+            machine,
+            vars: vars.to_vec(),
+            consts: (0..machine.constants)
+                .map(|_| ops.constants.sample(rng))
+                .collect(),
+            code: Vec::new(),
+        };
+
+        let length = rng.gen_range(1..=INITIAL_LENGTH.min(machine.max_length));
+        for _ in 0..length {
+            let instruction = prg.random_instruction(rng, ops);
+            prg.code.push(instruction);
+        }
+
+        prg
+    }
+
+    /// Returns for each instruction whether the result depends on it.
+    /// The others are introns, writing registers that are overwritten or
+    /// never read before the end.
+    pub fn effective(&self) -> Vec<bool> {
+        let mut needed = vec![false; self.machine.registers];
+        needed[0] = true;
+
+        let mut effective = vec![false; self.code.len()];
+        for (i, instruction) in self.code.iter().enumerate().rev() {
+            if !needed[instruction.dest] {
+                continue;
+            }
+            needed[instruction.dest] = false;
+            for &operand in self.read(instruction) {
+                if operand < self.machine.registers {
+                    needed[operand] = true;
+                }
+            }
+            effective[i] = true;
+        }
+
+        effective
+    }
+
+    /// Returns the code with the introns removed
+    pub fn effective_code(&self) -> Vec<Instruction> {
+        self.code
+            .iter()
+            .zip(self.effective())
+            .filter(|(_, effective)| *effective)
+            .map(|(instruction, _)| *instruction)
+            .collect()
+    }
+
+    /// Returns the effective code as postfix code, or None if it would be
+    /// longer than MAX_DECODED_LEN
+    pub fn to_code(&self) -> Option<Vec<Expr>> {
+        let code = self.effective_code();
+        let nregisters = self.machine.registers;
+
+        // Every effective instruction is part of the result, so no
+        // register gets longer than the result on the way
+        let mut lengths = vec![1usize; nregisters];
+        for instruction in code.iter() {
+            lengths[instruction.dest] =
+                self.read(instruction).iter().fold(1usize, |sum, &operand| {
+                    sum.saturating_add(lengths.get(operand).copied().unwrap_or(1))
+                });
+        }
+        if lengths[0] > MAX_DECODED_LEN {
+            return None;
+        }
+
+        let mut registers: Vec<Vec<Expr>> = (0..nregisters)
+            .map(|r| vec![Expr::Var(self.vars[r % self.vars.len()])])
+            .collect();
+        for instruction in code.iter() {
+            let mut value = Vec::with_capacity(lengths[instruction.dest]);
+            for &operand in self.read(instruction) {
+                match registers.get(operand) {
+                    Some(register) => value.extend_from_slice(register),
+                    None => value.push(Expr::Const(self.consts[operand - nregisters])),
+                }
+            }
+            value.push(instruction.op);
+            registers[instruction.dest] = value;
+        }
+
+        Some(registers.swap_remove(0))
+    }
+
+    /// Returns a program with the effective code and the other settings of
+    /// `prg`, or None if the code would be too long
+    pub fn to_program(&self, prg: &Program) -> Option<Program> {
+        self.to_code().map(|code| prg.with_code(code))
+    }
+
+    /// Inserts or deletes an instruction, or changes one of the effective
+    /// instructions, any if there are none, or a constant
+    pub fn mutate(&mut self, rng: &mut StdRng, ops: &OpSet) {
+        let mut candidates: Vec<usize> = (0..self.code.len())
+            .zip(self.effective())
+            .filter(|(_, effective)| *effective)
+            .map(|(i, _)| i)
+            .collect();
+        if candidates.is_empty() {
+            candidates = (0..self.code.len()).collect();
+        }
+
+        let choice = rng.gen_range(0..=9);
+        match choice {
+            0 if self.code.len() < self.machine.max_length => {
+                let i = rng.gen_range(0..=self.code.len());
+                let instruction = self.random_instruction(rng, ops);
+                self.code.insert(i, instruction);
+            }
+            1 if self.code.len() > 1 => {
+                self.code.remove(rng.gen_range(0..self.code.len()));
+            }
+            2 if !self.consts.is_empty() => {
+                let c = rng.gen_range(0..self.consts.len());
+                if rng.gen() {
+                    self.consts[c] = ops.constants.sample(rng);
+                } else {
+                    let step: f32 = rng.sample(StandardNormal);
+                    self.consts[c] += CONST_SIGMA * step;
+                }
+            }
+            _ => {
+                let i = candidates[rng.gen_range(0..candidates.len())];
+                match rng.gen_range(0..3) {
+                    0 => self.code[i].op = random_op(rng, &instruction_ops(ops)).unwrap(),
+                    1 => self.code[i].dest = rng.gen_range(0..self.machine.registers),
+                    _ => {
+                        let operand = self.random_operand(rng);
+                        self.code[i].operands[rng.gen_range(0..2)] = operand;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The operands the instruction reads
+    fn read<'a>(&self, instruction: &'a Instruction) -> &'a [usize] {
+        &instruction.operands[..instruction.op.arity()]
+    }
+
+    fn random_instruction(&self, rng: &mut StdRng, ops: &OpSet) -> Instruction {
+        Instruction {
+            op: random_op(rng, &instruction_ops(ops)).expect("random_instruction: no operators"),
+            dest: rng.gen_range(0..self.machine.registers),
+            operands: [self.random_operand(rng), self.random_operand(rng)],
+        }
+    }
+
+    fn random_operand(&self, rng: &mut StdRng) -> usize {
+        rng.gen_range(0..self.machine.registers + self.consts.len())
+    }
+}
+
+/// The operators instructions can have, the unary and binary ones of the
+/// OpSet
+pub fn instruction_ops(ops: &OpSet) -> Vec<Expr> {
+    [ops.unary, ops.binary].concat()
+}
+
+/// Runs the program on the register machine and returns register 0
+pub fn eval_linear(prg: &LinearProgram, state: &State) -> f32 {
+    let nregisters = prg.machine.registers;
+    let mut registers: Vec<f32> = (0..nregisters)
+        .map(|r| state.vars[prg.vars[r % prg.vars.len()]])
+        .collect();
+
+    for instruction in prg.code.iter() {
+        let mut args = [0.0; 2];
+        for (arg, &operand) in args.iter_mut().zip(prg.read(instruction)) {
+            *arg = match registers.get(operand) {
+                Some(&value) => value,
+                None => prg.consts[operand - nregisters],
+            };
+        }
+        registers[instruction.dest] = apply(instruction.op, &args[..instruction.op.arity()]);
+    }
+
+    registers[0]
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{
+        eval::eval,
+        inputs::{X, Y},
+        population::one_plus_lambda,
+    };
+
+    const MACHINE: Machine = Machine {
+        registers: 4,
+        constants: 2,
+        max_length: 30,
+    };
+
+    fn instruction(op: Expr, dest: usize, a: usize, b: usize) -> Instruction {
+        Instruction {
+            op,
+            dest,
+            operands: [a, b],
+        }
+    }
+
+    /// r3 = r0 * c0, r2 = r1 + r1, r0 = r3 - r1, r1 = sin(r0)
+    fn sample_program() -> LinearProgram {
+        LinearProgram {
+            machine: MACHINE,
+            vars: vec![X, Y],
+            consts: vec![0.5, 2.0],
+            code: vec![
+                instruction(Expr::Mul, 3, 0, 4),
+                instruction(Expr::Add, 2, 1, 1),
+                instruction(Expr::Sub, 0, 3, 1),
+                instruction(Expr::Sin, 1, 0, 0),
+            ],
+        }
+    }
+
+    #[test]
+    pub fn introns_are_removed() {
+        let prg = sample_program();
+        let code = prg.effective_code();
+
+        assert_eq!(vec![prg.code[0], prg.code[2]], code);
+        let decoded = prg.to_program(&Program::new(vec![])).unwrap();
+        assert_eq!("x 0.5 * y -", decoded.to_string());
+    }
+
+    #[test]
+    pub fn registers_start_with_the_inputs() {
+        let prg = LinearProgram {
+            code: vec![instruction(Expr::Mul, 0, 2, 3)],
+            ..sample_program()
+        };
+
+        let decoded = prg.to_program(&Program::new(vec![])).unwrap();
+        assert_eq!("x y *", decoded.to_string());
+        let state = State {
+            vars: vec![3.0, -2.0],
+        };
+        assert_eq!(-6.0, eval_linear(&prg, &state));
+    }
+
+    #[test]
+    pub fn decoded_programs_give_the_same_values() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..50 {
+            let mut prg = LinearProgram::random(&mut rng, MACHINE, &[X, Y], &OpSet::ALL);
+            for _ in 0..20 {
+                prg.mutate(&mut rng, &OpSet::ALL);
+                assert!(prg.code.len() <= MACHINE.max_length);
+
+                let Some(decoded) = prg.to_program(&Program::new(vec![])) else {
+                    continue;
+                };
+                assert!(decoded.is_well_formed(), "{}", decoded);
+                for (x, y) in [(0.25, -0.5), (-1.0, 0.75), (0.5, 0.5)] {
+                    let state = State { vars: vec![x, y] };
+                    let expected = eval_linear(&prg, &state);
+                    let value = eval(&decoded, &state);
+                    assert!(
+                        value.to_bits() == expected.to_bits()
+                            || value.is_nan() && expected.is_nan(),
+                        "{} != {} for {}",
+                        value,
+                        expected,
+                        decoded
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn one_plus_lambda_finds_a_target() {
+        let mut rng = StdRng::seed_from_u64(2);
+        // x * y + x on a few points
+        let points = [(0.5, -1.0), (0.25, 0.75), (-0.5, 0.5), (1.0, 1.0)];
+        let error_fn = |prg: &LinearProgram| -> f32 {
+            points
+                .iter()
+                .map(|&(x, y)| (eval_linear(prg, &State { vars: vec![x, y] }) - (x * y + x)).abs())
+                .sum()
+        };
+
+        let mut parent = LinearProgram::random(&mut rng, MACHINE, &[X, Y], &OpSet::DEFAULT);
+        let mut parent_error = error_fn(&parent);
+        for _ in 0..2000 {
+            (parent, parent_error) = one_plus_lambda(
+                &mut rng,
+                parent,
+                parent_error,
+                4,
+                |rng, prg| prg.mutate(rng, &OpSet::DEFAULT),
+                error_fn,
+            );
+        }
+
+        assert!(parent_error < 1e-5, "error was {}", parent_error);
+    }
+}
@@ -1,21 +1,47 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use std::{error::Error, thread};
+use std::{env, error::Error, process, thread};
 #[macro_use]
 extern crate smallvec;
 
+mod autodiff;
+mod cgp;
+mod codegen;
+mod coords;
+mod dag;
 mod eval;
 mod expr;
+mod infix;
+mod inputs;
+mod lgp;
 mod mutate;
 mod myimage;
+mod noise;
 mod optimize;
+mod parse;
 mod pixapprox;
 mod population;
+mod render;
+mod scaling;
+mod semantic;
+mod shapes;
 mod stack;
 mod state;
+mod transfer;
+mod tree;
+mod tune;
 
 fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("render") {
+        if let Err(error) = render::render_command(&args[2..]) {
+            eprintln!("render: {}", error);
+            process::exit(1);
+        }
+        return Ok(());
+    }
+
     const STACK_SIZE: usize = 32 * 1024 * 1024;
 
     // Spawn thread with explicit stack size
@@ -25,7 +51,7 @@ fn main() -> Result<(), Box<dyn Error>> {
         .unwrap();
 
     // Wait for thread to join
-    child.join().unwrap();
+    child.join().unwrap()?;
 
     Ok(())
 }
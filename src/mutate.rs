@@ -1,27 +1,180 @@
+use std::f32::consts::PI;
+
 use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
 
 use smallvec::SmallVec;
 
-use crate::expr::{Expr, Program};
+use crate::expr::{subtree_start, Expr, Function, Program};
 
 /// The maximum number of items returned by the mutate sub-functions.
 /// Keep this value as low as possible to ensure efficient
 /// transfer to called in registers instead of memcopy.
 const MAX_MUTATION_SIZE: usize = 3;
 
-pub fn mutate(rng: &mut StdRng, prg: &mut Program, nvars: usize) {
+/// How often, in percent, a mutation changes the structure of the program
+/// instead of a single instruction
+const STRUCTURAL_MUTATION_PERCENT: u32 = 10;
+
+/// The maximum depth of the subtrees grown by replace_subtree
+const MAX_SUBTREE_DEPTH: u32 = 3;
+
+/// Step size of the log-normal gamma mutation and the gamma limits
+const GAMMA_SIGMA: f32 = 0.1;
+const MIN_GAMMA: f32 = 0.1;
+const MAX_GAMMA: f32 = 10.0;
+
+/// How many random positions are tried when looking for a suitable subtree
+const PICK_ATTEMPTS: usize = 8;
+
+/// How often, in percent, a mutation changes the code of one of the
+/// program's functions instead of the program itself
+const FUNCTION_MUTATION_PERCENT: u32 = 10;
+
+/// How new (ephemeral random) constants are drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConstDist {
+    /// Uniform from -max to max
+    Uniform(f32),
+    /// Normal with mean 0 and the given standard deviation
+    Normal(f32),
+    /// Magnitudes from min to max, evenly spread over the orders of
+    /// magnitude, with a random sign
+    LogUniform(f32, f32),
+    /// Whole numbers from -max to max
+    Integer(i32),
+    /// Multiples of π/4 from -2π to 2π. Sin and cos work in turns, but
+    /// atan, atan2 and theta are in radians.
+    PiMultiples,
+}
+
+impl ConstDist {
+    pub fn sample(&self, rng: &mut StdRng) -> f32 {
+        match *self {
+            ConstDist::Uniform(max) => rng.gen::<f32>() * (2.0 * max) - max,
+            ConstDist::Normal(sigma) => sigma * rng.sample::<f32, _>(StandardNormal),
+            ConstDist::LogUniform(min, max) => {
+                let magnitude = rng.gen_range(min.ln()..max.ln()).exp();
+                if rng.gen() {
+                    magnitude
+                } else {
+                    -magnitude
+                }
+            }
+            ConstDist::Integer(max) => rng.gen_range(-max..=max) as f32,
+            ConstDist::PiMultiples => rng.gen_range(-8..=8) as f32 * PI / 4.0,
+        }
+    }
+}
+
+/// The operators mutations may introduce, by arity, and how constants are
+/// made. Constants are still added, subtracted and multiplied whatever the set.
+#[derive(Debug, Clone, Copy)]
+pub struct OpSet {
+    pub unary: &'static [Expr],
+    pub binary: &'static [Expr],
+    pub ternary: &'static [Expr],
+    /// Grown in place with random positions and sizes, see random_shape
+    pub shapes: &'static [Expr],
+    /// Grown as noise of x and y with a random frequency and seed, see random_noise
+    pub noise: &'static [Expr],
+    pub constants: ConstDist,
+    /// Keep the constants in the program's pool, where mutations can share
+    /// them between instructions, see Program::pooled
+    pub pool: bool,
+    /// The arities of the functions each program gets, called as fn0, fn1, ...
+    pub functions: &'static [usize],
+}
+
+impl OpSet {
+    /// cos, sin, +, - and *, the operators programs were always evolved with
+    pub const DEFAULT: OpSet = OpSet {
+        unary: &[Expr::Cos, Expr::Sin],
+        binary: &[Expr::Add, Expr::Sub, Expr::Mul],
+        ternary: &[],
+        shapes: &[],
+        noise: &[],
+        constants: ConstDist::Uniform(16.0),
+        pool: false,
+        functions: &[],
+    };
+
+    /// Every operator eval supports
+    pub const ALL: OpSet = OpSet {
+        unary: &[
+            Expr::Cos,
+            Expr::Sin,
+            Expr::Atan,
+            Expr::Abs,
+            Expr::Sqrt,
+            Expr::Exp,
+            Expr::Log,
+            Expr::Tanh,
+            Expr::Step,
+            Expr::Floor,
+            Expr::Fract,
+        ],
+        binary: &[
+            Expr::Add,
+            Expr::Sub,
+            Expr::Mul,
+            Expr::Max,
+            Expr::Min,
+            Expr::Div,
+            Expr::Mod,
+            Expr::Atan2,
+        ],
+        ternary: &[Expr::Select],
+        shapes: &[Expr::Rect, Expr::Disc, Expr::LineDist, Expr::Smoothstep],
+        noise: &[Expr::ValueNoise, Expr::Perlin, Expr::Worley, Expr::Fbm],
+        constants: ConstDist::Uniform(16.0),
+        pool: false,
+        functions: &[],
+    };
+}
+
+/// `sigma` is the standard deviation used when tweaking an existing constant
+pub fn mutate(rng: &mut StdRng, prg: &mut Program, vars: &[usize], ops: &OpSet, sigma: f32) {
+    // Programs without functions get a random library first
+    if prg.functions.is_empty() && !ops.functions.is_empty() {
+        prg.functions = random_functions(rng, ops);
+    }
+
+    if !prg.functions.is_empty() && rng.gen_range(0..100) < FUNCTION_MUTATION_PERCENT {
+        mutate_function(rng, prg, ops, sigma);
+    } else if rng.gen_range(0..100) < STRUCTURAL_MUTATION_PERCENT {
+        structural_mutate(rng, prg, vars, ops);
+    } else {
+        point_mutate(rng, prg, vars, ops, sigma);
+    }
+
+    // New constants are made inline
+    if ops.pool {
+        *prg = prg.pooled();
+    }
+}
+
+/// Replaces a single instruction, possibly growing it into a few
+pub fn point_mutate(rng: &mut StdRng, prg: &mut Program, vars: &[usize], ops: &OpSet, sigma: f32) {
     let nth = rng.gen_range(0..prg.code.len());
     let expr = prg.code[nth];
 
     let new_code: SmallVec<[_; MAX_MUTATION_SIZE]> = match expr {
-        Expr::Const(x) => mutated_constant(rng, x, nvars),
-        Expr::Var(i) => mutated_var(rng, i, nvars),
-        Expr::Add | Expr::Sub | Expr::Mul | Expr::Max | Expr::Min | Expr::Drop => {
-            mutated_binary_op(rng, nvars)
-        }
-        Expr::Cos | Expr::Sin | Expr::Atan => mutated_unary_op(rng, nvars),
-
-        Expr::Dup => mutated_dup(rng, nvars),
+        Expr::Const(x) => mutated_constant(rng, x, vars, ops, sigma),
+        Expr::Var(i) => mutated_var(rng, i, vars, ops),
+        Expr::ConstRef(i) => mutated_const_ref(rng, &mut prg.consts, i, vars, ops, sigma),
+        Expr::Drop => mutated_binary_op(rng, ops),
+        Expr::Dup => mutated_dup(rng, ops),
+        Expr::Call(_, arity) => mutated_call(rng, arity as usize, ops),
+        // Function bodies are mutated with their operands as variables, so this stays a leaf
+        Expr::Arg(_) => smallvec![expr],
+        op if op.is_shape() => mutated_shape(rng, op, ops),
+        op if op.is_noise() => mutated_noise(rng, ops),
+        op => match op.arity() {
+            1 => mutated_unary_op(rng, ops),
+            2 => mutated_binary_op(rng, ops),
+            _ => mutated_ternary_op(rng, ops),
+        },
     };
 
     if !new_code.is_empty() {
@@ -35,107 +188,752 @@ pub fn mutate(rng: &mut StdRng, prg: &mut Program, nvars: usize) {
 pub fn mutated_constant(
     rng: &mut StdRng,
     x: f32,
-    nvars: usize,
+    vars: &[usize],
+    ops: &OpSet,
+    sigma: f32,
 ) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
     let choice = rng.gen_range(0..=9);
     match choice {
-        0 => smallvec![make_const(rng)],
+        0 => smallvec![tweak_const(rng, x, sigma)],
 
-        1 => smallvec![Expr::Var(rng.gen_range(0..nvars))],
+        1 => smallvec![random_var(rng, vars)],
 
-        2 => smallvec![Expr::Const(x), make_const(rng), Expr::Add],
-        3 => smallvec![make_const(rng), Expr::Const(x), Expr::Add],
+        2 => smallvec![Expr::Const(x), make_const(rng, ops.constants), Expr::Add],
+        3 => smallvec![make_const(rng, ops.constants), Expr::Const(x), Expr::Add],
 
-        4 => smallvec![Expr::Const(x), make_const(rng), Expr::Sub],
-        5 => smallvec![make_const(rng), Expr::Const(x), Expr::Sub],
+        4 => smallvec![Expr::Const(x), make_const(rng, ops.constants), Expr::Sub],
+        5 => smallvec![make_const(rng, ops.constants), Expr::Const(x), Expr::Sub],
 
-        6 => smallvec![Expr::Const(x), make_const(rng), Expr::Mul],
-        7 => smallvec![make_const(rng), Expr::Const(x), Expr::Mul],
+        6 => smallvec![Expr::Const(x), make_const(rng, ops.constants), Expr::Mul],
+        7 => smallvec![make_const(rng, ops.constants), Expr::Const(x), Expr::Mul],
 
-        8 => smallvec![Expr::Const(x), Expr::Cos],
-        9 => smallvec![Expr::Const(x), Expr::Sin],
-        // 10 => smallvec![Expr::Const(x), Expr::Atan],
+        8 | 9 => match random_op(rng, ops.unary) {
+            Some(op) => smallvec![Expr::Const(x), op],
+            None => smallvec![tweak_const(rng, x, sigma)],
+        },
         _ => panic!("mutated_constant: choice {} not in match", choice),
     }
 }
 
+/// A pooled constant is tweaked in the pool, which changes every
+/// instruction referring to it, or refers to another entry. Otherwise it
+/// mutates like an inline constant.
+pub fn mutated_const_ref(
+    rng: &mut StdRng,
+    consts: &mut [f32],
+    i: usize,
+    vars: &[usize],
+    ops: &OpSet,
+    sigma: f32,
+) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    let choice = rng.gen_range(0..=9);
+    match choice {
+        0 => {
+            let step: f32 = rng.sample(StandardNormal);
+            consts[i] += sigma * step;
+            smallvec![]
+        }
+        1 => smallvec![Expr::ConstRef(rng.gen_range(0..consts.len()))],
+        _ => mutated_constant(rng, consts[i], vars, ops, sigma),
+    }
+}
+
 pub fn mutated_var(
     rng: &mut StdRng,
     i: usize,
-    nvars: usize,
+    vars: &[usize],
+    ops: &OpSet,
 ) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
     let choice = rng.gen_range(0..=9);
     match choice {
-        0 => smallvec![make_const(rng)],
+        0 => smallvec![make_const(rng, ops.constants)],
 
-        1 => smallvec![Expr::Var(rng.gen_range(0..nvars))],
+        1 => smallvec![random_var(rng, vars)],
 
-        2 => smallvec![Expr::Var(i), make_const(rng), Expr::Add],
-        3 => smallvec![make_const(rng), Expr::Var(i), Expr::Add],
+        2 => smallvec![Expr::Var(i), make_const(rng, ops.constants), Expr::Add],
+        3 => smallvec![make_const(rng, ops.constants), Expr::Var(i), Expr::Add],
 
-        4 => smallvec![Expr::Var(i), make_const(rng), Expr::Sub],
-        5 => smallvec![make_const(rng), Expr::Var(i), Expr::Sub],
+        4 => smallvec![Expr::Var(i), make_const(rng, ops.constants), Expr::Sub],
+        5 => smallvec![make_const(rng, ops.constants), Expr::Var(i), Expr::Sub],
 
-        6 => smallvec![Expr::Var(i), make_const(rng), Expr::Mul],
-        7 => smallvec![make_const(rng), Expr::Var(i), Expr::Mul],
+        6 => smallvec![Expr::Var(i), make_const(rng, ops.constants), Expr::Mul],
+        7 => smallvec![make_const(rng, ops.constants), Expr::Var(i), Expr::Mul],
 
-        8 => smallvec![Expr::Var(i), Expr::Cos],
-        9 => smallvec![Expr::Var(i), Expr::Sin],
-        // 10 => smallvec![Expr::Var(i), Expr::Atan],
+        8 | 9 => match random_op(rng, ops.unary) {
+            Some(op) => smallvec![Expr::Var(i), op],
+            None => smallvec![random_var(rng, vars)],
+        },
         _ => panic!("mutated_var: choice {} not in match", choice),
     }
 }
 
-pub fn mutated_binary_op(rng: &mut StdRng, nvars: usize) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
-    let choice = rng.gen_range(0..=2);
-    match choice {
-        0 => smallvec![Expr::Add],
-        1 => smallvec![Expr::Sub],
-        2 => smallvec![Expr::Mul],
-        3 => smallvec![Expr::Max],
-        4 => smallvec![Expr::Min],
-        // 3 => smallvec![Expr::Drop],
-        _ => panic!("mutated_binary_op: choice {} not in match", choice),
+/// An empty result leaves the instruction unchanged
+pub fn mutated_binary_op(rng: &mut StdRng, ops: &OpSet) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    random_op(rng, ops.binary).into_iter().collect()
+}
+
+/// Another unary operator, or a constant added to or multiplied with the operand
+pub fn mutated_unary_op(rng: &mut StdRng, ops: &OpSet) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    let choice = rng.gen_range(0..ops.unary.len() + 2);
+    if let Some(&op) = ops.unary.get(choice) {
+        smallvec![op]
+    } else if choice == ops.unary.len() {
+        smallvec![make_const(rng, ops.constants), Expr::Add]
+    } else {
+        smallvec![make_const(rng, ops.constants), Expr::Mul]
     }
 }
 
-pub fn mutated_unary_op(rng: &mut StdRng, nvars: usize) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
-    let choice = rng.gen_range(0..=3);
-    match choice {
-        0 => smallvec![Expr::Cos],
-        1 => smallvec![Expr::Sin],
-        2 => smallvec![make_const(rng), Expr::Add],
-        3 => smallvec![make_const(rng), Expr::Mul],
-        // 4 => smallvec![Expr::Atan],
-        // 5 => {
-        //     // a -> a a -> binop -> b
-        //     let binop = mutated_binary_op(rng, nvars);
-        //     smallvec![Expr::Dup, binop[0]]
-        // }
+/// Another shape taking the same arguments.
+/// An empty result leaves the instruction unchanged.
+pub fn mutated_shape(
+    rng: &mut StdRng,
+    op: Expr,
+    ops: &OpSet,
+) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    let same_arity: SmallVec<[Expr; 4]> = ops
+        .shapes
+        .iter()
+        .copied()
+        .filter(|shape| shape.arity() == op.arity())
+        .collect();
+    random_op(rng, &same_arity).into_iter().collect()
+}
 
-        // Removes the unary operator instead of replacing it
-        4 => smallvec![],
-        _ => panic!("mutated_unary_op: choice {} not in match", choice),
-    }
+/// Another kind of noise with the same operands.
+/// An empty result leaves the instruction unchanged.
+pub fn mutated_noise(rng: &mut StdRng, ops: &OpSet) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    random_op(rng, ops.noise).into_iter().collect()
+}
+
+/// An empty result leaves the instruction unchanged
+pub fn mutated_ternary_op(rng: &mut StdRng, ops: &OpSet) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    random_op(rng, ops.ternary).into_iter().collect()
+}
+
+/// A call of another function with the same arity.
+/// An empty result leaves the instruction unchanged.
+pub fn mutated_call(
+    rng: &mut StdRng,
+    arity: usize,
+    ops: &OpSet,
+) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+    random_call(rng, ops, |n| n == arity).into_iter().collect()
 }
 
-pub fn mutated_dup(rng: &mut StdRng, nvars: usize) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
+pub fn mutated_dup(rng: &mut StdRng, ops: &OpSet) -> SmallVec<[Expr; MAX_MUTATION_SIZE]> {
     let choice = rng.gen_range(0..=1);
     match choice {
         0 => smallvec![Expr::Dup],
-        1 => smallvec![make_const(rng)],
+        1 => smallvec![make_const(rng, ops.constants)],
 
         _ => panic!("mutated_dup: choice {} not in match", choice),
     }
 }
 
-pub fn make_const(rng: &mut StdRng) -> Expr {
-    Expr::Const(rng.gen::<f32>() * 32.0 - 16.0)
+pub fn make_const(rng: &mut StdRng, dist: ConstDist) -> Expr {
+    Expr::Const(dist.sample(rng))
+}
+
+/// Moves an existing constant a normally distributed step
+pub fn tweak_const(rng: &mut StdRng, x: f32, sigma: f32) -> Expr {
+    let step: f32 = rng.sample(StandardNormal);
+    Expr::Const(x + sigma * step)
+}
+
+/// Scales the gamma of the output mapping by a log-normal factor
+pub fn mutate_gamma(rng: &mut StdRng, prg: &mut Program) {
+    let step: f32 = rng.sample(StandardNormal);
+    let gamma = prg.transfer.gamma * (GAMMA_SIGMA * step).exp();
+    prg.transfer.gamma = gamma.clamp(MIN_GAMMA, MAX_GAMMA);
+}
+
+/// Mutates the code of a random function of the program, with its operands
+/// standing in for the input variables
+pub fn mutate_function(rng: &mut StdRng, prg: &mut Program, ops: &OpSet, sigma: f32) {
+    let n = rng.gen_range(0..prg.functions.len());
+    let function = &mut prg.functions[n];
+    let args: Vec<usize> = (0..function.arity).collect();
+
+    let mut body = Program::new(args_to_vars(&function.code));
+    mutate(rng, &mut body, &args, &function_ops(ops), sigma);
+    function.code = vars_to_args(&body.code);
+}
+
+/// Returns a function of each of the arities in the OpSet, with random code
+pub fn random_functions(rng: &mut StdRng, ops: &OpSet) -> Vec<Function> {
+    ops.functions
+        .iter()
+        .map(|&arity| {
+            let args: Vec<usize> = (0..arity).collect();
+            let code = random_code(rng, MAX_SUBTREE_DEPTH, &args, &function_ops(ops));
+            Function {
+                arity,
+                code: vars_to_args(&code),
+            }
+        })
+        .collect()
+}
+
+/// Replaces a random subtree of a random function of the program with a
+/// subtree of the same function of `other`, which has the same arity
+pub fn crossover_functions(rng: &mut StdRng, prg: &mut Program, other: &Program) {
+    if prg.functions.is_empty() || prg.functions.len() != other.functions.len() {
+        return;
+    }
+
+    let n = rng.gen_range(0..prg.functions.len());
+    let code = &other.functions[n].code;
+    let Some((start, end)) = pick_subtree(rng, code, |_| true) else {
+        return;
+    };
+    let donor = code[start..=end].to_vec();
+
+    let code = &mut prg.functions[n].code;
+    if let Some((start, end)) = pick_subtree(rng, code, |_| true) {
+        code.splice(start..=end, donor);
+    }
+}
+
+/// The operators of function code, which has neither x and y for shapes
+/// and noise nor a pool, and calls no functions
+fn function_ops(ops: &OpSet) -> OpSet {
+    OpSet {
+        shapes: &[],
+        noise: &[],
+        pool: false,
+        functions: &[],
+        ..*ops
+    }
+}
+
+fn args_to_vars(code: &[Expr]) -> Vec<Expr> {
+    code.iter()
+        .map(|expr| match *expr {
+            Expr::Arg(i) => Expr::Var(i),
+            expr => expr,
+        })
+        .collect()
+}
+
+fn vars_to_args(code: &[Expr]) -> Vec<Expr> {
+    code.iter()
+        .map(|expr| match *expr {
+            Expr::Var(i) => Expr::Arg(i),
+            expr => expr,
+        })
+        .collect()
+}
+
+/// Changes a whole subtree of the program at once.
+/// Unlike point mutations these can also make the program smaller.
+pub fn structural_mutate(rng: &mut StdRng, prg: &mut Program, vars: &[usize], ops: &OpSet) {
+    let choice = rng.gen_range(0..=4);
+    match choice {
+        0 => hoist(rng, prg),
+        1 => shrink(rng, prg, vars, ops),
+        2 => replace_subtree(rng, prg, vars, ops),
+        3 => point_delete(rng, prg),
+        4 => swap_sub(rng, prg),
+        _ => panic!("structural_mutate: choice {} not in match", choice),
+    }
+}
+
+/// Promotes a random subtree to be the whole program
+pub fn hoist(rng: &mut StdRng, prg: &mut Program) {
+    if let Some((start, end)) = pick_subtree(rng, &prg.code, |expr| !expr.is_leaf()) {
+        prg.code = prg.code[start..=end].to_vec();
+    }
+}
+
+/// Replaces a random subtree with a constant or a variable
+pub fn shrink(rng: &mut StdRng, prg: &mut Program, vars: &[usize], ops: &OpSet) {
+    if let Some((start, end)) = pick_subtree(rng, &prg.code, |expr| !expr.is_leaf()) {
+        let leaf = random_leaf(rng, vars, ops);
+        prg.code.splice(start..=end, [leaf]);
+    }
+}
+
+/// Replaces a random subtree with a newly grown one
+pub fn replace_subtree(rng: &mut StdRng, prg: &mut Program, vars: &[usize], ops: &OpSet) {
+    if let Some((start, end)) = pick_subtree(rng, &prg.code, |_| true) {
+        let new_code = random_code(rng, MAX_SUBTREE_DEPTH, vars, ops);
+        prg.code.splice(start..=end, new_code);
+    }
+}
+
+/// Removes a unary operator, or a binary operator together with its right operand
+pub fn point_delete(rng: &mut StdRng, prg: &mut Program) {
+    let is_operator = |expr: &Expr| matches!(expr.stack_effect(), (1, 1) | (2, 1));
+    let Some((_, end)) = pick_subtree(rng, &prg.code, is_operator) else {
+        return;
+    };
+
+    match prg.code[end].arity() {
+        1 => {
+            prg.code.remove(end);
+        }
+        2 => {
+            let Some(right) = subtree_start(&prg.code, end - 1) else {
+                return;
+            };
+            prg.code.drain(right..=end);
+        }
+        _ => {}
+    }
+}
+
+/// Exchanges the two operands of a Sub
+pub fn swap_sub(rng: &mut StdRng, prg: &mut Program) {
+    let Some((left, end)) = pick_subtree(rng, &prg.code, |expr| *expr == Expr::Sub) else {
+        return;
+    };
+    let Some(right) = subtree_start(&prg.code, end - 1) else {
+        return;
+    };
+
+    prg.code[left..end].rotate_left(right - left);
+}
+
+/// Grows a random program of at most `depth` levels of operators
+pub fn random_code(rng: &mut StdRng, depth: u32, vars: &[usize], ops: &OpSet) -> Vec<Expr> {
+    let mut code = Vec::new();
+    grow(rng, depth, vars, ops, &mut code);
+    code
+}
+
+/// Grows a leaf, an operator of an arity with enabled operators, a shape,
+/// noise or a call. Every OpSet needs at least one binary operator.
+fn grow(rng: &mut StdRng, depth: u32, vars: &[usize], ops: &OpSet, code: &mut Vec<Expr>) {
+    const SHAPE: usize = 4;
+    const NOISE: usize = 5;
+    const FUNCTION: usize = 6;
+
+    // Arities 0 to 3, then shapes, noise and calls, if any are enabled
+    let mut choices: SmallVec<[usize; 7]> = smallvec![0, 1, 2];
+    if !ops.ternary.is_empty() {
+        choices.push(3);
+    }
+    if !ops.shapes.is_empty() {
+        choices.push(SHAPE);
+    }
+    if !ops.noise.is_empty() {
+        choices.push(NOISE);
+    }
+    if !ops.functions.is_empty() {
+        choices.push(FUNCTION);
+    }
+    let choice = if depth == 0 {
+        0
+    } else {
+        choices[rng.gen_range(0..choices.len())]
+    };
+    let arity = match choice {
+        1 if ops.unary.is_empty() => 2,
+        SHAPE => return random_shape(rng, depth, vars, ops, code),
+        NOISE => return random_noise(rng, vars, ops, code),
+        FUNCTION => {
+            let call = random_call(rng, ops, |_| true).expect("grow: no functions");
+            for _ in 0..call.arity() {
+                grow(rng, depth - 1, vars, ops, code);
+            }
+            code.push(call);
+            return;
+        }
+        choice => choice,
+    };
+
+    for _ in 0..arity {
+        grow(rng, depth - 1, vars, ops, code);
+    }
+    let op = match arity {
+        0 => Some(random_leaf(rng, vars, ops)),
+        1 => random_op(rng, ops.unary),
+        2 => random_op(rng, ops.binary),
+        _ => random_op(rng, ops.ternary),
+    };
+    code.push(op.expect("grow: no binary operators"));
+}
+
+/// Grows one of the enabled shapes, placed at a random position in two of
+/// the input variables, or smoothstep of a grown subtree
+fn random_shape(rng: &mut StdRng, depth: u32, vars: &[usize], ops: &OpSet, code: &mut Vec<Expr>) {
+    let op = random_op(rng, ops.shapes).expect("random_shape: no shapes");
+
+    if op == Expr::Smoothstep {
+        let e0 = rng.gen_range(-1.0..1.0);
+        let e1 = e0 + rng.gen_range(0.01..0.5);
+        code.extend([Expr::Const(e0), Expr::Const(e1)]);
+        grow(rng, depth - 1, vars, ops, code);
+        code.push(op);
+        return;
+    }
+
+    let (u, v) = random_var_pair(rng, vars);
+    let mut uniform = |low: f32, high: f32| Expr::Const(rng.gen_range(low..high));
+    code.extend([u, uniform(-1.0, 1.0), Expr::Sub]);
+    code.extend([v, uniform(-1.0, 1.0), Expr::Sub]);
+    match op {
+        Expr::Rect => code.extend([uniform(0.05, 0.75), uniform(0.05, 0.75)]),
+        Expr::Disc => code.push(uniform(0.05, 0.75)),
+        _ => code.extend([uniform(-1.0, 1.0), uniform(-1.0, 1.0)]),
+    }
+    code.push(op);
+}
+
+/// Grows one of the enabled kinds of noise of two of the input variables,
+/// with a frequency of 1 to 16 cells per unit and an integer seed
+fn random_noise(rng: &mut StdRng, vars: &[usize], ops: &OpSet, code: &mut Vec<Expr>) {
+    let op = random_op(rng, ops.noise).expect("random_noise: no noise");
+    let frequency = Expr::Const(rng.gen_range(1.0..16.0));
+    let seed = Expr::Const(rng.gen_range(0..1024) as f32);
+    let (u, v) = random_var_pair(rng, vars);
+
+    code.extend([u, frequency, Expr::Mul]);
+    code.extend([v, frequency, Expr::Mul, seed, op]);
+}
+
+pub fn random_op(rng: &mut StdRng, ops: &[Expr]) -> Option<Expr> {
+    if ops.is_empty() {
+        None
+    } else {
+        Some(ops[rng.gen_range(0..ops.len())])
+    }
+}
+
+/// A call of one of the functions whose arity satisfies `accept`
+fn random_call(rng: &mut StdRng, ops: &OpSet, accept: impl Fn(usize) -> bool) -> Option<Expr> {
+    let calls: SmallVec<[Expr; 4]> = ops
+        .functions
+        .iter()
+        .enumerate()
+        .filter(|(_, &arity)| accept(arity))
+        .map(|(n, &arity)| Expr::Call(n as u16, arity as u8))
+        .collect();
+    random_op(rng, &calls)
 }
 
-// #[cfg(test)]
-// mod tests {
-//     use crate::state::State;
+/// Picks one of the input variables enabled for the run, by Var number
+fn random_var(rng: &mut StdRng, vars: &[usize]) -> Expr {
+    Expr::Var(vars[rng.gen_range(0..vars.len())])
+}
+
+/// Picks two different input variables in random order, the coordinates of
+/// a shape or noise. With a single input it is used for both.
+fn random_var_pair(rng: &mut StdRng, vars: &[usize]) -> (Expr, Expr) {
+    let first = rng.gen_range(0..vars.len());
+    let mut second = first;
+    if vars.len() > 1 {
+        second = rng.gen_range(0..vars.len() - 1);
+        if second >= first {
+            second += 1;
+        }
+    }
+
+    (Expr::Var(vars[first]), Expr::Var(vars[second]))
+}
+
+pub fn random_leaf(rng: &mut StdRng, vars: &[usize], ops: &OpSet) -> Expr {
+    if rng.gen() {
+        make_const(rng, ops.constants)
+    } else {
+        random_var(rng, vars)
+    }
+}
+
+/// Picks a random subtree whose root instruction satisfies `accept`.
+/// Returns the inclusive (start, end) range or None if nothing was found.
+fn pick_subtree(
+    rng: &mut StdRng,
+    code: &[Expr],
+    accept: impl Fn(&Expr) -> bool,
+) -> Option<(usize, usize)> {
+    for _ in 0..PICK_ATTEMPTS {
+        let end = rng.gen_range(0..code.len());
+        if !accept(&code[end]) {
+            continue;
+        }
+        if let Some(start) = subtree_start(code, end) {
+            return Some((start, end));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::inputs::{FIRST_FEATURE, R, THETA, X, Y};
+
+    /// 1 x 2 + * sin
+    fn sample_program() -> Program {
+        Program {
+            code: vec![
+                Expr::Const(1.0),
+                Expr::Var(0),
+                Expr::Const(2.0),
+                Expr::Add,
+                Expr::Mul,
+                Expr::Sin,
+            ],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn structural_mutations_keep_programs_well_formed() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..100 {
+            let mut prg = sample_program();
+            for _ in 0..20 {
+                structural_mutate(&mut rng, &mut prg, &[X, Y], &OpSet::DEFAULT);
+                assert!(prg.is_well_formed(), "not well formed: {}", prg);
+            }
+        }
+    }
+
+    #[test]
+    pub fn hoist_returns_a_subtree() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let original = format!("{}", sample_program());
+
+        for _ in 0..20 {
+            let mut prg = sample_program();
+            hoist(&mut rng, &mut prg);
+            assert!(original.contains(&format!("{}", prg)));
+            assert!(prg.is_well_formed());
+        }
+    }
+
+    #[test]
+    pub fn swap_sub_swaps_operands() {
+        let mut rng = StdRng::seed_from_u64(3);
+        // x 1 2 + -
+        let mut prg = Program {
+            code: vec![
+                Expr::Var(0),
+                Expr::Const(1.0),
+                Expr::Const(2.0),
+                Expr::Add,
+                Expr::Sub,
+            ],
+            ..Default::default()
+        };
+
+        while prg.code[0] == Expr::Var(0) {
+            swap_sub(&mut rng, &mut prg);
+        }
+
+        assert_eq!("1 2 + x -", format!("{}", prg));
+    }
+
+    #[test]
+    pub fn point_delete_removes_right_operand() {
+        let mut rng = StdRng::seed_from_u64(4);
+        // x 1 2 + -
+        let mut prg = Program {
+            code: vec![
+                Expr::Var(0),
+                Expr::Const(1.0),
+                Expr::Const(2.0),
+                Expr::Add,
+                Expr::Sub,
+            ],
+            ..Default::default()
+        };
+
+        point_delete(&mut rng, &mut prg);
+
+        let result = format!("{}", prg);
+        assert!(result == "x" || result == "x 1 -", "was {}", result);
+    }
+
+    #[test]
+    pub fn only_enabled_vars_are_used() {
+        let mut rng = StdRng::seed_from_u64(6);
+        let vars = [R, FIRST_FEATURE + 1];
+
+        for _ in 0..100 {
+            let mut prg = Program::new(random_code(&mut rng, 4, &vars, &OpSet::DEFAULT));
+            for _ in 0..10 {
+                mutate(&mut rng, &mut prg, &vars, &OpSet::DEFAULT, 1.0);
+            }
+            for expr in prg.code.iter() {
+                if let Expr::Var(i) = expr {
+                    assert!(vars.contains(i), "{} in {}", expr, prg);
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn only_enabled_ops_are_used() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let ops = OpSet {
+            unary: &[Expr::Abs],
+            binary: &[Expr::Div],
+            ternary: &[Expr::Select],
+            shapes: &[Expr::Disc],
+            noise: &[Expr::Worley],
+            ..OpSet::DEFAULT
+        };
+
+        for _ in 0..100 {
+            let mut prg = Program::new(random_code(&mut rng, 4, &[X, Y], &ops));
+            for _ in 0..10 {
+                mutate(&mut rng, &mut prg, &[X, Y], &ops, 1.0);
+                assert!(prg.is_well_formed(), "not well formed: {}", prg);
+            }
+            for expr in prg.code.iter() {
+                assert!(
+                    matches!(
+                        expr,
+                        Expr::Const(_)
+                            | Expr::Var(_)
+                            | Expr::Add
+                            | Expr::Sub
+                            | Expr::Mul
+                            | Expr::Abs
+                            | Expr::Div
+                            | Expr::Select
+                            | Expr::Disc
+                            | Expr::Worley
+                    ),
+                    "{} in {}",
+                    expr,
+                    prg
+                );
+            }
+        }
+    }
+
+    #[test]
+    pub fn shapes_are_grown_in_two_of_the_inputs() {
+        let mut rng = StdRng::seed_from_u64(8);
+        let ops = OpSet {
+            shapes: &[Expr::Rect],
+            ..OpSet::DEFAULT
+        };
+        let vars = [R, THETA, FIRST_FEATURE];
+
+        let mut code = Vec::new();
+        while code.len() != 9 {
+            code = random_code(&mut rng, 1, &vars, &ops);
+        }
+
+        assert!(Program::new(code.clone()).is_well_formed());
+        let (Expr::Var(u), Expr::Var(v)) = (code[0], code[3]) else {
+            panic!("the coordinates are not inputs");
+        };
+        assert!(vars.contains(&u) && vars.contains(&v) && u != v);
+        assert_eq!(Expr::Rect, code[8]);
+        let Expr::Const(w) = code[6] else {
+            panic!("the width is not a constant");
+        };
+        assert!((0.05..0.75).contains(&w), "was {}", w);
+    }
 
-//     use super::*;
-// }
+    #[test]
+    pub fn noise_is_grown_with_an_integer_seed() {
+        let mut rng = StdRng::seed_from_u64(9);
+        let ops = OpSet {
+            noise: &[Expr::Perlin],
+            ..OpSet::DEFAULT
+        };
+
+        let mut code = Vec::new();
+        while code.len() != 8 {
+            code = random_code(&mut rng, 1, &[R], &ops);
+        }
+
+        assert!(Program::new(code.clone()).is_well_formed());
+        assert_eq!(
+            [Expr::Var(R), Expr::Var(R), Expr::Perlin],
+            [code[0], code[3], code[7]]
+        );
+        let Expr::Const(seed) = code[6] else {
+            panic!("the seed is not a constant");
+        };
+        assert_eq!(seed.floor(), seed);
+    }
+
+    #[test]
+    pub fn constants_follow_the_distribution() {
+        let mut rng = StdRng::seed_from_u64(10);
+
+        for _ in 0..100 {
+            let x = ConstDist::Integer(3).sample(&mut rng);
+            assert!(x.fract() == 0.0 && x.abs() <= 3.0, "was {}", x);
+
+            let x = ConstDist::LogUniform(0.01, 100.0).sample(&mut rng);
+            assert!((0.01..100.0).contains(&x.abs()), "was {}", x);
+
+            let x = ConstDist::PiMultiples.sample(&mut rng) / (PI / 4.0);
+            assert!((x - x.round()).abs() < 1e-5, "was {} quarters", x);
+        }
+    }
+
+    #[test]
+    pub fn pooled_constants_stay_in_the_pool() {
+        let mut rng = StdRng::seed_from_u64(11);
+        let ops = OpSet {
+            pool: true,
+            ..OpSet::DEFAULT
+        };
+
+        for _ in 0..100 {
+            let mut prg = Program::new(random_code(&mut rng, 4, &[X, Y], &ops)).pooled();
+            for _ in 0..10 {
+                mutate(&mut rng, &mut prg, &[X, Y], &ops, 1.0);
+            }
+
+            let parsed: Program = format!("{}", prg).parse().unwrap();
+            assert_eq!(prg, parsed);
+            assert!(!prg.code.iter().any(|expr| matches!(expr, Expr::Const(_))));
+            assert!(prg.consts.len() <= prg.code.len());
+        }
+    }
+
+    #[test]
+    pub fn functions_stay_well_formed() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let ops = OpSet {
+            functions: &[1, 2],
+            ..OpSet::DEFAULT
+        };
+
+        for _ in 0..100 {
+            let mut prg = sample_program();
+            let mut other = sample_program();
+            for _ in 0..20 {
+                mutate(&mut rng, &mut prg, &[X, Y], &ops, 1.0);
+                mutate(&mut rng, &mut other, &[X, Y], &ops, 1.0);
+                crossover_functions(&mut rng, &mut prg, &other);
+            }
+
+            let parsed: Program = format!("{}", prg).parse().unwrap();
+            assert_eq!(prg, parsed);
+            assert_eq!([1, 2], [prg.functions[0].arity, prg.functions[1].arity]);
+            for function in prg.functions.iter() {
+                let body = Program::new(args_to_vars(&function.code));
+                assert!(body.is_well_formed(), "not well formed: {}", prg);
+            }
+        }
+    }
+
+    #[test]
+    pub fn tweak_const_stays_close() {
+        let mut rng = StdRng::seed_from_u64(5);
+
+        for _ in 0..100 {
+            let Expr::Const(x) = tweak_const(&mut rng, 3.0, 0.01) else {
+                panic!("tweak_const did not return a constant");
+            };
+            assert!((x - 3.0).abs() < 0.1, "was {}", x);
+        }
+    }
+}
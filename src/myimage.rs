@@ -42,11 +42,11 @@ impl GrayScaleImage {
 
     /// Returns the pixel value 0-255u8 at (x,y) or None
     pub fn read_pixel2(&self, x: i32, y: i32) -> Option<u8> {
-        if x < 0 || y < 0 || x >= self.width as i32 || y >= self.height as i32 {
+        if x < 0 || y < 0 || x >= self.width || y >= self.height {
             return None;
         }
 
-        let index = x + y * self.width as i32;
+        let index = x + y * self.width;
         Some(self.data[index as usize])
     }
 
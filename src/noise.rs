@@ -0,0 +1,160 @@
+/// Lattice coordinates and seeds are limited to ±2^24, where f32 still holds
+/// every integer, so converting them gives the same result everywhere
+const LIMIT: f32 = 16777216.0;
+
+// Only integer operations, + - * / and sqrt are used, which IEEE 754 defines
+// exactly, so the noise is the same on every platform. The helpers in
+// templates/ do the same operations in the same order, keep them in sync.
+
+/// The integer cell of a coordinate. Unlike clamp NaN becomes +2^24, like
+/// fmax and fmin do in the generated code.
+#[allow(clippy::manual_clamp)]
+fn lattice(x: f32) -> i32 {
+    x.floor().min(LIMIT).max(-LIMIT) as i32
+}
+
+/// The seed operand, rounded down to an integer
+fn seed(s: f32) -> u32 {
+    lattice(s) as u32
+}
+
+/// Mixes the cell and the seed into 32 well distributed bits
+fn hash(ix: i32, iy: i32, seed: u32) -> u32 {
+    let mut h =
+        seed ^ (ix as u32).wrapping_mul(0x27d4_eb2d) ^ (iy as u32).wrapping_mul(0x1656_67b1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^= h >> 15;
+    h
+}
+
+/// 0.0 to 1.0 from the top 24 bits of the hash
+fn unit(h: u32) -> f32 {
+    (h >> 8) as f32 / 16777216.0
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// -1.0 to 1.0 at every lattice point
+fn corner(ix: i32, iy: i32, seed: u32) -> f32 {
+    unit(hash(ix, iy, seed)) * 2.0 - 1.0
+}
+
+/// Random values at the lattice points, smoothly interpolated between them
+pub fn value_noise(x: f32, y: f32, s: f32) -> f32 {
+    let seed = seed(s);
+    let ix = lattice(x);
+    let iy = lattice(y);
+    let fx = x - ix as f32;
+    let fy = y - iy as f32;
+    let u = fx * fx * (3.0 - 2.0 * fx);
+    let v = fy * fy * (3.0 - 2.0 * fy);
+
+    lerp(
+        lerp(corner(ix, iy, seed), corner(ix + 1, iy, seed), u),
+        lerp(corner(ix, iy + 1, seed), corner(ix + 1, iy + 1, seed), u),
+        v,
+    )
+}
+
+/// The dot product of (dx, dy) with one of four diagonal gradients
+fn gradient(h: u32, dx: f32, dy: f32) -> f32 {
+    (1.0 - 2.0 * (h & 1) as f32) * dx + (1.0 - 2.0 * ((h >> 1) & 1) as f32) * dy
+}
+
+fn perlin_seeded(x: f32, y: f32, seed: u32) -> f32 {
+    let ix = lattice(x);
+    let iy = lattice(y);
+    let fx = x - ix as f32;
+    let fy = y - iy as f32;
+    let u = fx * fx * fx * (fx * (fx * 6.0 - 15.0) + 10.0);
+    let v = fy * fy * fy * (fy * (fy * 6.0 - 15.0) + 10.0);
+
+    let n00 = gradient(hash(ix, iy, seed), fx, fy);
+    let n10 = gradient(hash(ix + 1, iy, seed), fx - 1.0, fy);
+    let n01 = gradient(hash(ix, iy + 1, seed), fx, fy - 1.0);
+    let n11 = gradient(hash(ix + 1, iy + 1, seed), fx - 1.0, fy - 1.0);
+    lerp(lerp(n00, n10, u), lerp(n01, n11, u), v)
+}
+
+/// Gradient noise, 0.0 at every lattice point
+pub fn perlin(x: f32, y: f32, s: f32) -> f32 {
+    perlin_seeded(x, y, seed(s))
+}
+
+/// The distance to the nearest of one random point per cell
+pub fn worley(x: f32, y: f32, s: f32) -> f32 {
+    let seed = seed(s);
+    let ix = lattice(x);
+    let iy = lattice(y);
+    let fx = x - ix as f32;
+    let fy = y - iy as f32;
+
+    // Further than any point in the 3 x 3 cells around
+    let mut nearest: f32 = 8.0;
+    for j in -1..=1 {
+        for i in -1..=1 {
+            let h = hash(ix + i, iy + j, seed);
+            let dx = i as f32 + (h & 0xffff) as f32 / 65536.0 - fx;
+            let dy = j as f32 + (h >> 16) as f32 / 65536.0 - fy;
+            nearest = nearest.min(dx * dx + dy * dy);
+        }
+    }
+
+    nearest.sqrt()
+}
+
+/// Fractal Brownian motion, four octaves of perlin noise each with twice
+/// the frequency and half the amplitude of the one before
+pub fn fbm(x: f32, y: f32, s: f32) -> f32 {
+    let seed = seed(s);
+    let mut sum = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    for octave in 0..4 {
+        sum += amplitude * perlin_seeded(x * frequency, y * frequency, seed.wrapping_add(octave));
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+
+    sum / 0.9375
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn noise_is_reproducible() {
+        // Fixed values, a change in any of them changes saved programs
+        assert_eq!(0x29b6_32e8, hash(3, -7, 42));
+        assert_eq!(0.23398101, value_noise(1.3, -2.6, 7.0));
+        assert_eq!(-0.25409266, perlin(1.3, -2.6, 7.0));
+        assert_eq!(0.57791877, worley(1.3, -2.6, 7.0));
+        assert_eq!(-0.1334154, fbm(1.3, -2.6, 7.9));
+    }
+
+    #[test]
+    pub fn noise_on_the_lattice() {
+        assert_eq!(0.0, perlin(3.0, -2.0, 1.0));
+        assert_eq!(corner(3, -2, 5), value_noise(3.0, -2.0, 5.5));
+        assert_ne!(value_noise(0.5, 0.5, 1.0), value_noise(0.5, 0.5, 2.0));
+    }
+
+    #[test]
+    pub fn noise_ranges() {
+        for i in 0..400 {
+            let x = i as f32 * 0.137 - 20.0;
+            let y = i as f32 * 0.071 + 3.0;
+
+            assert!((-1.0..=1.0).contains(&value_noise(x, y, 3.0)));
+            assert!((-1.0..=1.0).contains(&perlin(x, y, 3.0)));
+            assert!((-1.0..=1.0).contains(&fbm(x, y, 3.0)));
+            assert!((0.0..1.5).contains(&worley(x, y, 3.0)));
+        }
+    }
+}
@@ -1,9 +1,9 @@
-use std::{collections::VecDeque, f32::consts::TAU};
+use std::collections::VecDeque;
 
 use crate::{
-    expr::{Expr, Program},
-    stack::Stack2,
-    state::State,
+    dag::inline_calls,
+    eval::apply,
+    expr::{subtree_start, Expr, Program},
 };
 
 fn top_is_two_constants(prg: &VecDeque<Expr>) -> bool {
@@ -24,7 +24,7 @@ fn top_is_two_constants(prg: &VecDeque<Expr>) -> bool {
 }
 
 fn top_is_one_constant(prg: &VecDeque<Expr>) -> bool {
-    if prg.len() < 1 {
+    if prg.is_empty() {
         return false;
     }
 
@@ -43,82 +43,37 @@ fn pop_const(prg: &mut VecDeque<Expr>) -> f32 {
     x
 }
 
+/// Simplifies the program without changing what it evaluates to.
+///
+/// Besides folding operators on constants, a set of algebraic rewrite
+/// rules are applied bottom up:
+///
+/// * `x 0 +`, `x 0 -`, `x 1 *` become `x`, and `x 0 *` becomes `0`
+/// * `x x -` becomes `0`, `x x max` and `x x min` become `x`
+/// * `x 1 /` becomes `x`, `x x /` becomes `1` and `x x mod` becomes `0`
+/// * repeated abs and floor are removed, `x abs abs` is `x abs`,
+///   and so is abs in front of the protected sqrt and log, `x abs sqrt` is `x sqrt`
+/// * select with a constant condition, or the same value in both branches,
+///   becomes the branch taken
+/// * constants of Add and Mul are moved to the right, `2 x +` is `x 2 +`
+/// * nested constants are combined, `x 2 + 3 +` becomes `x 5 +`
+/// * whole turns are removed from sin/cos phases, `x 1.25 + sin` is `x 0.25 + sin`,
+///   and quarter turn shifts between them, `x 0.25 + sin` is `x cos`
+///
+/// The rules assume finite values, `x 0 *` is 0 even if x is infinite.
+/// Pooled constants are folded too and calls are inlined, so the result
+/// has neither. A program whose inlined code would be too long, see
+/// Dag::to_code, is returned as it is.
 pub fn optimize(prg: &Program) -> Program {
+    let Some(prg) = &inline_calls(prg) else {
+        return prg.clone();
+    };
     let mut new_code: VecDeque<Expr> = VecDeque::new();
 
     for expr in prg.code.iter() {
         match *expr {
             Expr::Const(_) => new_code.push_back(*expr),
             Expr::Var(_) => new_code.push_back(*expr),
-            Expr::Add => {
-                if top_is_two_constants(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    let b = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const(a + b));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Sub => {
-                if top_is_two_constants(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    let b = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const(b - a));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Mul => {
-                if top_is_two_constants(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    let b = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const(b * a));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Max => {
-                if top_is_two_constants(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    let b = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const(a.max(b)));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Min => {
-                if top_is_two_constants(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    let b = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const(a.min(b)));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Cos => {
-                if top_is_one_constant(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const((a * TAU).cos()));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Sin => {
-                if top_is_one_constant(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const((a * TAU).sin()));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
-            Expr::Atan => {
-                if top_is_one_constant(&new_code) {
-                    let a = pop_const(&mut new_code);
-                    new_code.push_back(Expr::Const(a.atan()));
-                } else {
-                    new_code.push_back(*expr);
-                }
-            }
             Expr::Drop => {
                 panic!("Drop not done");
             }
@@ -131,23 +86,256 @@ pub fn optimize(prg: &Program) -> Program {
                     new_code.push_back(*expr);
                 }
             }
+            op => push_op(&mut new_code, op),
+        }
+    }
+
+    prg.with_code(new_code.into_iter().collect())
+}
+
+/// Pushes an operator whose operands are on top of the code, simplified
+/// as far as the rules allow
+fn push_op(code: &mut VecDeque<Expr>, op: Expr) {
+    match op.arity() {
+        1 => {
+            if top_is_one_constant(code) {
+                let a = pop_const(code);
+                code.push_back(Expr::Const(apply(op, &[a])));
+            } else if !simplify_unary(code, op) {
+                code.push_back(op);
+            }
         }
+        2 => {
+            if top_is_two_constants(code) {
+                let a = pop_const(code);
+                let b = pop_const(code);
+                code.push_back(Expr::Const(apply(op, &[b, a])));
+            } else if !simplify_binary(code, op) {
+                code.push_back(op);
+            }
+        }
+        _ => {
+            if !simplify_ternary(code, op) {
+                code.push_back(op);
+            }
+        }
+    }
+}
+
+/// Returns where the left and right operand on top of the code start
+fn binary_operands(code: &[Expr]) -> Option<(usize, usize)> {
+    let right = subtree_start(code, code.len().checked_sub(1)?)?;
+    let left = subtree_start(code, right.checked_sub(1)?)?;
+
+    Some((left, right))
+}
+
+/// Returns where the three operands on top of the code start
+fn ternary_operands(code: &[Expr]) -> Option<(usize, usize, usize)> {
+    let (middle, right) = binary_operands(code)?;
+    let left = subtree_start(code, middle.checked_sub(1)?)?;
+
+    Some((left, middle, right))
+}
+
+/// Returns the value if the range is a single constant
+fn single_const(code: &[Expr]) -> Option<f32> {
+    match code {
+        [Expr::Const(x)] => Some(*x),
+        _ => None,
+    }
+}
+
+/// Applies a rewrite rule for the binary operator if one matches.
+/// Returns true if the operator has been handled.
+fn simplify_binary(code: &mut VecDeque<Expr>, op: Expr) -> bool {
+    let slice = code.make_contiguous();
+    let Some((l, r)) = binary_operands(slice) else {
+        return false;
+    };
+
+    // Constants go to the right of commutative operators
+    if matches!(op, Expr::Add | Expr::Mul)
+        && single_const(&slice[l..r]).is_some()
+        && single_const(&slice[r..]).is_none()
+    {
+        slice[l..].rotate_left(1);
+        return simplify_binary(code, op);
     }
 
-    Program {
-        code: new_code.into_iter().collect(),
+    let right_const = single_const(&slice[r..]);
+    let same_operands = slice[l..r] == slice[r..];
+
+    match (op, right_const) {
+        (Expr::Add | Expr::Sub, Some(0.0)) => {
+            code.truncate(r);
+            true
+        }
+        (Expr::Mul, Some(1.0)) => {
+            code.truncate(r);
+            true
+        }
+        (Expr::Mul, Some(0.0)) => {
+            code.truncate(l);
+            code.push_back(Expr::Const(0.0));
+            true
+        }
+        (Expr::Sub, _) if same_operands => {
+            code.truncate(l);
+            code.push_back(Expr::Const(0.0));
+            true
+        }
+        (Expr::Max | Expr::Min, _) if same_operands => {
+            code.truncate(r);
+            true
+        }
+        (Expr::Div, Some(1.0)) => {
+            code.truncate(r);
+            true
+        }
+        (Expr::Div | Expr::Mod, _) if same_operands => {
+            code.truncate(l);
+            code.push_back(Expr::Const(if op == Expr::Div { 1.0 } else { 0.0 }));
+            true
+        }
+        (Expr::Add | Expr::Sub | Expr::Mul, Some(b)) => reassociate(code, l, r, op, b),
+        _ => false,
     }
 }
 
+/// Combines the constant `b` with a constant in the left operand,
+/// `x a + b +` becomes `x (a + b) +`.
+/// Returns true if the operator has been handled.
+fn reassociate(code: &mut VecDeque<Expr>, l: usize, r: usize, op: Expr, b: f32) -> bool {
+    // The left operand must be `x a inner` with x non-empty
+    if r < l + 3 {
+        return false;
+    }
+    let Expr::Const(a) = code[r - 2] else {
+        return false;
+    };
+    let inner = code[r - 1];
+
+    let (c, new_op) = match (inner, op) {
+        (Expr::Add, Expr::Add) => (a + b, Expr::Add),
+        (Expr::Sub, Expr::Add) => (a - b, Expr::Sub),
+        (Expr::Add, Expr::Sub) => (a - b, Expr::Add),
+        (Expr::Sub, Expr::Sub) => (a + b, Expr::Sub),
+        (Expr::Mul, Expr::Mul) => (a * b, Expr::Mul),
+        _ => return false,
+    };
+
+    code.truncate(r - 2);
+    code.push_back(Expr::Const(c));
+    push_op(code, new_op);
+    true
+}
+
+/// Applies a rewrite rule for the unary operator if one matches.
+/// Returns true if the operator has been handled.
+fn simplify_unary(code: &mut VecDeque<Expr>, op: Expr) -> bool {
+    // Applying these twice gives the same value as once. Not so for fract:
+    // that of a tiny negative x rounds to 1.0, whose fract is 0.0.
+    if matches!(op, Expr::Abs | Expr::Floor) && code.back() == Some(&op) {
+        return true;
+    }
+    // sqrt and log already take the absolute value
+    if matches!(op, Expr::Sqrt | Expr::Log) && code.back() == Some(&Expr::Abs) {
+        code.pop_back();
+        code.push_back(op);
+        return true;
+    }
+
+    if !matches!(op, Expr::Sin | Expr::Cos) {
+        return false;
+    }
+
+    // Look for a phase: `x c +` or `x c -`
+    let slice = code.make_contiguous();
+    if slice.len() < 3 {
+        return false;
+    }
+    let Some((l, r)) = binary_operands(&slice[..slice.len() - 1]) else {
+        return false;
+    };
+    let inner = slice[slice.len() - 1];
+    let Some(c) = single_const(&slice[r..slice.len() - 1]) else {
+        return false;
+    };
+    if !matches!(inner, Expr::Add | Expr::Sub) {
+        return false;
+    }
+
+    // sin and cos are in turns, whole turns do not matter
+    let reduced = c - c.round();
+    let phase = if inner == Expr::Add {
+        reduced
+    } else {
+        -reduced
+    };
+
+    let replacement = match (op, phase) {
+        (_, 0.0) => Some(op),
+        (Expr::Sin, 0.25) => Some(Expr::Cos),
+        (Expr::Cos, -0.25) => Some(Expr::Sin),
+        _ => None,
+    };
+
+    match replacement {
+        Some(new_op) => {
+            code.truncate(r);
+            push_op(code, new_op);
+        }
+        None if reduced != c => {
+            code[r] = Expr::Const(reduced);
+            code.push_back(op);
+        }
+        None => return false,
+    }
+
+    true
+}
+
+/// Applies a rewrite rule for the ternary operator if one matches.
+/// Returns true if the operator has been handled.
+fn simplify_ternary(code: &mut VecDeque<Expr>, op: Expr) -> bool {
+    if op != Expr::Select {
+        return false;
+    }
+
+    let slice = code.make_contiguous();
+    let Some((l, m, r)) = ternary_operands(slice) else {
+        return false;
+    };
+
+    // Like eval, a NaN condition takes the second branch
+    let keep = match single_const(&slice[l..m]) {
+        Some(c) if c > 0.0 => m..r,
+        Some(_) => r..slice.len(),
+        None if slice[m..r] == slice[r..] => r..slice.len(),
+        None => return false,
+    };
+
+    let branch: Vec<Expr> = slice[keep].to_vec();
+    code.truncate(l);
+    code.extend(branch);
+    true
+}
+
 #[cfg(test)]
+#[allow(clippy::excessive_precision, clippy::bool_assert_comparison)]
 mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
     use super::*;
+    use crate::{eval::eval, state::State};
 
     #[test]
     pub fn optimize_add_1() {
         // 0.46546388 1 +
         let prg = Program {
             code: vec![Expr::Const(0.46546388), Expr::Const(1.0), Expr::Add],
+            ..Default::default()
         };
 
         let actual = optimize(&prg).code;
@@ -156,6 +344,147 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    fn optimized(code: Vec<Expr>) -> String {
+        format!("{}", optimize(&Program::new(code)))
+    }
+
+    #[test]
+    pub fn optimize_identities() {
+        use Expr::*;
+
+        assert_eq!("x", optimized(vec![Var(0), Const(0.0), Add]));
+        assert_eq!("x", optimized(vec![Const(0.0), Var(0), Add]));
+        assert_eq!("x", optimized(vec![Var(0), Const(0.0), Sub]));
+        assert_eq!("x", optimized(vec![Var(0), Const(1.0), Mul]));
+        assert_eq!("x", optimized(vec![Const(1.0), Var(0), Mul]));
+        assert_eq!("0", optimized(vec![Var(0), Var(1), Add, Const(0.0), Mul]));
+        assert_eq!("0", optimized(vec![Var(0), Sin, Var(0), Sin, Sub]));
+        assert_eq!("x sin", optimized(vec![Var(0), Sin, Var(0), Sin, Max]));
+        assert_eq!("y", optimized(vec![Var(1), Var(1), Min]));
+    }
+
+    #[test]
+    pub fn optimize_reassociate() {
+        use Expr::*;
+
+        assert_eq!(
+            "x 5 +",
+            optimized(vec![Var(0), Const(2.0), Add, Const(3.0), Add])
+        );
+        assert_eq!(
+            "x 5 +",
+            optimized(vec![Const(2.0), Var(0), Add, Const(3.0), Add])
+        );
+        assert_eq!(
+            "x -1 +",
+            optimized(vec![Var(0), Const(2.0), Add, Const(3.0), Sub])
+        );
+        assert_eq!(
+            "x 5 -",
+            optimized(vec![Var(0), Const(2.0), Sub, Const(3.0), Sub])
+        );
+        assert_eq!(
+            "x 6 *",
+            optimized(vec![Var(0), Const(2.0), Mul, Const(3.0), Mul])
+        );
+        assert_eq!(
+            "x",
+            optimized(vec![Var(0), Const(2.0), Add, Const(-2.0), Add])
+        );
+    }
+
+    #[test]
+    pub fn optimize_phase() {
+        use Expr::*;
+
+        assert_eq!("x sin", optimized(vec![Var(0), Const(3.0), Add, Sin]));
+        assert_eq!("x cos", optimized(vec![Var(0), Const(1.25), Add, Sin]));
+        assert_eq!("x sin", optimized(vec![Var(0), Const(0.25), Sub, Cos]));
+        assert_eq!(
+            "x 0.375 + cos",
+            optimized(vec![Var(0), Const(2.375), Add, Cos])
+        );
+    }
+
+    #[test]
+    pub fn optimize_extended_ops() {
+        let optimized = |text: &str| format!("{}", optimize(&text.parse().unwrap()));
+
+        assert_eq!("x", optimized("x 1 /"));
+        assert_eq!("1", optimized("x sin x sin /"));
+        assert_eq!("0", optimized("y x mod y x mod mod"));
+        assert_eq!("x abs", optimized("x abs abs"));
+        assert_eq!(
+            "x floor fract fract",
+            optimized("x floor floor fract fract")
+        );
+        assert_eq!("y log", optimized("y abs log"));
+        assert_eq!("x 2 *", optimized("1 x 2 * y select"));
+        assert_eq!("y", optimized("-1 x 2 * y select"));
+        assert_eq!("x sin", optimized("y x sin x sin select"));
+        assert_eq!("x y 2 select", optimized("x y 2 select"));
+        assert_eq!("3", optimized("2 4 / 3 abs 0 select"));
+    }
+
+    /// Programs built from constants that trigger the rewrite rules
+    fn random_program(rng: &mut StdRng, depth: u32, code: &mut Vec<Expr>) {
+        const CONSTS: [f32; 7] = [0.0, 1.0, 0.25, -0.25, 2.0, 1.5, -3.0];
+
+        let choice = if depth == 0 {
+            rng.gen_range(0..=1)
+        } else {
+            rng.gen_range(0..=4)
+        };
+        match choice {
+            0 => code.push(Expr::Const(CONSTS[rng.gen_range(0..CONSTS.len())])),
+            1 => code.push(Expr::Var(rng.gen_range(0..2))),
+            2 | 3 => {
+                random_program(rng, depth - 1, code);
+                random_program(rng, depth - 1, code);
+                let ops = [Expr::Add, Expr::Sub, Expr::Mul, Expr::Max, Expr::Min];
+                code.push(ops[rng.gen_range(0..ops.len())]);
+            }
+            _ => {
+                random_program(rng, depth - 1, code);
+                let ops = [Expr::Sin, Expr::Cos, Expr::Atan];
+                code.push(ops[rng.gen_range(0..ops.len())]);
+            }
+        }
+    }
+
+    #[test]
+    pub fn optimize_keeps_values_on_grid() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut state = State::new(2);
+
+        for _ in 0..2000 {
+            let mut code = Vec::new();
+            random_program(&mut rng, 5, &mut code);
+            let prg = Program::new(code);
+            let opt = optimize(&prg);
+            assert!(opt.is_well_formed());
+            assert!(opt.code.len() <= prg.code.len());
+
+            for y in 0..9 {
+                for x in 0..9 {
+                    state.vars[0] = x as f32 / 4.0 - 1.0;
+                    state.vars[1] = y as f32 / 4.0 - 1.0;
+
+                    let expected = eval(&prg, &state);
+                    let actual = eval(&opt, &state);
+                    assert!(
+                        (expected - actual).abs() <= 1e-4 * (1.0 + expected.abs()),
+                        "{} gave {} but {} gave {}",
+                        prg,
+                        expected,
+                        opt,
+                        actual
+                    );
+                }
+            }
+        }
+    }
+
     #[test]
     pub fn top_is_one_constant_test_0() {
         let prg = VecDeque::from(vec![]);
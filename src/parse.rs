@@ -0,0 +1,497 @@
+use std::{error::Error, fmt::Display, fs, str::FromStr};
+
+use crate::{
+    coords::{Mapping, Origin, Sampling},
+    expr::{parse_var_str, Expr, Function, Program},
+    transfer::TransferFn,
+};
+
+/// A parse error with the 1-based line and column where it was found
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Parses the text written by Program's Display.
+///
+/// Lines starting with `@` are directives, e.g. `@transfer sigmoid`.
+/// A constant pool, `@consts`, and functions, `@fn`, have to come before the
+/// code referring to them.
+/// All other lines hold postfix code, which may be spread over several lines.
+/// Everything after a `#` is a comment.
+impl FromStr for Program {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut prg = Program::new(Vec::new());
+        let mut depth = 0usize;
+        let mut last = (1, 1);
+
+        for (line_index, line) in s.lines().enumerate() {
+            let line_number = line_index + 1;
+            let line = line.split('#').next().unwrap_or("");
+            let tokens = tokens(line);
+
+            if let Some(&(column, first)) = tokens.first() {
+                if let Some(directive) = first.strip_prefix('@') {
+                    parse_directive(&mut prg, directive, &tokens[1..], line_number, column)?;
+                    continue;
+                }
+            }
+
+            for (column, token) in tokens {
+                let error = |message: String| ParseError {
+                    line: line_number,
+                    column,
+                    message,
+                };
+
+                let expr = parse_instruction(&prg, token, None).map_err(error)?;
+
+                let (npop, npush) = expr.stack_effect();
+                if depth < npop {
+                    return Err(error(format!(
+                        "'{}' needs {} values but the stack has {}",
+                        token, npop, depth
+                    )));
+                }
+                depth = depth - npop + npush;
+
+                prg.code.push(expr);
+                last = (line_number, column + token.len());
+            }
+        }
+
+        if depth != 1 {
+            return Err(ParseError {
+                line: last.0,
+                column: last.1,
+                message: format!("the program should leave 1 value but leaves {}", depth),
+            });
+        }
+
+        Ok(prg)
+    }
+}
+
+/// Parses an instruction of the code, or of the code of a function taking
+/// `arity` operands, checking that what it refers to exists
+fn parse_instruction(prg: &Program, token: &str, arity: Option<usize>) -> Result<Expr, String> {
+    if let Some(n) = parse_index(token, "fn") {
+        return match (prg.functions.get(n), arity) {
+            (Some(function), None) => Ok(Expr::Call(n as u16, function.arity as u8)),
+            (Some(_), Some(_)) => Err(format!("'{}' is called from a function", token)),
+            (None, _) => Err(format!(
+                "'{}' is not one of the {} functions",
+                token,
+                prg.functions.len()
+            )),
+        };
+    }
+
+    let expr = token
+        .parse::<Expr>()
+        .map_err(|_| format!("unknown instruction '{}'", token))?;
+
+    match (expr, arity) {
+        (Expr::ConstRef(i), None) if i >= prg.consts.len() => Err(format!(
+            "'{}' is not in the constant pool of {}",
+            token,
+            prg.consts.len()
+        )),
+        (Expr::Arg(i), Some(arity)) if i >= arity => Err(format!(
+            "'{}' is not an operand of a function taking {}",
+            token, arity
+        )),
+        (Expr::Arg(_), None) => Err(format!("'{}' is only allowed in functions", token)),
+        (Expr::Drop, _) => Err(format!("'{}' can not be evaluated", token)),
+        (Expr::Var(_) | Expr::ConstRef(_), Some(_)) => {
+            Err(format!("'{}' is not allowed in functions", token))
+        }
+        (expr, _) => Ok(expr),
+    }
+}
+
+/// Parses a single instruction mnemonic, variable name or number
+impl FromStr for Expr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let expr = match s {
+            "+" => Expr::Add,
+            "-" => Expr::Sub,
+            "*" => Expr::Mul,
+            "max" => Expr::Max,
+            "min" => Expr::Min,
+            "/" => Expr::Div,
+            "mod" => Expr::Mod,
+            "atan2" => Expr::Atan2,
+            "cos" => Expr::Cos,
+            "sin" => Expr::Sin,
+            "atan" => Expr::Atan,
+            "abs" => Expr::Abs,
+            "sqrt" => Expr::Sqrt,
+            "exp" => Expr::Exp,
+            "log" => Expr::Log,
+            "tanh" => Expr::Tanh,
+            "step" => Expr::Step,
+            "floor" => Expr::Floor,
+            "fract" => Expr::Fract,
+            "select" => Expr::Select,
+            "rect" => Expr::Rect,
+            "disc" => Expr::Disc,
+            "linedist" => Expr::LineDist,
+            "smoothstep" => Expr::Smoothstep,
+            "vnoise" => Expr::ValueNoise,
+            "perlin" => Expr::Perlin,
+            "worley" => Expr::Worley,
+            "fbm" => Expr::Fbm,
+            "drop" => Expr::Drop,
+            "dup" => Expr::Dup,
+            _ => match parse_var_str(s) {
+                Some(i) => Expr::Var(i),
+                None => match (parse_index(s, "c"), parse_index(s, "a")) {
+                    (Some(i), _) => Expr::ConstRef(i),
+                    (_, Some(i)) => Expr::Arg(i),
+                    _ => Expr::Const(s.parse::<f32>().map_err(|_| ())?),
+                },
+            },
+        };
+
+        Ok(expr)
+    }
+}
+
+/// The number after the prefix, e.g. 3 for `c3` with the prefix "c"
+fn parse_index(s: &str, prefix: &str) -> Option<usize> {
+    let digits = s.strip_prefix(prefix)?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse::<usize>().ok()
+}
+
+fn parse_directive(
+    prg: &mut Program,
+    directive: &str,
+    args: &[(usize, &str)],
+    line: usize,
+    column: usize,
+) -> Result<(), ParseError> {
+    let error = |column: usize, message: String| ParseError {
+        line,
+        column,
+        message,
+    };
+
+    let one_argument = || match args {
+        [arg] => Ok(*arg),
+        _ => Err(error(
+            column,
+            format!("'@{}' takes exactly one argument", directive),
+        )),
+    };
+
+    match directive {
+        "transfer" => {
+            let (arg_column, arg) = one_argument()?;
+            prg.transfer.function = arg
+                .parse::<TransferFn>()
+                .map_err(|_| error(arg_column, format!("unknown transfer function '{}'", arg)))?;
+        }
+        "gamma" => {
+            let (arg_column, arg) = one_argument()?;
+            prg.transfer.gamma = arg
+                .parse::<f32>()
+                .map_err(|_| error(arg_column, format!("'{}' is not a number", arg)))?;
+        }
+        "mapping" => {
+            let (arg_column, arg) = one_argument()?;
+            prg.coords.mapping = arg
+                .parse::<Mapping>()
+                .map_err(|_| error(arg_column, format!("unknown mapping '{}'", arg)))?;
+        }
+        "sampling" => {
+            let (arg_column, arg) = one_argument()?;
+            prg.coords.sampling = arg
+                .parse::<Sampling>()
+                .map_err(|_| error(arg_column, format!("unknown sampling '{}'", arg)))?;
+        }
+        "origin" => {
+            let (arg_column, arg) = one_argument()?;
+            prg.coords.origin = arg
+                .parse::<Origin>()
+                .map_err(|_| error(arg_column, format!("unknown origin '{}'", arg)))?;
+        }
+        "size" => {
+            let [width, height] = args else {
+                return Err(error(
+                    column,
+                    "'@size' takes a width and a height".to_string(),
+                ));
+            };
+            let dimension = |&(arg_column, arg): &(usize, &str)| match arg.parse::<i32>() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(error(arg_column, format!("'{}' is not a valid size", arg))),
+            };
+            prg.size = Some((dimension(width)?, dimension(height)?));
+        }
+        "fn" => {
+            let Some(&(arity_column, arity)) = args.first() else {
+                return Err(error(
+                    column,
+                    "'@fn' takes an arity and the code".to_string(),
+                ));
+            };
+            let arity = match arity.parse::<usize>() {
+                Ok(n) if (1..=2).contains(&n) => n,
+                _ => {
+                    return Err(error(
+                        arity_column,
+                        format!("'{}' is not an arity of 1 or 2", arity),
+                    ))
+                }
+            };
+
+            let mut code = Vec::new();
+            let mut depth = 0usize;
+            for &(token_column, token) in args[1..].iter() {
+                let expr = parse_instruction(prg, token, Some(arity))
+                    .map_err(|message| error(token_column, message))?;
+                let (npop, npush) = expr.stack_effect();
+                if depth < npop {
+                    return Err(error(
+                        token_column,
+                        format!(
+                            "'{}' needs {} values but the stack has {}",
+                            token, npop, depth
+                        ),
+                    ));
+                }
+                depth = depth - npop + npush;
+                code.push(expr);
+            }
+            if depth != 1 {
+                return Err(error(
+                    column,
+                    format!("the function should leave 1 value but leaves {}", depth),
+                ));
+            }
+
+            prg.functions.push(Function { arity, code });
+        }
+        "consts" => {
+            for &(arg_column, arg) in args {
+                let x = arg
+                    .parse::<f32>()
+                    .map_err(|_| error(arg_column, format!("'{}' is not a number", arg)))?;
+                prg.consts.push(x);
+            }
+        }
+        _ => return Err(error(column, format!("unknown directive '@{}'", directive))),
+    }
+
+    Ok(())
+}
+
+/// Splits a line on whitespace, keeping the 1-based column of each token
+fn tokens(line: &str) -> Vec<(usize, &str)> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+
+    for (i, c) in line.char_indices() {
+        match (c.is_whitespace(), start) {
+            (true, Some(s)) => {
+                tokens.push((s, &line[s..i]));
+                start = None;
+            }
+            (false, None) => start = Some(i),
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((s, &line[s..]));
+    }
+
+    tokens
+        .into_iter()
+        .map(|(i, token)| (line[..i].chars().count() + 1, token))
+        .collect()
+}
+
+/// Reads a program saved as text, e.g. `result/00042.txt`
+pub fn load_program(path: &str) -> Result<Program, Box<dyn Error>> {
+    let text = fs::read_to_string(path)?;
+    let prg = text
+        .parse::<Program>()
+        .map_err(|e| format!("{}:{}", path, e))?;
+
+    Ok(prg)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        coords::Coords,
+        inputs::{X, Y},
+        mutate::{random_code, OpSet},
+        transfer::Transfer,
+    };
+
+    #[test]
+    pub fn parse_prg() {
+        let prg: Program = "1 x 2 + *".parse().unwrap();
+
+        assert_eq!(
+            vec![
+                Expr::Const(1.0),
+                Expr::Var(0),
+                Expr::Const(2.0),
+                Expr::Add,
+                Expr::Mul,
+            ],
+            prg.code
+        );
+    }
+
+    #[test]
+    pub fn parse_all_mnemonics() {
+        let text = "x y + y - x * y max x min cos sin atan dup + -0.5 + r theta * + f2 + \
+                    y / x mod y atan2 abs sqrt exp log tanh step floor fract x y select \
+                    x y 0.5 disc x 0.2 0.3 rect y 0.5 linedist x y smoothstep \
+                    x y 1 vnoise y 2 perlin x 3 worley y 4 fbm +";
+        let prg: Program = text.parse().unwrap();
+
+        assert_eq!(text, format!("{}", prg));
+    }
+
+    #[test]
+    pub fn parse_directives_and_comments() {
+        let text =
+            "# saved program\n@transfer tanh\n@gamma 0.5\n@size 64 48\nx sin # the code\ny +\n";
+        let prg: Program = text.parse().unwrap();
+
+        assert_eq!(Transfer::new(TransferFn::Tanh, 0.5), prg.transfer);
+        assert_eq!(Some((64, 48)), prg.size);
+        assert_eq!(
+            "@transfer tanh\n@gamma 0.5\n@size 64 48\nx sin y +",
+            format!("{}", prg)
+        );
+    }
+
+    #[test]
+    pub fn parse_coords() {
+        let text = "@mapping aspect\n@sampling center\n@origin topleft\nx y *";
+        let prg: Program = text.parse().unwrap();
+
+        assert_eq!(
+            Coords::new(Mapping::Aspect, Sampling::Center, Origin::TopLeft),
+            prg.coords
+        );
+        assert_eq!(text, format!("{}", prg));
+    }
+
+    #[test]
+    pub fn round_trip_random_programs() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..100 {
+            let prg = Program::new(random_code(&mut rng, 6, &[X, Y], &OpSet::DEFAULT));
+            let parsed: Program = format!("{}", prg).parse().unwrap();
+
+            assert_eq!(prg, parsed);
+        }
+    }
+
+    #[test]
+    pub fn parse_const_pool() {
+        let text = "@consts 0.5 -3\nc1 x * c0 + c1 *";
+        let prg: Program = text.parse().unwrap();
+
+        assert_eq!(vec![0.5, -3.0], prg.consts);
+        assert_eq!(Expr::ConstRef(1), prg.code[0]);
+        assert_eq!(text, format!("{}", prg));
+
+        let error = "@consts 0.5\nx c1 +".parse::<Program>().unwrap_err();
+        assert_eq!((2, 3), (error.line, error.column));
+    }
+
+    #[test]
+    pub fn parse_functions() {
+        let text = "@fn 1 a0 3 * sin\n@fn 2 a0 a1 - dup *\nx fn0 y fn0 fn1";
+        let prg: Program = text.parse().unwrap();
+
+        assert_eq!(2, prg.functions[1].arity);
+        assert_eq!(Expr::Arg(1), prg.functions[1].code[1]);
+        assert_eq!(Expr::Call(1, 2), prg.code[4]);
+        assert_eq!(text, format!("{}", prg));
+
+        for (text, column) in [
+            ("@fn 1 a1\nx fn0", 7),
+            ("@fn 1 x\nx fn0", 7),
+            ("@fn 3 a0\nx fn0", 5),
+            ("@fn 1 a0\nx fn1", 3),
+            ("x a0 +", 3),
+        ] {
+            let error = text.parse::<Program>().unwrap_err();
+            assert_eq!(column, error.column, "{}", text);
+        }
+    }
+
+    #[test]
+    pub fn error_unknown_instruction() {
+        let error = "x y\n  + foo".parse::<Program>().unwrap_err();
+
+        assert_eq!((2, 5), (error.line, error.column));
+        assert_eq!("2:5: unknown instruction 'foo'", format!("{}", error));
+    }
+
+    #[test]
+    pub fn error_drop() {
+        let error = "x y drop".parse::<Program>().unwrap_err();
+
+        assert_eq!("1:5: 'drop' can not be evaluated", format!("{}", error));
+    }
+
+    #[test]
+    pub fn error_underflow() {
+        let error = "x sin +".parse::<Program>().unwrap_err();
+
+        assert_eq!((1, 7), (error.line, error.column));
+    }
+
+    #[test]
+    pub fn error_values_left() {
+        let error = "x y".parse::<Program>().unwrap_err();
+
+        assert_eq!((1, 4), (error.line, error.column));
+    }
+
+    #[test]
+    pub fn error_directive() {
+        let error = "@transfer foo\nx".parse::<Program>().unwrap_err();
+        assert_eq!((1, 11), (error.line, error.column));
+
+        let error = "@foo 1\nx".parse::<Program>().unwrap_err();
+        assert_eq!((1, 1), (error.line, error.column));
+
+        let error = "@size 64 -1\nx".parse::<Program>().unwrap_err();
+        assert_eq!((1, 10), (error.line, error.column));
+
+        let error = "@size 64\nx".parse::<Program>().unwrap_err();
+        assert_eq!((1, 1), (error.line, error.column));
+    }
+}
@@ -1,25 +1,94 @@
-use rand::{rngs::StdRng, SeedableRng};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 use rayon::prelude::*;
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
-use std::{fs::File, io::Write, iter::zip};
-
-use crate::eval::eval;
+use std::{fs::File, io::Write};
+
+use crate::autodiff::image_error_gradient;
+use crate::cgp::{Genome, Grid};
+use crate::codegen::{to_c, to_glsl, to_python, to_rust, to_wgsl};
+use crate::dag::Dag;
+use crate::infix::{to_infix, to_latex};
+use crate::lgp::{LinearProgram, Machine};
 use crate::optimize::optimize;
+use crate::parse::load_program;
 use crate::{
+    coords::{Coords, Mapping, Origin, Sampling},
     expr::Program,
-    mutate::mutate,
+    inputs::{check_features, Features, FIRST_FEATURE, R, THETA, X, Y},
+    mutate::{crossover_functions, mutate, mutate_gamma, OpSet},
     myimage::{GrayScaleImage, MyRgbImage},
-    population::{Individual, Population},
-    state::State,
+    population::{one_plus_lambda, Individual, Population, Strategy},
+    scaling::LinearScale,
+    semantic::{simplify_semantic, SemanticTolerance},
+    transfer::{Transfer, TransferFn},
+    tune::{tune_constants, tune_constants_adam, TuneMethod},
 };
 
 /// Set to true if you want a <number>.txt written containing the code for the image
 const OUTPUT_CODE: bool = true;
 const OUTPUT_OPTIMIZED_CODE: bool = false;
 
+/// Set to true to also write the code as a readable formula to <number>_infix.txt,
+/// and as LaTeX to <number>_latex.txt
+const OUTPUT_INFIX: bool = false;
+const OUTPUT_LATEX: bool = false;
+
+/// Set to true to also write the code as fragment shaders to <number>.frag (GLSL)
+/// and <number>.wgsl
+const OUTPUT_SHADERS: bool = false;
+
+/// Set to true to also write the code as standalone functions to <number>.rs,
+/// <number>.c and <number>.py (NumPy)
+const OUTPUT_SOURCE: bool = false;
+
+/// Tolerances when replacing subtrees that are numerically constant, or equal
+/// to x or y, in the optimized code. None skips the (slow) semantic pass.
+const SEMANTIC_TOLERANCE: Option<SemanticTolerance> = Some(SemanticTolerance {
+    value: 1e-3,
+    error: 1e-3,
+});
+
+/// Set to the path of a saved program, e.g. "result/00042.txt", to start
+/// the whole population from it instead of from scratch
+const SEED_PROGRAM: Option<&str> = None;
+
 /// The maximum number of generations before the program ends
 const NGENERATIONS: u32 = 15000;
 
+/// How programs are represented while they evolve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Representation {
+    /// Postfix code, a population mutated as set below
+    Postfix,
+    /// A Cartesian GP genome evolved with the (1+λ) strategy, see cgp.rs
+    Cgp,
+    /// Register based linear GP evolved with the (1+λ) strategy, see lgp.rs.
+    /// Only the unary and binary operators of OPS are used.
+    Lgp,
+}
+
+/// Gamma, tuning and functions only apply to Postfix
+const REPRESENTATION: Representation = Representation::Postfix;
+
+/// The number of offspring per generation of Cgp and Lgp
+const LAMBDA: usize = 64;
+
+/// The grid of the CGP genome
+const CGP_GRID: Grid = Grid {
+    rows: 1,
+    columns: 100,
+    levels_back: 100,
+    constants: 4,
+};
+
+/// The register machine of linear GP
+const LGP_MACHINE: Machine = Machine {
+    registers: 8,
+    constants: 4,
+    max_length: 200,
+};
+
 /// The number of individuals in each generation.
 /// Higher number is slower but not always better.
 /// 20-1000 seems like good values depending on the image.
@@ -32,13 +101,124 @@ const USE_ELITISM: usize = 0;
 /// The number of the best individuals the next generation will be based on
 const NBEST: usize = 1;
 
-/// The number of mutations done on each individual
+/// The number of mutations done on each individual.
+/// With SELF_ADAPTATION this is only the starting value.
 const NUMBER_OF_MUTATIONS: usize = 10;
 
-/// Number of variables, 2 means x and y
-const NVARS: usize = 2;
+/// The initial standard deviation used when tweaking constants
+const INITIAL_SIGMA: f32 = 1.0;
+
+/// Set to true to let each individual evolve its own number of mutations
+/// and constant step size
+const SELF_ADAPTATION: bool = false;
+
+/// How the program output is mapped to pixels, recorded with each program
+const TRANSFER_FN: TransferFn = TransferFn::Clamp;
 
-pub fn approx_pic() {
+/// Set to true to let each individual evolve the gamma of its output mapping
+const EVOLVE_GAMMA: bool = false;
+
+/// The chance, in percent, that a new individual gets its gamma mutated
+const GAMMA_MUTATION_PERCENT: u32 = 5;
+
+/// The chance, in percent, that a new individual gets a piece of a function
+/// of another of the NBEST individuals, when OPS has functions and NBEST > 1
+const FUNCTION_CROSSOVER_PERCENT: u32 = 5;
+
+/// Set to true to score each individual as a + b * output, with a and b
+/// fitted by least squares against the goal image
+const LINEAR_SCALING: bool = false;
+
+/// Fine-tune the constants of the NBEST individuals every n generations, 0 disables it
+const TUNE_EVERY: u32 = 0;
+
+/// How the constants are tuned
+const TUNE_METHOD: TuneMethod = TuneMethod::CoordinateDescent;
+
+/// The number of coordinate descent passes over the constants when tuning
+const TUNE_PASSES: usize = 5;
+
+/// The number of steps and the step size when tuning with Adam
+const ADAM_STEPS: usize = 50;
+const ADAM_LEARNING_RATE: f32 = 0.01;
+
+/// How pixels are mapped to x and y, see coords.rs.
+/// Aspect keeps circles round on images that are not square.
+const COORDS: Coords = Coords {
+    mapping: Mapping::Stretch,
+    sampling: Sampling::Corner,
+    origin: Origin::Center,
+};
+
+/// Set to true to give the programs r = sqrt(x² + y²) and theta = atan2(y, x)
+/// as inputs besides x and y
+const POLAR_INPUTS: bool = false;
+
+/// Images given to the programs as the extra inputs f0, f1, ..., e.g. an
+/// edge map of the goal image. They are resampled to the size evaluated at.
+const FEATURE_IMAGES: &[&str] = &[];
+
+/// The operators mutations may use, see mutate.rs. OpSet::ALL adds
+/// protected division, abs, sqrt, exp/log, tanh, step, floor/fract/mod,
+/// atan2, max/min and select, the shapes rect, disc, linedist and
+/// smoothstep that suit images of geometric figures, and vnoise, perlin,
+/// worley and fbm noise for textures. Or list just the ones wanted.
+///
+/// `constants` sets how new constants are drawn, e.g. ConstDist::Integer(8),
+/// and `pool: true` keeps them in a pool per program that mutations can
+/// share between instructions, saved as `@consts` before the code.
+/// `functions: &[1, 2]` evolves a function of one and one of two operands
+/// along with each program, called as fn0 and fn1 and saved as `@fn`
+/// lines. The exported code has them inlined.
+const OPS: OpSet = OpSet::DEFAULT;
+
+/// The feature channels, loaded by approx_pic
+static FEATURES: OnceLock<Features> = OnceLock::new();
+
+/// Feature channels sampled at a width and height
+type SampledFeatures = ((i32, i32), Arc<Vec<Vec<f32>>>);
+
+/// The feature channels sampled at the size last evaluated at
+static SAMPLED_FEATURES: Mutex<Option<SampledFeatures>> = Mutex::new(None);
+
+fn load_features() -> Result<(), String> {
+    let features = Features::load(FEATURE_IMAGES)?;
+    FEATURES.get_or_init(|| features);
+    Ok(())
+}
+
+/// The feature channels, none if approx_pic has not loaded them
+pub fn features() -> &'static Features {
+    FEATURES.get_or_init(Features::default)
+}
+
+/// The feature channels sampled on a width x height grid. Every program
+/// of a run is evaluated at the same size, so they are only sampled again
+/// when the size changes.
+pub fn sampled_features(width: i32, height: i32) -> Arc<Vec<Vec<f32>>> {
+    let mut sampled = SAMPLED_FEATURES.lock().unwrap();
+    match &*sampled {
+        Some((size, channels)) if *size == (width, height) => channels.clone(),
+        _ => {
+            let channels = Arc::new(features().sampled(width, height));
+            *sampled = Some(((width, height), channels.clone()));
+            channels
+        }
+    }
+}
+
+/// The Var numbers mutation picks inputs from
+fn input_vars() -> Vec<usize> {
+    let mut vars = vec![X, Y];
+    if POLAR_INPUTS {
+        vars.extend([R, THETA]);
+    }
+    vars.extend((0..FEATURE_IMAGES.len()).map(|i| FIRST_FEATURE + i));
+    vars
+}
+
+pub fn approx_pic() -> Result<(), String> {
+    load_features()?;
     let mut rng = StdRng::from_rng(rand::thread_rng()).unwrap();
 
     //
@@ -67,53 +247,180 @@ pub fn approx_pic() {
         .unwrap()
         .to_gray_scale_image();
 
+    let settings = Program {
+        transfer: Transfer::new(TRANSFER_FN, 1.0),
+        coords: COORDS,
+        ..Program::new(vec![])
+    };
+    let vars = input_vars();
+    match REPRESENTATION {
+        Representation::Postfix => run_postfix(&goal_image, &mut rng)?,
+        Representation::Cgp => {
+            let parent = Genome::random(&mut rng, CGP_GRID, &vars, &OPS);
+            run_one_plus_lambda(
+                &goal_image,
+                &mut rng,
+                parent,
+                |rng, genome| genome.mutate(rng, &OPS),
+                |genome| genome.to_program(&settings),
+            );
+        }
+        Representation::Lgp => {
+            let parent = LinearProgram::random(&mut rng, LGP_MACHINE, &vars, &OPS);
+            run_one_plus_lambda(
+                &goal_image,
+                &mut rng,
+                parent,
+                |rng, prg| prg.mutate(rng, &OPS),
+                |prg| prg.to_program(&settings),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn run_postfix(goal_image: &GrayScaleImage, rng: &mut StdRng) -> Result<(), String> {
     let npixels = goal_image.data.len() as u64;
 
-    let mut last_error: f32 = f32::MAX;
-    let mut population = Population::random(&rng, POPULATION_SIZE);
+    let strategy = Strategy::new(NUMBER_OF_MUTATIONS as f32, INITIAL_SIGMA);
+    let transfer = Transfer::new(TRANSFER_FN, 1.0);
+    let mut population = Population::random(rng, POPULATION_SIZE, strategy, transfer, COORDS);
+
+    if let Some(path) = SEED_PROGRAM {
+        let prg = load_program(path).map_err(|error| format!("{}: {}", path, error))?;
+        check_features(&prg, features().len()).map_err(|error| format!("{}: {}", path, error))?;
+        for individual in population.individuals.iter_mut() {
+            individual.prg = prg.clone();
+        }
+    }
+    let vars = input_vars();
     let mut file_number = 0u64;
     for gen in 0..NGENERATIONS {
         let start_time = Instant::now();
 
-        simulate(&goal_image, &mut population);
+        simulate(goal_image, &mut population);
+
+        if TUNE_EVERY != 0 && gen % TUNE_EVERY == 0 {
+            tune_elite(goal_image, &mut population);
+        }
 
-        let best_ind_error = population.individuals[0].error.unwrap();
-        // if best_ind_error < last_error {
         file_number += 1;
-        save_best(&goal_image, &mut population, file_number);
-        last_error = best_ind_error;
-        // }
+        save_best(goal_image, &mut population, file_number);
 
-        population = evolve(gen, population, &mut rng, NVARS);
+        population = evolve(gen, population, rng, &vars);
 
         let simulate_time = start_time.elapsed();
         print_best_info(&population, gen, npixels, simulate_time);
     }
+
+    Ok(())
+}
+
+/// Evolves a genome with the (1+λ) strategy, scoring and saving the
+/// programs `decode` turns it into like the postfix ones
+fn run_one_plus_lambda<G: Clone + Send + Sync>(
+    goal_image: &GrayScaleImage,
+    rng: &mut StdRng,
+    mut parent: G,
+    mutate: impl Fn(&mut StdRng, &mut G),
+    decode: impl Fn(&G) -> Option<Program> + Sync,
+) {
+    let npixels = goal_image.data.len() as u64;
+    let error_fn = |genome: &G| match decode(genome) {
+        Some(prg) => program_error(goal_image, &prg).0,
+        None => f32::MAX,
+    };
+
+    let mut parent_error = error_fn(&parent);
+    let mut last_error = f32::MAX;
+    let mut file_number = 0u64;
+    for gen in 0..NGENERATIONS {
+        let start_time = Instant::now();
+
+        (parent, parent_error) =
+            one_plus_lambda(rng, parent, parent_error, LAMBDA, &mutate, error_fn);
+
+        let Some(prg) = decode(&parent) else {
+            continue;
+        };
+        let mut population = Population {
+            individuals: vec![Individual {
+                prg,
+                error: Some(parent_error),
+                strategy: Strategy::new(1.0, 0.0),
+                scale: None,
+            }],
+        };
+
+        // Most generations are neutral, only improvements are saved
+        if parent_error < last_error {
+            let best_ind = &mut population.individuals[0];
+            best_ind.scale = program_error(goal_image, &best_ind.prg).1;
+            file_number += 1;
+            save_best(goal_image, &mut population, file_number);
+            last_error = parent_error;
+        }
+
+        print_best_info(&population, gen, npixels, start_time.elapsed());
+    }
 }
 
 fn print_best_info(population: &Population, gen: u32, npixels: u64, duration: Duration) {
     let best_ind = &population.individuals[0];
 
-    let best_ind_error = best_ind.error.unwrap() as f32;
+    let best_ind_error = best_ind.error.unwrap();
     let code_size = best_ind.prg.code.len();
     let error_per_pixel = best_ind_error / (npixels as f32);
+    let mutations = best_ind.strategy.nmutations;
+    let sigma = best_ind.strategy.sigma;
     let time = duration.as_millis();
 
-    println!("Gen: {gen}, Code: {code_size}, Error: {error_per_pixel:.7}, Time: {time} ms");
+    println!("Gen: {gen}, Code: {code_size}, Error: {error_per_pixel:.7}, Mutations: {mutations:.1}, Sigma: {sigma:.4}, Time: {time} ms");
 }
 
-fn evolve(gen: u32, population: Population, rng: &mut StdRng, nvars: usize) -> Population {
+fn evolve(gen: u32, population: Population, rng: &mut StdRng, vars: &[usize]) -> Population {
     let mut new_population = Population {
         individuals: Vec::with_capacity(POPULATION_SIZE),
     };
 
     // New population is a mutated version of the NBEST individuals from previous generation
     for i in 0..POPULATION_SIZE {
-        let mut individual = population.individuals[i % NBEST].clone();
+        #[allow(clippy::modulo_one)]
+        let parent = i % NBEST;
+        let mut individual = population.individuals[parent].clone();
+
+        if SELF_ADAPTATION {
+            individual.strategy.mutate(rng);
+        }
 
-        for _ in 0..NUMBER_OF_MUTATIONS {
+        for _ in 0..individual.strategy.mutation_count() {
             // Mutate
-            mutate(rng, &mut individual.prg, nvars);
+            mutate(
+                rng,
+                &mut individual.prg,
+                vars,
+                &OPS,
+                individual.strategy.sigma,
+            );
+        }
+
+        if EVOLVE_GAMMA && rng.gen_range(0..100) < GAMMA_MUTATION_PERCENT {
+            mutate_gamma(rng, &mut individual.prg);
+        }
+
+        // The donor is one of the other NBEST individuals, so there is none
+        // with a single one
+        if !OPS.functions.is_empty()
+            && NBEST > 1
+            && rng.gen_range(0..100) < FUNCTION_CROSSOVER_PERCENT
+        {
+            let mut donor = rng.gen_range(0..NBEST - 1);
+            if donor >= parent {
+                donor += 1;
+            }
+            let other = &population.individuals[donor].prg;
+            crossover_functions(rng, &mut individual.prg, other);
         }
 
         new_population.individuals.push(individual);
@@ -141,71 +448,223 @@ fn simulate(goal_image: &GrayScaleImage, population: &mut Population) {
         .sort_by(|a, b| a.error.unwrap().partial_cmp(&b.error.unwrap()).unwrap());
 }
 
+/// Fine-tunes the constants of the individuals the next generation is based on
+fn tune_elite(goal_image: &GrayScaleImage, population: &mut Population) {
+    let nbest = NBEST.min(population.size());
+    let elite = &mut population.individuals[..nbest];
+
+    elite.par_iter_mut().for_each(|individual| {
+        tune_individual(goal_image, individual, |prg| program_error(goal_image, prg))
+    });
+
+    // Tuning only lowers errors, so the elite stays ahead of the rest
+    elite.sort_by(|a, b| a.error.unwrap().partial_cmp(&b.error.unwrap()).unwrap());
+}
+
+/// Fine-tunes the constants of an individual. `scored_error_fn` returns the
+/// error of a program and the linear scaling it was calculated with, which
+/// is kept with the error so the saved program is the one scored.
+fn tune_individual(
+    goal_image: &GrayScaleImage,
+    individual: &mut Individual,
+    scored_error_fn: impl Fn(&Program) -> (f32, Option<LinearScale>),
+) {
+    let error_fn = |prg: &Program| scored_error_fn(prg).0;
+
+    match TUNE_METHOD {
+        TuneMethod::CoordinateDescent => {
+            tune_constants(&mut individual.prg, error_fn, TUNE_PASSES);
+        }
+        TuneMethod::Adam => {
+            // Adam minimizes a stand-in error, only keep the result if
+            // the real error improved too
+            let original = individual.prg.clone();
+            let error_gradient_fn = |prg: &Program| image_error_gradient(goal_image, prg);
+            tune_constants_adam(
+                &mut individual.prg,
+                error_gradient_fn,
+                ADAM_STEPS,
+                ADAM_LEARNING_RATE,
+            );
+
+            if error_fn(&individual.prg) >= individual.error.unwrap() {
+                individual.prg = original;
+                return;
+            }
+        }
+    }
+
+    // The scaling of the tuned program, not the one fitted before tuning
+    let (error, scale) = scored_error_fn(&individual.prg);
+    individual.error = Some(error);
+    individual.scale = scale;
+}
+
 fn save_best(goal_image: &GrayScaleImage, population: &mut Population, file_number: u64) {
     let best_ind = &population.individuals[0];
 
+    // The linear scaling becomes part of the saved program
+    let mut best_prg = match best_ind.scale {
+        Some(scale) => scale.fold(&best_ind.prg),
+        None => best_ind.prg.clone(),
+    };
+    // So the program can be rendered at other sizes with the same aspect ratio
+    best_prg.size = Some((goal_image.width, goal_image.height));
+    if OPS.pool {
+        // The linear scaling constants too
+        best_prg = best_prg.pooled();
+    }
+
     // Save image result
     let filename = format!("result/{:05}.png", file_number);
-    let generated_image = eval_into_image(goal_image, &best_ind.prg);
+    let generated_image = eval_into_image(goal_image, &best_prg);
     save_comparison_image(goal_image, &generated_image, filename.as_str());
 
     if OUTPUT_CODE {
         // Save code result
         let filename = format!("result/{:05}.txt", file_number);
         let mut output = File::create(filename).unwrap();
-        let line = format!("{}", best_ind.prg);
+        let line = format!("{}", best_prg);
         output.write_all(line.as_bytes()).unwrap();
     }
 
+    if OUTPUT_INFIX {
+        // Save the code as a formula
+        let filename = format!("result/{:05}_infix.txt", file_number);
+        let mut output = File::create(filename).unwrap();
+        let line = to_infix(&best_prg);
+        output.write_all(line.as_bytes()).unwrap();
+    }
+
+    if OUTPUT_LATEX {
+        // Save the code as a LaTeX formula
+        let filename = format!("result/{:05}_latex.txt", file_number);
+        let mut output = File::create(filename).unwrap();
+        let line = to_latex(&best_prg);
+        output.write_all(line.as_bytes()).unwrap();
+    }
+
+    if OUTPUT_SHADERS {
+        // Save the code as shaders rendering it on the GPU
+        let filename = format!("result/{:05}.frag", file_number);
+        let mut output = File::create(filename).unwrap();
+        output.write_all(to_glsl(&best_prg).as_bytes()).unwrap();
+
+        let filename = format!("result/{:05}.wgsl", file_number);
+        let mut output = File::create(filename).unwrap();
+        output.write_all(to_wgsl(&best_prg).as_bytes()).unwrap();
+    }
+
+    if OUTPUT_SOURCE {
+        // Save the code as functions for embedding in other tools
+        let sources = [
+            ("rs", to_rust(&best_prg)),
+            ("c", to_c(&best_prg)),
+            ("py", to_python(&best_prg)),
+        ];
+        for (extension, source) in sources {
+            let filename = format!("result/{:05}.{}", file_number, extension);
+            let mut output = File::create(filename).unwrap();
+            output.write_all(source.as_bytes()).unwrap();
+        }
+    }
+
     if OUTPUT_OPTIMIZED_CODE {
         // Save optimized code result
         let filename = format!("result/{:05}_opt.txt", file_number);
         let mut output = File::create(filename).unwrap();
-        let opt_code = optimize(&best_ind.prg);
+        let opt_code = match SEMANTIC_TOLERANCE {
+            Some(tolerance) => optimize(&simplify_semantic(
+                &optimize(&best_prg),
+                goal_image,
+                &tolerance,
+                &input_vars(),
+            )),
+            None => optimize(&best_prg),
+        };
         let line = format!("{}", opt_code);
         output.write_all(line.as_bytes()).unwrap();
     }
 }
 
 fn eval_individual(goal_image: &GrayScaleImage, individual: &mut Individual) {
-    let generated_image = eval_into_image(goal_image, &individual.prg);
-    let error_sum = calc_image_error(goal_image, &generated_image);
+    let (error_sum, scale) = program_error(goal_image, &individual.prg);
 
     // individual.error = Some(error_sum + individual.prg.code.len() as u64);
     individual.error = Some(error_sum);
+    individual.scale = scale;
 }
 
-fn eval_into_image(goal_image: &GrayScaleImage, prg: &Program) -> GrayScaleImage {
-    let mut image = GrayScaleImage::with_dimensions(goal_image.width, goal_image.height);
+/// Returns the error of the program output compared to the goal image.
+/// With LINEAR_SCALING the output is first scaled by the least squares
+/// optimal a + b * output, which is returned too.
+fn program_error(goal_image: &GrayScaleImage, prg: &Program) -> (f32, Option<LinearScale>) {
+    scaled_program_error(goal_image, prg, LINEAR_SCALING)
+}
+
+fn scaled_program_error(
+    goal_image: &GrayScaleImage,
+    prg: &Program,
+    linear_scaling: bool,
+) -> (f32, Option<LinearScale>) {
+    let mut outputs = eval_outputs(goal_image, prg);
+
+    let scale = if linear_scaling {
+        let scale = LinearScale::fit(&outputs, goal_image, &prg.transfer);
+        scale.apply_all(&mut outputs);
+        Some(scale)
+    } else {
+        None
+    };
+
+    let generated_image = outputs_into_image(goal_image, &outputs, &prg.transfer);
+    let error_sum = calc_image_error(goal_image, &generated_image);
 
-    // State is where x and y are stored
-    let mut state = State::new(NVARS);
+    (error_sum, scale)
+}
 
-    for y in 0..image.height {
-        for x in 0..image.width {
-            // Convert width and height from
-            //   0..height/width
-            // to
-            //   -1.0 to +1.0
-            state.vars[0] = (x as f32) / (image.width as f32) * 2.0 - 1.0;
-            state.vars[1] = (y as f32) / (image.height as f32) * 2.0 - 1.0;
+pub fn eval_into_image(goal_image: &GrayScaleImage, prg: &Program) -> GrayScaleImage {
+    let outputs = eval_outputs(goal_image, prg);
+    outputs_into_image(goal_image, &outputs, &prg.transfer)
+}
 
-            let mut result = eval(prg, &state);
+/// Evaluates the program for every pixel, returns the raw outputs row by row
+pub fn eval_outputs(goal_image: &GrayScaleImage, prg: &Program) -> Vec<f32> {
+    // Convert width and height from
+    //   0..height/width
+    // to the coordinates of the program, by default
+    //   -1.0 to +1.0
+    let (xs, ys) = prg.coords.axes(goal_image.width, goal_image.height);
 
-            // Limit the output to stay between -1.0 and 1.0
-            result = result.min(1.0).max(-1.0);
+    let features = sampled_features(goal_image.width, goal_image.height);
+    let features: Vec<&[f32]> = features.iter().map(Vec::as_slice).collect();
 
-            // Rescale the value to be from 0-255
-            result = result * 127.0 + 128.0;
+    // Common subexpressions are only computed once per pixel, and the
+    // parts depending only on x or only on y once per column or row
+    Dag::from_program(prg).eval_grid_with_features(&xs, &ys, &features)
+}
 
-            let pix = result.trunc() as u8;
-            image.data.push(pix);
-        }
+/// Converts raw program outputs to a gray-scale image
+pub fn outputs_into_image(
+    goal_image: &GrayScaleImage,
+    outputs: &[f32],
+    transfer: &Transfer,
+) -> GrayScaleImage {
+    let mut image = GrayScaleImage::with_dimensions(goal_image.width, goal_image.height);
+
+    for &output in outputs {
+        image.data.push(transfer.pixel(output));
     }
 
     image
 }
 
+/// Converts a pixel index 0..size to a coordinate from -1.0 to +1.0,
+/// as the default Coords do
+pub fn pixel_coord(i: i32, size: i32) -> f32 {
+    (i as f32) / (size as f32) * 2.0 - 1.0
+}
+
 pub fn save_comparison_image(goal: &GrayScaleImage, generated: &GrayScaleImage, filename: &str) {
     assert_eq!(goal.width, generated.width);
     assert_eq!(goal.height, generated.height);
@@ -236,12 +695,8 @@ fn get_pixel_error(
 ) -> Option<f32> {
     // println!("get_pixel_error: x={x}, y={y}");
 
-    let Some(goal_pixel) = goal_image.read_pixel2(x, y) else {
-        return None;
-    };
-    let Some(generated_pixel) = generated_image.read_pixel2(x, y) else {
-        return None;
-    };
+    let goal_pixel = goal_image.read_pixel2(x, y)?;
+    let generated_pixel = generated_image.read_pixel2(x, y)?;
 
     let error = goal_pixel.abs_diff(generated_pixel) as f32;
 
@@ -371,4 +826,39 @@ mod tests {
 
         assert_eq!(0.0, error);
     }
+
+    #[test]
+    pub fn tuning_keeps_the_scaling_of_the_tuned_program() {
+        let mut goal_image = GrayScaleImage::new(20, 20);
+        for y in 0..20 {
+            for x in 0..20 {
+                goal_image.write_pixel(x, y, (x * 12 + y) as u8);
+            }
+        }
+        let scored_error_fn = |prg: &Program| scaled_program_error(&goal_image, prg, true);
+
+        let prg: Program = "x 0.5 * 0.2 + sin y 0.1 * +".parse().unwrap();
+        let (error, scale) = scored_error_fn(&prg);
+        let mut individual = Individual {
+            prg,
+            error: Some(error),
+            strategy: Strategy::new(1.0, 1.0),
+            scale,
+        };
+        tune_individual(&goal_image, &mut individual, scored_error_fn);
+
+        assert!(individual.error.unwrap() < error);
+        assert_ne!(scale, individual.scale);
+
+        // The program save_best writes gives the error that was reported
+        let saved = individual.scale.unwrap().fold(&individual.prg);
+        let (saved_error, _) = scaled_program_error(&goal_image, &saved, false);
+        let reported = individual.error.unwrap();
+        assert!(
+            (saved_error - reported).abs() <= 1e-3 * reported,
+            "saved {} reported {}",
+            saved_error,
+            reported
+        );
+    }
 }
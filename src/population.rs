@@ -1,6 +1,25 @@
-use rand::rngs::StdRng;
+use rand::{rngs::StdRng, Rng};
+use rand_distr::StandardNormal;
+use rayon::prelude::*;
 
-use crate::expr::{Expr, Program};
+use crate::{
+    coords::Coords,
+    expr::{Expr, Program},
+    scaling::LinearScale,
+    transfer::Transfer,
+};
+
+/// Learning rate of the log-normal self-adaptation, 1/sqrt(2) is the
+/// usual choice for a couple of strategy parameters
+const SELF_ADAPT_TAU: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Bounds for the self-adapted number of mutations
+const MIN_MUTATIONS: f32 = 1.0;
+const MAX_MUTATIONS: f32 = 50.0;
+
+/// Bounds for the self-adapted constant step size
+const MIN_SIGMA: f32 = 1e-4;
+const MAX_SIGMA: f32 = 16.0;
 
 #[derive(Debug, Clone)]
 pub struct Population {
@@ -15,11 +34,17 @@ impl Population {
     }
 
     /// Generates a population with random simple individuals
-    pub fn random(rng: &StdRng, size: usize) -> Self {
+    pub fn random(
+        rng: &StdRng,
+        size: usize,
+        strategy: Strategy,
+        transfer: Transfer,
+        coords: Coords,
+    ) -> Self {
         let mut pop = Population::with_capacity(size);
 
         for i in 0..size {
-            let ind = Individual::random(rng);
+            let ind = Individual::random(rng, strategy, transfer, coords);
             pop.individuals.push(ind);
         }
 
@@ -35,16 +60,104 @@ impl Population {
 pub struct Individual {
     pub prg: Program,
     pub error: Option<f32>,
+    pub strategy: Strategy,
+    /// The linear scaling the error was calculated with, if any
+    pub scale: Option<LinearScale>,
 }
 
 impl Individual {
     /// Generates a random simple individual
-    pub fn random(rng: &StdRng) -> Self {
+    pub fn random(rng: &StdRng, strategy: Strategy, transfer: Transfer, coords: Coords) -> Self {
         Individual {
             error: None,
+            strategy,
+            scale: None,
             prg: Program {
-                code: vec![Expr::Const(1.0)],
+                transfer,
+                coords,
+                ..Program::new(vec![Expr::Const(1.0)])
             },
         }
     }
 }
+
+/// Mutation parameters that are inherited and mutated along with the
+/// program, as in evolution strategies
+#[derive(Debug, Clone, Copy)]
+pub struct Strategy {
+    /// The number of mutations done on the individual, rounded when used
+    pub nmutations: f32,
+    /// The standard deviation used when tweaking constants
+    pub sigma: f32,
+}
+
+impl Strategy {
+    pub fn new(nmutations: f32, sigma: f32) -> Self {
+        Self { nmutations, sigma }
+    }
+
+    /// Log-normal self-adaptation of the parameters
+    pub fn mutate(&mut self, rng: &mut StdRng) {
+        let n: f32 = rng.sample(StandardNormal);
+        self.nmutations =
+            (self.nmutations * (SELF_ADAPT_TAU * n).exp()).clamp(MIN_MUTATIONS, MAX_MUTATIONS);
+
+        let n: f32 = rng.sample(StandardNormal);
+        self.sigma = (self.sigma * (SELF_ADAPT_TAU * n).exp()).clamp(MIN_SIGMA, MAX_SIGMA);
+    }
+
+    pub fn mutation_count(&self) -> usize {
+        self.nmutations.round() as usize
+    }
+}
+
+/// One generation of the (1+λ) strategy: `lambda` mutants of the parent
+/// are scored by `error_fn` and the best of them replaces the parent,
+/// unless it is worse. Offspring as good as the parent win, letting the
+/// search drift over neutral changes.
+///
+/// Returns the new parent and its error.
+pub fn one_plus_lambda<G: Clone + Send + Sync>(
+    rng: &mut StdRng,
+    parent: G,
+    parent_error: f32,
+    lambda: usize,
+    mutate: impl Fn(&mut StdRng, &mut G),
+    error_fn: impl Fn(&G) -> f32 + Sync,
+) -> (G, f32) {
+    let offspring: Vec<G> = (0..lambda)
+        .map(|_| {
+            let mut child = parent.clone();
+            mutate(rng, &mut child);
+            child
+        })
+        .collect();
+
+    let errors: Vec<f32> = offspring.par_iter().map(&error_fn).collect();
+
+    let best = (0..lambda).min_by(|&a, &b| errors[a].total_cmp(&errors[b]));
+    match best {
+        Some(i) if errors[i] <= parent_error => (offspring[i].clone(), errors[i]),
+        _ => (parent, parent_error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    pub fn strategy_stays_within_bounds() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut strategy = Strategy::new(10.0, 1.0);
+
+        for _ in 0..1000 {
+            strategy.mutate(&mut rng);
+
+            assert!((1..=50).contains(&strategy.mutation_count()));
+            assert!(strategy.sigma >= MIN_SIGMA && strategy.sigma <= MAX_SIGMA);
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::error::Error;
+
+use image::{ImageBuffer, Luma};
+use rayon::prelude::*;
+
+use crate::{
+    dag::Dag,
+    expr::Program,
+    inputs::{check_features, Features},
+    parse::load_program,
+};
+
+/// The bits per channel of a rendered PNG
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+/// Returns the size to render at, keeping the aspect ratio of the image the
+/// program was evolved for.
+///
+/// With one side given the other is scaled to match, with both the image is
+/// fitted inside them, and with neither the original size is used.
+/// Programs without a recorded size are rendered as asked, an error without
+/// any size at all.
+pub fn render_size(
+    prg: &Program,
+    width: Option<i32>,
+    height: Option<i32>,
+) -> Result<(i32, i32), String> {
+    let scaled =
+        |n: i32, from: i32, to: i32| ((n as f64 * to as f64 / from as f64).round() as i32).max(1);
+
+    let size = match (prg.size, width, height) {
+        (Some((w, h)), None, None) => (w, h),
+        (Some((w, h)), Some(width), None) => (width, scaled(h, w, width)),
+        (Some((w, h)), None, Some(height)) => (scaled(w, h, height), height),
+        (Some((w, h)), Some(width), Some(height)) => {
+            if width as i64 * h as i64 <= height as i64 * w as i64 {
+                (width, scaled(h, w, width))
+            } else {
+                (scaled(w, h, height), height)
+            }
+        }
+        (None, Some(width), Some(height)) => (width, height),
+        (None, Some(side), None) | (None, None, Some(side)) => (side, side),
+        (None, None, None) => {
+            return Err("the program has no @size, give --width or --height".to_string())
+        }
+    };
+
+    Ok(size)
+}
+
+/// Renders the program, returning the level (-1.0 to 1.0) of every pixel
+/// row by row.
+///
+/// Each pixel is the mean of samples x samples evenly spaced points, placed
+/// like the pixels of an image that many times larger. A single sample gives
+/// the same image as the one saved during evolution. The program must only
+/// read the given feature channels, and width and height times samples must
+/// fit in an i32.
+pub fn render_levels(
+    prg: &Program,
+    width: i32,
+    height: i32,
+    samples: i32,
+    features: &Features,
+) -> Vec<f32> {
+    let dag = Dag::from_program(prg);
+    let (xs, all_ys) = prg.coords.axes(width * samples, height * samples);
+
+    // One row at a time, so huge images do not need all samples in memory
+    let rows: Vec<Vec<f32>> = (0..height)
+        .into_par_iter()
+        .map(|row| {
+            let band = row * samples..(row + 1) * samples;
+            let ys = &all_ys[band.start as usize..band.end as usize];
+            let features = features.sampled_rows(width * samples, height * samples, band);
+            let features: Vec<&[f32]> = features.iter().map(|channel| &channel[..]).collect();
+            let outputs = dag.eval_grid_with_features(&xs, ys, &features);
+
+            let mut levels = vec![0.0; width as usize];
+            for row_outputs in outputs.chunks(xs.len()) {
+                for (x, &output) in row_outputs.iter().enumerate() {
+                    levels[x / samples as usize] += prg.transfer.level(output);
+                }
+            }
+
+            let count = (samples * samples) as f32;
+            levels.iter().map(|level| level / count).collect()
+        })
+        .collect();
+
+    rows.concat()
+}
+
+/// Saves levels from render_levels as a gray-scale PNG
+pub fn save_levels(
+    levels: &[f32],
+    width: i32,
+    height: i32,
+    depth: BitDepth,
+    filename: &str,
+) -> Result<(), image::ImageError> {
+    match depth {
+        BitDepth::Eight => {
+            // The same mapping as Transfer::pixel
+            let data = levels
+                .iter()
+                .map(|level| (level * 127.0 + 128.0).trunc() as u8)
+                .collect();
+            let image =
+                ImageBuffer::<Luma<u8>, Vec<u8>>::from_raw(width as u32, height as u32, data)
+                    .unwrap();
+            image.save(filename)
+        }
+        BitDepth::Sixteen => {
+            let data = levels
+                .iter()
+                .map(|level| (level * 32767.0 + 32768.0).trunc() as u16)
+                .collect();
+            let image =
+                ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width as u32, height as u32, data)
+                    .unwrap();
+            image.save(filename)
+        }
+    }
+}
+
+/// The render subcommand:
+/// `render <program.txt> <output.png> [--width W] [--height H] [--samples N] [--bits 8|16]
+/// [--feature <image.png>]...`, the feature images giving f0, f1, ... in order
+pub fn render_command(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let mut paths = Vec::new();
+    let mut width = None;
+    let mut height = None;
+    let mut samples = 1;
+    let mut depth = BitDepth::Eight;
+    let mut feature_paths = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            paths.push(arg.as_str());
+            continue;
+        }
+
+        if !["--width", "--height", "--samples", "--bits", "--feature"].contains(&arg.as_str()) {
+            return Err(format!("unknown option {}", arg).into());
+        }
+        let value = args
+            .next()
+            .ok_or_else(|| format!("{} needs a value", arg))?;
+        if arg == "--feature" {
+            feature_paths.push(value.as_str());
+            continue;
+        }
+        let number = value
+            .parse::<i32>()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("{} needs a positive number, not '{}'", arg, value))?;
+
+        match arg.as_str() {
+            "--width" => width = Some(number),
+            "--height" => height = Some(number),
+            "--samples" => samples = number,
+            "--bits" => {
+                depth = match number {
+                    8 => BitDepth::Eight,
+                    16 => BitDepth::Sixteen,
+                    _ => return Err("--bits must be 8 or 16".into()),
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    let [program_path, image_path] = paths[..] else {
+        return Err(
+            "usage: render <program.txt> <output.png> [--width W] [--height H] [--samples N] [--bits 8|16] [--feature <image.png>]..."
+                .into(),
+        );
+    };
+
+    let prg = load_program(program_path)?;
+    let features = Features::load(&feature_paths)?;
+    check_features(&prg, features.len())
+        .map_err(|message| format!("{}: {}, give them with --feature", program_path, message))?;
+    let (width, height) = render_size(&prg, width, height)
+        .map_err(|message| format!("{}: {}", program_path, message))?;
+    if width.checked_mul(samples).is_none() || height.checked_mul(samples).is_none() {
+        return Err(format!(
+            "{} x {} pixels with {} samples each way is too large",
+            width, height, samples
+        )
+        .into());
+    }
+    let levels = render_levels(&prg, width, height, samples, &features);
+    save_levels(&levels, width, height, depth, image_path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        myimage::GrayScaleImage,
+        pixapprox::eval_into_image,
+        transfer::{Transfer, TransferFn},
+    };
+
+    #[test]
+    pub fn size_keeps_aspect_ratio() {
+        let mut prg: Program = "x".parse().unwrap();
+        prg.size = Some((200, 100));
+
+        assert_eq!((200, 100), render_size(&prg, None, None).unwrap());
+        assert_eq!((1000, 500), render_size(&prg, Some(1000), None).unwrap());
+        assert_eq!((60, 30), render_size(&prg, None, Some(30)).unwrap());
+        assert_eq!((400, 200), render_size(&prg, Some(400), Some(400)).unwrap());
+        assert_eq!((200, 100), render_size(&prg, Some(400), Some(100)).unwrap());
+
+        prg.size = None;
+        assert!(render_size(&prg, None, None).is_err());
+    }
+
+    #[test]
+    pub fn unknown_options_are_reported() {
+        let args = ["a.txt", "a.png", "--depth", "16"].map(String::from);
+        let error = render_command(&args).unwrap_err();
+
+        assert_eq!("unknown option --depth", error.to_string());
+    }
+
+    #[test]
+    pub fn single_sample_matches_eval_into_image() {
+        let mut prg: Program = "x 3 * sin y 2 * cos *".parse().unwrap();
+        prg.transfer = Transfer::new(TransferFn::Tanh, 1.5);
+        let goal = GrayScaleImage::new(12, 7);
+
+        let levels = render_levels(&prg, 12, 7, 1, &Features::default());
+        let pixels: Vec<u8> = levels
+            .iter()
+            .map(|level| (level * 127.0 + 128.0).trunc() as u8)
+            .collect();
+
+        assert_eq!(eval_into_image(&goal, &prg).data, pixels);
+    }
+
+    #[test]
+    pub fn supersampling_averages() {
+        // x is -1, -0.5, 0 and 0.5 at the four samples of the two pixels
+        let prg: Program = "x".parse().unwrap();
+
+        let levels = render_levels(&prg, 2, 1, 2, &Features::default());
+
+        assert_eq!(vec![-0.75, 0.25], levels);
+    }
+
+    #[test]
+    pub fn features_are_sampled_at_the_render_size() {
+        let mut channel = GrayScaleImage::new(1, 2);
+        channel.write_pixel(0, 1, 255);
+        let features = Features {
+            channels: vec![channel],
+        };
+        let prg: Program = "f0".parse().unwrap();
+
+        let levels = render_levels(&prg, 1, 2, 2, &features);
+
+        assert_eq!(vec![-1.0, 1.0], levels);
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{
+    expr::{Expr, Program},
+    myimage::GrayScaleImage,
+    transfer::Transfer,
+};
+
+/// Linear scaling of the program output: a + b * output
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinearScale {
+    pub a: f32,
+    pub b: f32,
+}
+
+impl LinearScale {
+    /// Fits a and b by least squares so the scaled outputs come as close
+    /// as possible to the outputs the goal pixels ask for.
+    /// Outputs that are not finite are left out of the fit.
+    pub fn fit(outputs: &[f32], goal_image: &GrayScaleImage, transfer: &Transfer) -> Self {
+        let mut n = 0.0f64;
+        let mut sum_f = 0.0f64;
+        let mut sum_t = 0.0f64;
+        let mut sum_ff = 0.0f64;
+        let mut sum_ft = 0.0f64;
+
+        for (&output, &goal) in outputs.iter().zip(goal_image.data.iter()) {
+            if !output.is_finite() {
+                continue;
+            }
+            let f = output as f64;
+            let t = transfer.inverse((goal as f32 - 128.0) / 127.0) as f64;
+
+            n += 1.0;
+            sum_f += f;
+            sum_t += t;
+            sum_ff += f * f;
+            sum_ft += f * t;
+        }
+
+        if n == 0.0 {
+            return Self { a: 0.0, b: 1.0 };
+        }
+
+        let mean_f = sum_f / n;
+        let mean_t = sum_t / n;
+        let var_f = sum_ff / n - mean_f * mean_f;
+        let cov_ft = sum_ft / n - mean_f * mean_t;
+
+        // A flat output can only be moved, not stretched
+        let b = if var_f > 1e-12 { cov_ft / var_f } else { 0.0 };
+        let a = mean_t - b * mean_f;
+
+        Self {
+            a: a as f32,
+            b: b as f32,
+        }
+    }
+
+    /// Scales a single output the same way the folded program does
+    pub fn apply(&self, output: f32) -> f32 {
+        self.a + output * self.b
+    }
+
+    pub fn apply_all(&self, outputs: &mut [f32]) {
+        for output in outputs.iter_mut() {
+            *output = self.apply(*output);
+        }
+    }
+
+    /// Returns the program with the scaling appended as `b * a +`
+    pub fn fold(&self, prg: &Program) -> Program {
+        let mut code = prg.code.clone();
+        code.extend([
+            Expr::Const(self.b),
+            Expr::Mul,
+            Expr::Const(self.a),
+            Expr::Add,
+        ]);
+
+        prg.with_code(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{eval::eval, state::State};
+
+    #[test]
+    pub fn fit_exact_line() {
+        let mut goal_image = GrayScaleImage::new(3, 1);
+        goal_image.data = vec![1, 128, 255];
+        let outputs = [-2.0, 0.0, 2.0];
+
+        let scale = LinearScale::fit(&outputs, &goal_image, &Transfer::default());
+
+        assert!(scale.a.abs() < 1e-6);
+        assert!((scale.b - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    pub fn fold_matches_apply() {
+        let scale = LinearScale { a: 0.25, b: -1.5 };
+        let prg = Program {
+            code: vec![Expr::Var(0), Expr::Sin],
+            ..Default::default()
+        };
+        let folded = scale.fold(&prg);
+
+        let state = State {
+            vars: vec![0.1, 0.0],
+        };
+        assert_eq!(scale.apply(eval(&prg, &state)), eval(&folded, &state));
+    }
+}
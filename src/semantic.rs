@@ -0,0 +1,178 @@
+use crate::{
+    expr::{subtree_start, Expr, Program},
+    myimage::GrayScaleImage,
+    pixapprox::{calc_image_error, eval_into_image, eval_outputs},
+};
+
+/// Tolerances for simplify_semantic
+#[derive(Debug, Clone, Copy)]
+pub struct SemanticTolerance {
+    /// How far a subtree may be from a constant or a variable, at every
+    /// pixel, to be replaced by it
+    pub value: f32,
+    /// How much calc_image_error may change, relative to the error of the
+    /// original program. 0.001 allows a change of 0.1%.
+    pub error: f32,
+}
+
+/// Replaces subtrees that are numerically constant, or equal to one of the
+/// input variables `vars`, over the pixel grid of the goal image.
+///
+/// Such subtrees are not always found by `optimize`, e.g. the sine of a
+/// tiny multiple of x. Each replacement is only kept if the image error
+/// of the whole program stays within `tolerance.error` of the original,
+/// so the error of the result differs at most that much.
+pub fn simplify_semantic(
+    prg: &Program,
+    goal_image: &GrayScaleImage,
+    tolerance: &SemanticTolerance,
+    vars: &[usize],
+) -> Program {
+    let error_of = |prg: &Program| calc_image_error(goal_image, &eval_into_image(goal_image, prg));
+    let original_error = error_of(prg);
+
+    let mut prg = prg.clone();
+
+    // Larger subtrees first, so nothing is sampled inside a replaced one
+    let mut end = prg.code.len();
+    while end > 0 {
+        end -= 1;
+        if prg.code[end].is_leaf() {
+            continue;
+        }
+        let Some(start) = subtree_start(&prg.code, end) else {
+            continue;
+        };
+
+        let subtree = prg.with_code(prg.code[start..=end].to_vec());
+        let outputs = eval_outputs(goal_image, &subtree);
+        let Some(leaf) = equivalent_leaf(&prg, goal_image, &outputs, tolerance.value, vars) else {
+            continue;
+        };
+
+        let mut candidate = prg.clone();
+        candidate.code.splice(start..=end, [leaf]);
+        if (error_of(&candidate) - original_error).abs() <= tolerance.error * original_error {
+            prg = candidate;
+            end = start;
+        }
+    }
+
+    prg
+}
+
+/// Returns a constant or one of `vars` that is within `tolerance` of every
+/// output, the variables sampled with the coordinates of `prg`
+fn equivalent_leaf(
+    prg: &Program,
+    goal_image: &GrayScaleImage,
+    outputs: &[f32],
+    tolerance: f32,
+    vars: &[usize],
+) -> Option<Expr> {
+    if outputs.iter().any(|output| !output.is_finite()) {
+        return None;
+    }
+
+    let min = outputs.iter().copied().fold(f32::MAX, f32::min);
+    let max = outputs.iter().copied().fold(f32::MIN, f32::max);
+    if max - min <= 2.0 * tolerance {
+        return Some(Expr::Const((min + max) * 0.5));
+    }
+
+    vars.iter().map(|&i| Expr::Var(i)).find(|&var| {
+        let values = eval_outputs(goal_image, &prg.with_code(vec![var]));
+        outputs
+            .iter()
+            .zip(values)
+            .all(|(output, value)| (output - value).abs() <= tolerance)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        coords::{Coords, Origin},
+        inputs::{R, X, Y},
+    };
+
+    /// A horizontal gradient to compare against
+    fn goal_image() -> GrayScaleImage {
+        let mut image = GrayScaleImage::new(16, 16);
+        for y in 0..16 {
+            for x in 0..16 {
+                image.write_pixel(x, y, (x * 16) as u8);
+            }
+        }
+        image
+    }
+
+    const TOLERANCE: SemanticTolerance = SemanticTolerance {
+        value: 1e-3,
+        error: 1e-3,
+    };
+
+    #[test]
+    pub fn near_constant_subtree_becomes_constant() {
+        // x 0.3 + y 0.00001 * sin +
+        let prg = Program::new(vec![
+            Expr::Var(0),
+            Expr::Const(0.3),
+            Expr::Add,
+            Expr::Var(1),
+            Expr::Const(0.00001),
+            Expr::Mul,
+            Expr::Sin,
+            Expr::Add,
+        ]);
+
+        let simplified = simplify_semantic(&prg, &goal_image(), &TOLERANCE, &[X, Y]);
+
+        assert_eq!(5, simplified.code.len());
+        assert_eq!(prg.code[..3], simplified.code[..3]);
+        assert!(matches!(simplified.code[3], Expr::Const(_)));
+    }
+
+    #[test]
+    pub fn near_variable_subtree_becomes_variable() {
+        // x 0.00001 + sin
+        let prg = Program::new(vec![
+            Expr::Var(0),
+            Expr::Const(0.00001),
+            Expr::Add,
+            Expr::Sin,
+        ]);
+
+        let simplified = simplify_semantic(&prg, &goal_image(), &TOLERANCE, &[X, Y]);
+
+        assert_eq!("x sin", format!("{}", simplified));
+    }
+
+    #[test]
+    pub fn variables_follow_the_coords_and_inputs() {
+        let mut prg: Program = "x 0.00001 + sin r 0.00001 + *".parse().unwrap();
+        prg.coords = Coords {
+            origin: Origin::TopLeft,
+            ..Coords::default()
+        };
+
+        let simplified = simplify_semantic(&prg, &goal_image(), &TOLERANCE, &[X, Y, R]);
+
+        assert_eq!("@origin topleft\nx sin r *", format!("{}", simplified));
+    }
+
+    #[test]
+    pub fn error_change_is_limited() {
+        // x 0.1 + is within 0.5 of x but renders differently
+        let prg = Program::new(vec![Expr::Var(0), Expr::Const(0.1), Expr::Add]);
+        let tolerance = SemanticTolerance {
+            value: 0.5,
+            error: 1e-3,
+        };
+
+        let simplified = simplify_semantic(&prg, &goal_image(), &tolerance, &[X, Y]);
+
+        assert_eq!("x 0.1 +", format!("{}", simplified));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::eval::PROTECTED_EPSILON;
+
+/// The width of the soft edge of rect and disc, about a pixel at 200 pixels
+pub const SHAPE_EDGE: f32 = 0.01;
+
+// codegen writes the same operations in the same order, keep them in sync
+
+/// Limits the value to 0.0 - 1.0. Unlike clamp NaN becomes 0.0, like
+/// fmax and fmin do in the generated code.
+#[allow(clippy::manual_clamp)]
+fn unit(value: f32) -> f32 {
+    value.max(0.0).min(1.0)
+}
+
+/// 1.0 inside, 0.0 outside and a linear ramp SHAPE_EDGE wide in between,
+/// from the signed distance `inside` to the outline
+pub fn edge(inside: f32) -> f32 {
+    unit(inside / SHAPE_EDGE + 0.5)
+}
+
+/// The rectangle with the half sizes |w| and |h| around the origin
+pub fn rect(x: f32, y: f32, w: f32, h: f32) -> f32 {
+    edge((w.abs() - x.abs()).min(h.abs() - y.abs()))
+}
+
+/// The disc with the radius |r| around the origin
+pub fn disc(x: f32, y: f32, r: f32) -> f32 {
+    edge(r.abs() - (x * x + y * y).sqrt())
+}
+
+/// The distance from (x, y) to the line segment from the origin to (a, b)
+pub fn line_dist(x: f32, y: f32, a: f32, b: f32) -> f32 {
+    let length = a * a + b * b;
+    let t = if length < PROTECTED_EPSILON {
+        0.0
+    } else {
+        unit((x * a + y * b) / length)
+    };
+    let u = x - t * a;
+    let v = y - t * b;
+    (u * u + v * v).sqrt()
+}
+
+/// Hermite interpolation from 0.0 at e0 to 1.0 at e1, a step at e0 if they
+/// are (almost) the same
+pub fn smoothstep(e0: f32, e1: f32, a: f32) -> f32 {
+    let d = e1 - e0;
+    let t = if d.abs() < PROTECTED_EPSILON {
+        if a < e0 {
+            0.0
+        } else {
+            1.0
+        }
+    } else {
+        unit((a - e0) / d)
+    };
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn rect_and_disc_indicators() {
+        assert_eq!(1.0, rect(0.2, -0.3, 0.5, -0.4));
+        assert_eq!(0.0, rect(0.2, 0.5, 0.5, 0.4));
+        assert_eq!(0.5, rect(0.5, 0.0, 0.5, 0.4));
+
+        assert_eq!(1.0, disc(0.3, 0.4, 0.6));
+        assert_eq!(0.5, disc(0.3, 0.4, -0.5));
+        assert_eq!(0.0, disc(0.3, 0.4, 0.45));
+    }
+
+    #[test]
+    pub fn line_dist_to_segment() {
+        assert_eq!(0.5, line_dist(0.5, 0.5, 1.0, 0.0));
+        assert_eq!(1.0, line_dist(2.0, 0.0, 1.0, 0.0));
+        assert_eq!(0.5, line_dist(-0.3, 0.4, 0.0, 0.0));
+    }
+
+    #[test]
+    pub fn smoothstep_like_glsl() {
+        assert_eq!(0.0, smoothstep(0.0, 1.0, -1.0));
+        assert_eq!(0.5, smoothstep(0.0, 1.0, 0.5));
+        assert_eq!(1.0, smoothstep(0.0, 1.0, 2.0));
+        assert_eq!(0.0, smoothstep(0.3, 0.3, 0.2));
+        assert_eq!(1.0, smoothstep(0.3, 0.3, 0.3));
+    }
+}
@@ -0,0 +1,244 @@
+use std::{
+    f32::consts::{FRAC_PI_2, PI},
+    fmt::Display,
+    str::FromStr,
+};
+
+/// Transfer functions never reach exactly -1 or 1, so inverting a goal
+/// level of -1 or 1 is done at this level instead
+const MAX_INVERSE_LEVEL: f32 = 0.999;
+
+/// How the raw program output is mapped to a level from -1.0 to 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransferFn {
+    /// Hard limit to -1.0..1.0
+    Clamp,
+    /// 2 / (1 + e^-x) - 1
+    Sigmoid,
+    Tanh,
+    /// sin(x * pi / 2), identity-like around zero and periodic outside
+    SineWrap,
+    /// Wraps around so 1.0 continues at -1.0
+    Modulo,
+}
+
+impl Display for TransferFn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferFn::Clamp => write!(f, "clamp"),
+            TransferFn::Sigmoid => write!(f, "sigmoid"),
+            TransferFn::Tanh => write!(f, "tanh"),
+            TransferFn::SineWrap => write!(f, "sinewrap"),
+            TransferFn::Modulo => write!(f, "modulo"),
+        }
+    }
+}
+
+impl FromStr for TransferFn {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clamp" => Ok(TransferFn::Clamp),
+            "sigmoid" => Ok(TransferFn::Sigmoid),
+            "tanh" => Ok(TransferFn::Tanh),
+            "sinewrap" => Ok(TransferFn::SineWrap),
+            "modulo" => Ok(TransferFn::Modulo),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The complete mapping from program output to pixel value.
+///
+/// The transfer function gives a level from -1.0 to 1.0, which is then
+/// gamma corrected and rescaled to 0-255.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    pub function: TransferFn,
+    pub gamma: f32,
+}
+
+impl Default for Transfer {
+    fn default() -> Self {
+        Self {
+            function: TransferFn::Clamp,
+            gamma: 1.0,
+        }
+    }
+}
+
+impl Transfer {
+    pub fn new(function: TransferFn, gamma: f32) -> Self {
+        Self { function, gamma }
+    }
+
+    /// Maps a raw program output to a gamma corrected level from -1.0 to 1.0
+    pub fn level(&self, output: f32) -> f32 {
+        let level = match self.function {
+            TransferFn::Clamp => output,
+            TransferFn::Sigmoid => 2.0 / (1.0 + (-output).exp()) - 1.0,
+            TransferFn::Tanh => output.tanh(),
+            TransferFn::SineWrap => (output * FRAC_PI_2).sin(),
+            TransferFn::Modulo => (output + 1.0).rem_euclid(2.0) - 1.0,
+        };
+
+        // Limit the output to stay between -1.0 and 1.0
+        // (min/max instead of clamp so NaN ends up as 1.0)
+        #[allow(clippy::manual_clamp)]
+        let level = level.min(1.0).max(-1.0);
+
+        if self.gamma == 1.0 {
+            level
+        } else {
+            ((level + 1.0) * 0.5).powf(self.gamma) * 2.0 - 1.0
+        }
+    }
+
+    /// Maps a raw program output to a pixel value 0-255
+    pub fn pixel(&self, output: f32) -> u8 {
+        // Rescale the value to be from 0-255
+        let result = self.level(output) * 127.0 + 128.0;
+
+        result.trunc() as u8
+    }
+
+    /// The derivative of level() with respect to the output.
+    /// Zero where the level is limited.
+    pub fn derivative(&self, output: f32) -> f32 {
+        let (level, slope) = match self.function {
+            TransferFn::Clamp => {
+                if output > -1.0 && output < 1.0 {
+                    (output, 1.0)
+                } else {
+                    return 0.0;
+                }
+            }
+            TransferFn::Sigmoid => {
+                let level = 2.0 / (1.0 + (-output).exp()) - 1.0;
+                (level, (1.0 - level * level) * 0.5)
+            }
+            TransferFn::Tanh => {
+                let level = output.tanh();
+                (level, 1.0 - level * level)
+            }
+            TransferFn::SineWrap => {
+                let level = (output * FRAC_PI_2).sin();
+                (level, FRAC_PI_2 * (output * FRAC_PI_2).cos())
+            }
+            TransferFn::Modulo => ((output + 1.0).rem_euclid(2.0) - 1.0, 1.0),
+        };
+
+        if !slope.is_finite() {
+            return 0.0;
+        }
+        if self.gamma == 1.0 {
+            slope
+        } else {
+            let u = ((level + 1.0) * 0.5).max(0.0);
+            self.gamma * u.powf(self.gamma - 1.0) * slope
+        }
+    }
+
+    /// Returns an output that maps to the given level, used to find the
+    /// outputs a goal image asks for
+    pub fn inverse(&self, level: f32) -> f32 {
+        let level = if self.gamma == 1.0 {
+            level
+        } else {
+            ((level + 1.0) * 0.5).max(0.0).powf(1.0 / self.gamma) * 2.0 - 1.0
+        };
+
+        let limited = level.clamp(-MAX_INVERSE_LEVEL, MAX_INVERSE_LEVEL);
+        match self.function {
+            TransferFn::Clamp => level,
+            TransferFn::Sigmoid => 2.0 * limited.atanh(),
+            TransferFn::Tanh => limited.atanh(),
+            TransferFn::SineWrap => level.clamp(-1.0, 1.0).asin() * 2.0 / PI,
+            TransferFn::Modulo => limited,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FUNCTIONS: [TransferFn; 5] = [
+        TransferFn::Clamp,
+        TransferFn::Sigmoid,
+        TransferFn::Tanh,
+        TransferFn::SineWrap,
+        TransferFn::Modulo,
+    ];
+
+    #[test]
+    pub fn clamp_matches_old_mapping() {
+        let transfer = Transfer::default();
+
+        for output in [-3.0, -1.0, -0.5, 0.0, 0.3, 1.0, 7.0, f32::NAN] {
+            #[allow(clippy::manual_clamp)]
+            let old = (output.min(1.0).max(-1.0) * 127.0 + 128.0).trunc() as u8;
+            assert_eq!(old, transfer.pixel(output));
+        }
+    }
+
+    #[test]
+    pub fn inverse_round_trips() {
+        for function in FUNCTIONS {
+            for gamma in [1.0, 0.5, 2.2] {
+                let transfer = Transfer::new(function, gamma);
+                for level in [-0.9, -0.2, 0.0, 0.4, 0.9] {
+                    let back = transfer.level(transfer.inverse(level));
+                    assert!(
+                        (back - level).abs() < 1e-4,
+                        "{} {}: {} became {}",
+                        function,
+                        gamma,
+                        level,
+                        back
+                    );
+                }
+
+                // A goal pixel of 0 is a level below -1. The ends are only
+                // reached up to MAX_INVERSE_LEVEL, which a gamma below 1 stretches
+                for level in [-128.0 / 127.0, 1.0] {
+                    let output = transfer.inverse(level);
+                    let back = transfer.level(output);
+                    assert!(
+                        output.is_finite() && (back - level.clamp(-1.0, 1.0)).abs() < 0.05,
+                        "{} {}: {} became {}",
+                        function,
+                        gamma,
+                        level,
+                        back
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    pub fn derivative_matches_difference() {
+        const H: f32 = 1e-3;
+
+        for function in FUNCTIONS {
+            for gamma in [1.0, 2.2] {
+                let transfer = Transfer::new(function, gamma);
+                for output in [-0.7, -0.1, 0.2, 0.6] {
+                    let numeric =
+                        (transfer.level(output + H) - transfer.level(output - H)) / (2.0 * H);
+                    let exact = transfer.derivative(output);
+                    assert!(
+                        (numeric - exact).abs() < 1e-2,
+                        "{} {}: numeric {} exact {}",
+                        function,
+                        gamma,
+                        numeric,
+                        exact
+                    );
+                }
+            }
+        }
+    }
+}
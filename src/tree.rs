@@ -0,0 +1,355 @@
+use std::fmt::{self, Display};
+
+use rand::{rngs::StdRng, Rng};
+
+use crate::{
+    expr::{Expr, Program},
+    mutate::{random_code, random_leaf, random_op, OpSet},
+};
+
+/// A program as a tree, where each operator owns the subtrees computing its
+/// operands, in the order they are pushed.
+///
+/// A Dup becomes a leaf repeating the value computed just before it, e.g.
+/// `x atan dup *` is (* (atan x) dup). to_code gives back the same code,
+/// but a subtree with a Dup in it only means the same thing in its place.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprTree {
+    pub op: Expr,
+    pub children: Vec<ExprTree>,
+}
+
+impl Display for ExprTree {
+    /// Writes the tree as an s-expression, e.g. (sin (* x 3))
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.children.is_empty() {
+            return write!(f, "{}", self.op);
+        }
+
+        write!(f, "({}", self.op)?;
+        for child in self.children.iter() {
+            write!(f, " {}", child)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl ExprTree {
+    pub fn leaf(op: Expr) -> Self {
+        ExprTree {
+            op,
+            children: Vec::new(),
+        }
+    }
+
+    /// Builds the tree of postfix code.
+    /// Returns None if the code is not well formed or has a Drop.
+    pub fn from_code(code: &[Expr]) -> Option<Self> {
+        let mut stack: Vec<ExprTree> = Vec::new();
+
+        for &expr in code.iter() {
+            let (npop, npush) = expr.stack_effect();
+            if stack.len() < npop {
+                return None;
+            }
+            match (expr, npush) {
+                (Expr::Dup, _) => stack.push(ExprTree::leaf(expr)),
+                (op, 1) => {
+                    let children = stack.split_off(stack.len() - npop);
+                    stack.push(ExprTree { op, children });
+                }
+                _ => return None,
+            }
+        }
+
+        if stack.len() == 1 {
+            stack.pop()
+        } else {
+            None
+        }
+    }
+
+    pub fn from_program(prg: &Program) -> Option<Self> {
+        Self::from_code(&prg.code)
+    }
+
+    /// Returns the postfix code of the tree
+    pub fn to_code(&self) -> Vec<Expr> {
+        let mut code = Vec::with_capacity(self.size());
+        self.push_code(&mut code);
+        code
+    }
+
+    /// Returns a program with the code of the tree and the other settings,
+    /// pool and functions of `prg`
+    pub fn to_program(&self, prg: &Program) -> Program {
+        prg.with_code(self.to_code())
+    }
+
+    fn push_code(&self, code: &mut Vec<Expr>) {
+        for child in self.children.iter() {
+            child.push_code(code);
+        }
+        code.push(self.op);
+    }
+
+    /// The number of nodes, the same as the length of the code
+    pub fn size(&self) -> usize {
+        1 + self
+            .children
+            .iter()
+            .map(|child| child.size())
+            .sum::<usize>()
+    }
+
+    /// The number of levels of operators, 0 for a leaf
+    pub fn depth(&self) -> usize {
+        self.children
+            .iter()
+            .map(|child| child.depth() + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// True if the subtree has a Dup, so it can't be moved elsewhere
+    pub fn has_dup(&self) -> bool {
+        self.op == Expr::Dup || self.children.iter().any(|child| child.has_dup())
+    }
+
+    /// Returns the nodes in preorder, the root being node 0
+    pub fn nodes(&self) -> Vec<&ExprTree> {
+        let mut nodes = Vec::with_capacity(self.size());
+        self.push_nodes(&mut nodes);
+        nodes
+    }
+
+    fn push_nodes<'a>(&'a self, nodes: &mut Vec<&'a ExprTree>) {
+        nodes.push(self);
+        for child in self.children.iter() {
+            child.push_nodes(nodes);
+        }
+    }
+
+    /// Returns node `n` in preorder
+    pub fn get_mut(&mut self, n: usize) -> &mut ExprTree {
+        if n == 0 {
+            return self;
+        }
+
+        let mut rest = n - 1;
+        for child in self.children.iter_mut() {
+            let size = child.size();
+            if rest < size {
+                return child.get_mut(rest);
+            }
+            rest -= size;
+        }
+        panic!("get_mut: node {} is not in the tree", n);
+    }
+
+    /// The number of operators above node `n` in preorder
+    pub fn node_level(&self, n: usize) -> usize {
+        if n == 0 {
+            return 0;
+        }
+
+        let mut rest = n - 1;
+        for child in self.children.iter() {
+            let size = child.size();
+            if rest < size {
+                return 1 + child.node_level(rest);
+            }
+            rest -= size;
+        }
+        panic!("node_level: node {} is not in the tree", n);
+    }
+}
+
+/// Grows a tree of at most `depth` levels of operators, the same way
+/// mutations grow subtrees
+pub fn grow_tree(rng: &mut StdRng, depth: u32, vars: &[usize], ops: &OpSet) -> ExprTree {
+    ExprTree::from_code(&random_code(rng, depth, vars, ops)).expect("grow_tree: not a tree")
+}
+
+/// Grows a tree with every leaf at `depth` levels of operators, from the
+/// unary, binary and ternary operators of the OpSet
+pub fn full_tree(rng: &mut StdRng, depth: u32, vars: &[usize], ops: &OpSet) -> ExprTree {
+    if depth == 0 {
+        return ExprTree::leaf(random_leaf(rng, vars, ops));
+    }
+
+    let op = match rng.gen_range(0..3) {
+        0 => random_op(rng, ops.unary),
+        2 => random_op(rng, ops.ternary),
+        _ => None,
+    };
+    let op = op
+        .or_else(|| random_op(rng, ops.binary))
+        .expect("full_tree: no binary operators");
+
+    ExprTree {
+        op,
+        children: (0..op.arity())
+            .map(|_| full_tree(rng, depth - 1, vars, ops))
+            .collect(),
+    }
+}
+
+/// Replaces a random subtree with a newly grown one. Shapes and noise can
+/// grow deeper than asked, such trees are left unchanged to keep within
+/// `max_depth` levels of operators.
+pub fn subtree_mutate(
+    rng: &mut StdRng,
+    tree: &mut ExprTree,
+    vars: &[usize],
+    ops: &OpSet,
+    max_depth: usize,
+) {
+    let n = rng.gen_range(0..tree.size());
+    let level = tree.node_level(n);
+    let depth = max_depth.saturating_sub(level) as u32;
+
+    let subtree = grow_tree(rng, depth, vars, ops);
+    if level + subtree.depth() <= max_depth {
+        *tree.get_mut(n) = subtree;
+    }
+}
+
+/// Returns `a` with a random subtree replaced by a random subtree of `b`.
+/// Returns an unchanged copy of `a` if the result would be deeper than
+/// `max_depth` or `b` has no subtree without a Dup.
+pub fn crossover(rng: &mut StdRng, a: &ExprTree, b: &ExprTree, max_depth: usize) -> ExprTree {
+    let mut child = a.clone();
+
+    let Some(donor) = pick_node(rng, b, |node| !node.has_dup()) else {
+        return child;
+    };
+    let n = rng.gen_range(0..a.size());
+    if a.node_level(n) + donor.depth() <= max_depth {
+        *child.get_mut(n) = donor.clone();
+    }
+
+    child
+}
+
+/// Promotes a random subtree without a Dup to be the whole tree
+pub fn hoist(rng: &mut StdRng, tree: &mut ExprTree) {
+    if let Some(node) = pick_node(rng, tree, |node| !node.has_dup()) {
+        *tree = node.clone();
+    }
+}
+
+/// Picks a random node satisfying `accept`, or None if there is none
+fn pick_node<'a>(
+    rng: &mut StdRng,
+    tree: &'a ExprTree,
+    accept: impl Fn(&ExprTree) -> bool,
+) -> Option<&'a ExprTree> {
+    let nodes: Vec<&ExprTree> = tree
+        .nodes()
+        .into_iter()
+        .filter(|node| accept(node))
+        .collect();
+    if nodes.is_empty() {
+        None
+    } else {
+        Some(nodes[rng.gen_range(0..nodes.len())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::inputs::{X, Y};
+
+    const OPS: OpSet = OpSet {
+        functions: &[1, 2],
+        ..OpSet::ALL
+    };
+
+    #[test]
+    pub fn random_programs_round_trip() {
+        let mut rng = StdRng::seed_from_u64(1);
+
+        for _ in 0..200 {
+            let code = random_code(&mut rng, 5, &[X, Y], &OPS);
+            let tree = ExprTree::from_code(&code).unwrap();
+
+            assert_eq!(code, tree.to_code());
+            assert_eq!(code.len(), tree.size());
+        }
+    }
+
+    #[test]
+    pub fn programs_round_trip() {
+        let text = "@consts 0.5\n@fn 1 a0 3 * sin\nx atan dup * c0 fn0 +";
+        let prg: Program = text.parse().unwrap();
+        let tree = ExprTree::from_program(&prg).unwrap();
+
+        assert_eq!("(+ (* (atan x) dup) (fn0 c0))", format!("{}", tree));
+        assert_eq!(prg, tree.to_program(&prg));
+        assert_eq!(3, tree.depth());
+        assert!(tree.has_dup());
+    }
+
+    #[test]
+    pub fn malformed_code_is_not_a_tree() {
+        for text in ["x +", "x y", "dup", "x y drop"] {
+            let code: Vec<Expr> = text
+                .split_whitespace()
+                .map(|token| token.parse().unwrap())
+                .collect();
+            assert_eq!(None, ExprTree::from_code(&code), "{}", text);
+        }
+    }
+
+    #[test]
+    pub fn nodes_in_preorder() {
+        let prg: Program = "x 2 * y sin +".parse().unwrap();
+        let mut tree = ExprTree::from_program(&prg).unwrap();
+
+        let ops: Vec<Expr> = tree.nodes().iter().map(|node| node.op).collect();
+        assert_eq!("+ * x 2 sin y", Program::new(ops).to_string());
+        assert_eq!(2, tree.node_level(5));
+
+        *tree.get_mut(4) = ExprTree::leaf(Expr::Const(1.0));
+        assert_eq!("x 2 * 1 +", tree.to_program(&prg).to_string());
+    }
+
+    #[test]
+    pub fn full_trees_have_every_leaf_at_the_depth() {
+        let mut rng = StdRng::seed_from_u64(2);
+
+        for _ in 0..50 {
+            let tree = full_tree(&mut rng, 3, &[X, Y], &OPS);
+            let leaves: Vec<usize> = (0..tree.size())
+                .filter(|&n| tree.nodes()[n].children.is_empty())
+                .map(|n| tree.node_level(n))
+                .collect();
+            assert!(leaves.iter().all(|&level| level == 3), "{}", tree);
+        }
+    }
+
+    #[test]
+    pub fn mutations_keep_trees_within_the_depth() {
+        let mut rng = StdRng::seed_from_u64(3);
+        let max_depth = 6;
+
+        for _ in 0..50 {
+            let mut a = grow_tree(&mut rng, 4, &[X, Y], &OPS);
+            let b = full_tree(&mut rng, 4, &[X, Y], &OPS);
+            for _ in 0..20 {
+                match rng.gen_range(0..3) {
+                    0 => subtree_mutate(&mut rng, &mut a, &[X, Y], &OPS, max_depth),
+                    1 => a = crossover(&mut rng, &a, &b, max_depth),
+                    _ => hoist(&mut rng, &mut a),
+                }
+
+                assert!(a.depth() <= max_depth, "too deep: {}", a);
+                assert!(Program::new(a.to_code()).is_well_formed(), "{}", a);
+            }
+        }
+    }
+}
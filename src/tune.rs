@@ -0,0 +1,250 @@
+use crate::expr::{Expr, Program};
+
+/// The first step tried for a constant, relative to its size
+const RELATIVE_STEP: f32 = 0.1;
+
+/// Steps never start smaller than this, so zero constants can move too
+const MIN_START_STEP: f32 = 0.01;
+
+/// A constant is left alone once its step shrinks below this
+const MIN_STEP: f32 = 1e-5;
+
+/// Adam moment decay rates and the guard against division by zero
+const ADAM_BETA1: f32 = 0.9;
+const ADAM_BETA2: f32 = 0.999;
+const ADAM_EPSILON: f32 = 1e-8;
+
+/// How the constants of the elite are fine-tuned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TuneMethod {
+    /// Derivative free, works directly on calc_image_error
+    CoordinateDescent,
+    /// Gradient descent with exact gradients of a smooth stand-in error
+    Adam,
+}
+
+/// Returns the indices in the code of all constants
+pub fn const_positions(prg: &Program) -> Vec<usize> {
+    prg.code
+        .iter()
+        .enumerate()
+        .filter(|(_, expr)| matches!(expr, Expr::Const(_)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Returns the values of all constants as one vector, the inline ones in
+/// the order of const_positions followed by the pool
+pub fn constants(prg: &Program) -> Vec<f32> {
+    const_positions(prg)
+        .into_iter()
+        .map(|i| const_value(prg, i))
+        .chain(prg.consts.iter().copied())
+        .collect()
+}
+
+/// The reverse of constants
+pub fn set_constants(prg: &mut Program, values: &[f32]) {
+    let positions = const_positions(prg);
+    let (inline, pool) = values.split_at(positions.len());
+
+    for (&i, &x) in positions.iter().zip(inline) {
+        prg.code[i] = Expr::Const(x);
+    }
+    prg.consts.copy_from_slice(pool);
+}
+
+/// Fine-tunes the constants of the program with coordinate descent.
+///
+/// Each constant in turn is moved up or down by its own step size.
+/// An improving step is kept and the step size doubled, otherwise the
+/// step size is halved. `error_fn` is the function being minimized.
+///
+/// Returns the error of the tuned program.
+pub fn tune_constants(prg: &mut Program, error_fn: impl Fn(&Program) -> f32, passes: usize) -> f32 {
+    let mut values = constants(prg);
+    let mut steps: Vec<f32> = values
+        .iter()
+        .map(|x| (x.abs() * RELATIVE_STEP).max(MIN_START_STEP))
+        .collect();
+
+    let mut best_error = error_fn(prg);
+
+    for _ in 0..passes {
+        let mut improved = false;
+
+        for (k, step) in steps.iter_mut().enumerate() {
+            if *step < MIN_STEP {
+                continue;
+            }
+
+            let original = values[k];
+            let mut accepted = false;
+
+            for candidate in [original + *step, original - *step] {
+                values[k] = candidate;
+                set_constants(prg, &values);
+                let error = error_fn(prg);
+                if error < best_error {
+                    best_error = error;
+                    accepted = true;
+                    break;
+                }
+            }
+
+            if accepted {
+                *step *= 2.0;
+                improved = true;
+            } else {
+                values[k] = original;
+                set_constants(prg, &values);
+                *step *= 0.5;
+            }
+        }
+
+        if !improved && steps.iter().all(|step| *step < MIN_STEP) {
+            break;
+        }
+    }
+
+    best_error
+}
+
+/// Fine-tunes the constants of the program with the Adam optimizer.
+///
+/// `error_gradient_fn` returns the error and its gradient with respect to
+/// each constant in the order of `constants`. The constants with the
+/// lowest error seen are kept.
+///
+/// Returns the error of the tuned program.
+pub fn tune_constants_adam(
+    prg: &mut Program,
+    error_gradient_fn: impl Fn(&Program) -> (f32, Vec<f32>),
+    steps: usize,
+    learning_rate: f32,
+) -> f32 {
+    let mut values = constants(prg);
+    let mut m = vec![0.0; values.len()];
+    let mut v = vec![0.0; values.len()];
+
+    let mut best_error = f32::MAX;
+    let mut best_values = values.clone();
+
+    for t in 1..=steps {
+        let (error, gradient) = error_gradient_fn(prg);
+        if error < best_error {
+            best_error = error;
+            best_values.clone_from(&values);
+        }
+
+        let correction1 = 1.0 - ADAM_BETA1.powi(t as i32);
+        let correction2 = 1.0 - ADAM_BETA2.powi(t as i32);
+
+        for (k, x) in values.iter_mut().enumerate() {
+            let g = gradient[k];
+            m[k] = ADAM_BETA1 * m[k] + (1.0 - ADAM_BETA1) * g;
+            v[k] = ADAM_BETA2 * v[k] + (1.0 - ADAM_BETA2) * g * g;
+
+            let m_hat = m[k] / correction1;
+            let v_hat = v[k] / correction2;
+            *x -= learning_rate * m_hat / (v_hat.sqrt() + ADAM_EPSILON);
+        }
+        set_constants(prg, &values);
+    }
+
+    let (error, _) = error_gradient_fn(prg);
+    if error < best_error {
+        best_error = error;
+    } else {
+        set_constants(prg, &best_values);
+    }
+
+    best_error
+}
+
+fn const_value(prg: &Program, i: usize) -> f32 {
+    let Expr::Const(x) = prg.code[i] else {
+        panic!("const_value: instruction {} is not a constant", i);
+    };
+
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn const_positions_test() {
+        let prg = Program {
+            code: vec![Expr::Const(1.0), Expr::Var(0), Expr::Const(2.0), Expr::Add],
+            ..Default::default()
+        };
+
+        assert_eq!(vec![0, 2], const_positions(&prg));
+    }
+
+    #[test]
+    pub fn constants_include_the_pool() {
+        let mut prg: Program = "@consts 0.5 -3\nc1 x * 2 + c0 *".parse().unwrap();
+        assert_eq!(vec![2.0, 0.5, -3.0], constants(&prg));
+
+        set_constants(&mut prg, &[4.0, 1.5, 0.25]);
+        assert_eq!("@consts 1.5 0.25\nc1 x * 4 + c0 *", format!("{}", prg));
+    }
+
+    #[test]
+    pub fn tune_finds_minimum() {
+        // x 1 + 2 * with the error minimized at constants 3 and -0.5
+        let mut prg = Program {
+            code: vec![
+                Expr::Var(0),
+                Expr::Const(1.0),
+                Expr::Add,
+                Expr::Const(2.0),
+                Expr::Mul,
+            ],
+            ..Default::default()
+        };
+
+        let error_fn = |prg: &Program| {
+            let a = const_value(prg, 1);
+            let b = const_value(prg, 3);
+            (a - 3.0).powi(2) + (b + 0.5).powi(2)
+        };
+
+        let error = tune_constants(&mut prg, error_fn, 200);
+
+        assert!(error < 1e-6, "error was {}", error);
+        assert!((const_value(&prg, 1) - 3.0).abs() < 1e-3);
+        assert!((const_value(&prg, 3) + 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    pub fn adam_finds_minimum() {
+        // x 1 + 2 * with the error minimized at constants 3 and -0.5
+        let mut prg = Program {
+            code: vec![
+                Expr::Var(0),
+                Expr::Const(1.0),
+                Expr::Add,
+                Expr::Const(2.0),
+                Expr::Mul,
+            ],
+            ..Default::default()
+        };
+
+        let error_gradient_fn = |prg: &Program| {
+            let a = const_value(prg, 1);
+            let b = const_value(prg, 3);
+            let error = (a - 3.0).powi(2) + (b + 0.5).powi(2);
+            (error, vec![2.0 * (a - 3.0), 2.0 * (b + 0.5)])
+        };
+
+        let error = tune_constants_adam(&mut prg, error_gradient_fn, 2000, 0.05);
+
+        assert!(error < 1e-4, "error was {}", error);
+        assert!((const_value(&prg, 1) - 3.0).abs() < 1e-2);
+        assert!((const_value(&prg, 3) + 0.5).abs() < 1e-2);
+    }
+}
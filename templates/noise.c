@@ -0,0 +1,102 @@
+/* The noise matches pixapprox bit for bit only without fused multiply-add,
+   compilers that ignore this pragma, like GCC, need -ffp-contract=off */
+#pragma STDC FP_CONTRACT OFF
+
+static int32_t noise_lattice(float x)
+{
+    return (int32_t)fmaxf(fminf(floorf(x), 16777216.0f), -16777216.0f);
+}
+
+static uint32_t noise_hash(int32_t ix, int32_t iy, uint32_t seed)
+{
+    uint32_t h = seed ^ ((uint32_t)ix * 0x27d4eb2du) ^ ((uint32_t)iy * 0x165667b1u);
+    h ^= h >> 15;
+    h *= 0x2c1b3c6du;
+    h ^= h >> 12;
+    h *= 0x297a2d39u;
+    h ^= h >> 15;
+    return h;
+}
+
+static float noise_lerp(float a, float b, float t)
+{
+    return a + (b - a) * t;
+}
+
+static float noise_corner(int32_t ix, int32_t iy, uint32_t seed)
+{
+    return (float)(noise_hash(ix, iy, seed) >> 8) / 16777216.0f * 2.0f - 1.0f;
+}
+
+static float noise_value(float x, float y, float s)
+{
+    uint32_t seed = (uint32_t)noise_lattice(s);
+    int32_t ix = noise_lattice(x);
+    int32_t iy = noise_lattice(y);
+    float fx = x - (float)ix;
+    float fy = y - (float)iy;
+    float u = fx * fx * (3.0f - 2.0f * fx);
+    float v = fy * fy * (3.0f - 2.0f * fy);
+    return noise_lerp(
+        noise_lerp(noise_corner(ix, iy, seed), noise_corner(ix + 1, iy, seed), u),
+        noise_lerp(noise_corner(ix, iy + 1, seed), noise_corner(ix + 1, iy + 1, seed), u),
+        v);
+}
+
+static float noise_gradient(uint32_t h, float dx, float dy)
+{
+    return (1.0f - 2.0f * (float)(h & 1u)) * dx + (1.0f - 2.0f * (float)((h >> 1) & 1u)) * dy;
+}
+
+static float noise_perlin_seeded(float x, float y, uint32_t seed)
+{
+    int32_t ix = noise_lattice(x);
+    int32_t iy = noise_lattice(y);
+    float fx = x - (float)ix;
+    float fy = y - (float)iy;
+    float u = fx * fx * fx * (fx * (fx * 6.0f - 15.0f) + 10.0f);
+    float v = fy * fy * fy * (fy * (fy * 6.0f - 15.0f) + 10.0f);
+    float n00 = noise_gradient(noise_hash(ix, iy, seed), fx, fy);
+    float n10 = noise_gradient(noise_hash(ix + 1, iy, seed), fx - 1.0f, fy);
+    float n01 = noise_gradient(noise_hash(ix, iy + 1, seed), fx, fy - 1.0f);
+    float n11 = noise_gradient(noise_hash(ix + 1, iy + 1, seed), fx - 1.0f, fy - 1.0f);
+    return noise_lerp(noise_lerp(n00, n10, u), noise_lerp(n01, n11, u), v);
+}
+
+static float noise_perlin(float x, float y, float s)
+{
+    return noise_perlin_seeded(x, y, (uint32_t)noise_lattice(s));
+}
+
+static float noise_worley(float x, float y, float s)
+{
+    uint32_t seed = (uint32_t)noise_lattice(s);
+    int32_t ix = noise_lattice(x);
+    int32_t iy = noise_lattice(y);
+    float fx = x - (float)ix;
+    float fy = y - (float)iy;
+    float nearest = 8.0f;
+    for (int32_t j = -1; j <= 1; j++) {
+        for (int32_t i = -1; i <= 1; i++) {
+            uint32_t h = noise_hash(ix + i, iy + j, seed);
+            float dx = (float)i + (float)(h & 0xffffu) / 65536.0f - fx;
+            float dy = (float)j + (float)(h >> 16) / 65536.0f - fy;
+            nearest = fminf(nearest, dx * dx + dy * dy);
+        }
+    }
+    return sqrtf(nearest);
+}
+
+static float noise_fbm(float x, float y, float s)
+{
+    uint32_t seed = (uint32_t)noise_lattice(s);
+    float sum = 0.0f;
+    float amplitude = 0.5f;
+    float frequency = 1.0f;
+    for (uint32_t octave = 0; octave < 4; octave++) {
+        sum += amplitude * noise_perlin_seeded(x * frequency, y * frequency, seed + octave);
+        amplitude *= 0.5f;
+        frequency *= 2.0f;
+    }
+    return sum / 0.9375f;
+}
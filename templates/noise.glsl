@@ -0,0 +1,88 @@
+int noise_lattice(float x) {
+    return int(max(min(floor(x), 16777216.0), -16777216.0));
+}
+
+uint noise_hash(int ix, int iy, uint seed) {
+    uint h = seed ^ (uint(ix) * 0x27d4eb2du) ^ (uint(iy) * 0x165667b1u);
+    h ^= h >> 15u;
+    h *= 0x2c1b3c6du;
+    h ^= h >> 12u;
+    h *= 0x297a2d39u;
+    h ^= h >> 15u;
+    return h;
+}
+
+float noise_lerp(float a, float b, float t) {
+    return a + (b - a) * t;
+}
+
+float noise_corner(int ix, int iy, uint seed) {
+    return float(noise_hash(ix, iy, seed) >> 8u) / 16777216.0 * 2.0 - 1.0;
+}
+
+float noise_value(float x, float y, float s) {
+    uint seed = uint(noise_lattice(s));
+    int ix = noise_lattice(x);
+    int iy = noise_lattice(y);
+    float fx = x - float(ix);
+    float fy = y - float(iy);
+    float u = fx * fx * (3.0 - 2.0 * fx);
+    float v = fy * fy * (3.0 - 2.0 * fy);
+    return noise_lerp(
+        noise_lerp(noise_corner(ix, iy, seed), noise_corner(ix + 1, iy, seed), u),
+        noise_lerp(noise_corner(ix, iy + 1, seed), noise_corner(ix + 1, iy + 1, seed), u),
+        v);
+}
+
+float noise_gradient(uint h, float dx, float dy) {
+    return (1.0 - 2.0 * float(h & 1u)) * dx + (1.0 - 2.0 * float((h >> 1u) & 1u)) * dy;
+}
+
+float noise_perlin_seeded(float x, float y, uint seed) {
+    int ix = noise_lattice(x);
+    int iy = noise_lattice(y);
+    float fx = x - float(ix);
+    float fy = y - float(iy);
+    float u = fx * fx * fx * (fx * (fx * 6.0 - 15.0) + 10.0);
+    float v = fy * fy * fy * (fy * (fy * 6.0 - 15.0) + 10.0);
+    float n00 = noise_gradient(noise_hash(ix, iy, seed), fx, fy);
+    float n10 = noise_gradient(noise_hash(ix + 1, iy, seed), fx - 1.0, fy);
+    float n01 = noise_gradient(noise_hash(ix, iy + 1, seed), fx, fy - 1.0);
+    float n11 = noise_gradient(noise_hash(ix + 1, iy + 1, seed), fx - 1.0, fy - 1.0);
+    return noise_lerp(noise_lerp(n00, n10, u), noise_lerp(n01, n11, u), v);
+}
+
+float noise_perlin(float x, float y, float s) {
+    return noise_perlin_seeded(x, y, uint(noise_lattice(s)));
+}
+
+float noise_worley(float x, float y, float s) {
+    uint seed = uint(noise_lattice(s));
+    int ix = noise_lattice(x);
+    int iy = noise_lattice(y);
+    float fx = x - float(ix);
+    float fy = y - float(iy);
+    float nearest = 8.0;
+    for (int j = -1; j <= 1; j++) {
+        for (int i = -1; i <= 1; i++) {
+            uint h = noise_hash(ix + i, iy + j, seed);
+            float dx = float(i) + float(h & 0xffffu) / 65536.0 - fx;
+            float dy = float(j) + float(h >> 16u) / 65536.0 - fy;
+            nearest = min(nearest, dx * dx + dy * dy);
+        }
+    }
+    return sqrt(nearest);
+}
+
+float noise_fbm(float x, float y, float s) {
+    uint seed = uint(noise_lattice(s));
+    float sum = 0.0;
+    float amplitude = 0.5;
+    float frequency = 1.0;
+    for (uint octave = 0u; octave < 4u; octave++) {
+        sum += amplitude * noise_perlin_seeded(x * frequency, y * frequency, seed + octave);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return sum / 0.9375;
+}
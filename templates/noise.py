@@ -0,0 +1,94 @@
+def noise_lattice(x):
+    limit = np.float32(16777216.0)
+    return np.fmax(np.fmin(np.floor(x), limit), -limit).astype(np.int32)
+
+
+def noise_hash(ix, iy, seed):
+    # uint32 arithmetic wraps around like in the other languages
+    with np.errstate(over="ignore"):
+        h = seed ^ (ix.astype(np.uint32) * np.uint32(0x27D4EB2D)) ^ (iy.astype(np.uint32) * np.uint32(0x165667B1))
+        h = h ^ (h >> np.uint32(15))
+        h = h * np.uint32(0x2C1B3C6D)
+        h = h ^ (h >> np.uint32(12))
+        h = h * np.uint32(0x297A2D39)
+        h = h ^ (h >> np.uint32(15))
+    return h
+
+
+def noise_lerp(a, b, t):
+    return a + (b - a) * t
+
+
+def noise_corner(ix, iy, seed):
+    h = noise_hash(ix, iy, seed) >> np.uint32(8)
+    return h.astype(np.float32) / np.float32(16777216.0) * np.float32(2.0) - np.float32(1.0)
+
+
+def noise_value(x, y, s):
+    seed = noise_lattice(s).astype(np.uint32)
+    ix = noise_lattice(x)
+    iy = noise_lattice(y)
+    fx = x - ix.astype(np.float32)
+    fy = y - iy.astype(np.float32)
+    u = fx * fx * (np.float32(3.0) - np.float32(2.0) * fx)
+    v = fy * fy * (np.float32(3.0) - np.float32(2.0) * fy)
+    return noise_lerp(
+        noise_lerp(noise_corner(ix, iy, seed), noise_corner(ix + 1, iy, seed), u),
+        noise_lerp(noise_corner(ix, iy + 1, seed), noise_corner(ix + 1, iy + 1, seed), u),
+        v,
+    )
+
+
+def noise_gradient(h, dx, dy):
+    gx = np.float32(1.0) - np.float32(2.0) * (h & np.uint32(1)).astype(np.float32)
+    gy = np.float32(1.0) - np.float32(2.0) * ((h >> np.uint32(1)) & np.uint32(1)).astype(np.float32)
+    return gx * dx + gy * dy
+
+
+def noise_perlin_seeded(x, y, seed):
+    ix = noise_lattice(x)
+    iy = noise_lattice(y)
+    fx = x - ix.astype(np.float32)
+    fy = y - iy.astype(np.float32)
+    u = fx * fx * fx * (fx * (fx * np.float32(6.0) - np.float32(15.0)) + np.float32(10.0))
+    v = fy * fy * fy * (fy * (fy * np.float32(6.0) - np.float32(15.0)) + np.float32(10.0))
+    one = np.float32(1.0)
+    n00 = noise_gradient(noise_hash(ix, iy, seed), fx, fy)
+    n10 = noise_gradient(noise_hash(ix + 1, iy, seed), fx - one, fy)
+    n01 = noise_gradient(noise_hash(ix, iy + 1, seed), fx, fy - one)
+    n11 = noise_gradient(noise_hash(ix + 1, iy + 1, seed), fx - one, fy - one)
+    return noise_lerp(noise_lerp(n00, n10, u), noise_lerp(n01, n11, u), v)
+
+
+def noise_perlin(x, y, s):
+    return noise_perlin_seeded(x, y, noise_lattice(s).astype(np.uint32))
+
+
+def noise_worley(x, y, s):
+    seed = noise_lattice(s).astype(np.uint32)
+    ix = noise_lattice(x)
+    iy = noise_lattice(y)
+    fx = x - ix.astype(np.float32)
+    fy = y - iy.astype(np.float32)
+    nearest = np.float32(8.0)
+    for j in range(-1, 2):
+        for i in range(-1, 2):
+            h = noise_hash(ix + i, iy + j, seed)
+            dx = np.float32(i) + (h & np.uint32(0xFFFF)).astype(np.float32) / np.float32(65536.0) - fx
+            dy = np.float32(j) + (h >> np.uint32(16)).astype(np.float32) / np.float32(65536.0) - fy
+            nearest = np.fmin(nearest, dx * dx + dy * dy)
+    return np.sqrt(nearest)
+
+
+def noise_fbm(x, y, s):
+    seed = noise_lattice(s).astype(np.uint32)
+    total = np.float32(0.0)
+    amplitude = np.float32(0.5)
+    frequency = np.float32(1.0)
+    for octave in range(4):
+        with np.errstate(over="ignore"):
+            octave_seed = seed + np.uint32(octave)
+        total = total + amplitude * noise_perlin_seeded(x * frequency, y * frequency, octave_seed)
+        amplitude = amplitude * np.float32(0.5)
+        frequency = frequency * np.float32(2.0)
+    return total / np.float32(0.9375)
@@ -0,0 +1,89 @@
+fn noise_lattice(x: f32) -> i32 {
+    x.floor().min(16777216.0).max(-16777216.0) as i32
+}
+
+fn noise_hash(ix: i32, iy: i32, seed: u32) -> u32 {
+    let mut h = seed ^ (ix as u32).wrapping_mul(0x27d4eb2d) ^ (iy as u32).wrapping_mul(0x165667b1);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a2d39);
+    h ^= h >> 15;
+    h
+}
+
+fn noise_lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+fn noise_corner(ix: i32, iy: i32, seed: u32) -> f32 {
+    (noise_hash(ix, iy, seed) >> 8) as f32 / 16777216.0 * 2.0 - 1.0
+}
+
+fn noise_value(x: f32, y: f32, s: f32) -> f32 {
+    let seed = noise_lattice(s) as u32;
+    let ix = noise_lattice(x);
+    let iy = noise_lattice(y);
+    let fx = x - ix as f32;
+    let fy = y - iy as f32;
+    let u = fx * fx * (3.0 - 2.0 * fx);
+    let v = fy * fy * (3.0 - 2.0 * fy);
+    noise_lerp(
+        noise_lerp(noise_corner(ix, iy, seed), noise_corner(ix + 1, iy, seed), u),
+        noise_lerp(noise_corner(ix, iy + 1, seed), noise_corner(ix + 1, iy + 1, seed), u),
+        v,
+    )
+}
+
+fn noise_gradient(h: u32, dx: f32, dy: f32) -> f32 {
+    (1.0 - 2.0 * (h & 1) as f32) * dx + (1.0 - 2.0 * ((h >> 1) & 1) as f32) * dy
+}
+
+fn noise_perlin_seeded(x: f32, y: f32, seed: u32) -> f32 {
+    let ix = noise_lattice(x);
+    let iy = noise_lattice(y);
+    let fx = x - ix as f32;
+    let fy = y - iy as f32;
+    let u = fx * fx * fx * (fx * (fx * 6.0 - 15.0) + 10.0);
+    let v = fy * fy * fy * (fy * (fy * 6.0 - 15.0) + 10.0);
+    let n00 = noise_gradient(noise_hash(ix, iy, seed), fx, fy);
+    let n10 = noise_gradient(noise_hash(ix + 1, iy, seed), fx - 1.0, fy);
+    let n01 = noise_gradient(noise_hash(ix, iy + 1, seed), fx, fy - 1.0);
+    let n11 = noise_gradient(noise_hash(ix + 1, iy + 1, seed), fx - 1.0, fy - 1.0);
+    noise_lerp(noise_lerp(n00, n10, u), noise_lerp(n01, n11, u), v)
+}
+
+fn noise_perlin(x: f32, y: f32, s: f32) -> f32 {
+    noise_perlin_seeded(x, y, noise_lattice(s) as u32)
+}
+
+fn noise_worley(x: f32, y: f32, s: f32) -> f32 {
+    let seed = noise_lattice(s) as u32;
+    let ix = noise_lattice(x);
+    let iy = noise_lattice(y);
+    let fx = x - ix as f32;
+    let fy = y - iy as f32;
+    let mut nearest: f32 = 8.0;
+    for j in -1..=1 {
+        for i in -1..=1 {
+            let h = noise_hash(ix + i, iy + j, seed);
+            let dx = i as f32 + (h & 0xffff) as f32 / 65536.0 - fx;
+            let dy = j as f32 + (h >> 16) as f32 / 65536.0 - fy;
+            nearest = nearest.min(dx * dx + dy * dy);
+        }
+    }
+    nearest.sqrt()
+}
+
+fn noise_fbm(x: f32, y: f32, s: f32) -> f32 {
+    let seed = noise_lattice(s) as u32;
+    let mut sum = 0.0;
+    let mut amplitude = 0.5;
+    let mut frequency = 1.0;
+    for octave in 0..4 {
+        sum += amplitude * noise_perlin_seeded(x * frequency, y * frequency, seed.wrapping_add(octave));
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    sum / 0.9375
+}
@@ -0,0 +1,88 @@
+fn noise_lattice(x: f32) -> i32 {
+    return i32(max(min(floor(x), 16777216.0), -16777216.0));
+}
+
+fn noise_hash(ix: i32, iy: i32, seed: u32) -> u32 {
+    var h = seed ^ (u32(ix) * 0x27d4eb2du) ^ (u32(iy) * 0x165667b1u);
+    h ^= h >> 15u;
+    h *= 0x2c1b3c6du;
+    h ^= h >> 12u;
+    h *= 0x297a2d39u;
+    h ^= h >> 15u;
+    return h;
+}
+
+fn noise_lerp(a: f32, b: f32, t: f32) -> f32 {
+    return a + (b - a) * t;
+}
+
+fn noise_corner(ix: i32, iy: i32, seed: u32) -> f32 {
+    return f32(noise_hash(ix, iy, seed) >> 8u) / 16777216.0 * 2.0 - 1.0;
+}
+
+fn noise_value(x: f32, y: f32, s: f32) -> f32 {
+    let seed = u32(noise_lattice(s));
+    let ix = noise_lattice(x);
+    let iy = noise_lattice(y);
+    let fx = x - f32(ix);
+    let fy = y - f32(iy);
+    let u = fx * fx * (3.0 - 2.0 * fx);
+    let v = fy * fy * (3.0 - 2.0 * fy);
+    return noise_lerp(
+        noise_lerp(noise_corner(ix, iy, seed), noise_corner(ix + 1, iy, seed), u),
+        noise_lerp(noise_corner(ix, iy + 1, seed), noise_corner(ix + 1, iy + 1, seed), u),
+        v);
+}
+
+fn noise_gradient(h: u32, dx: f32, dy: f32) -> f32 {
+    return (1.0 - 2.0 * f32(h & 1u)) * dx + (1.0 - 2.0 * f32((h >> 1u) & 1u)) * dy;
+}
+
+fn noise_perlin_seeded(x: f32, y: f32, seed: u32) -> f32 {
+    let ix = noise_lattice(x);
+    let iy = noise_lattice(y);
+    let fx = x - f32(ix);
+    let fy = y - f32(iy);
+    let u = fx * fx * fx * (fx * (fx * 6.0 - 15.0) + 10.0);
+    let v = fy * fy * fy * (fy * (fy * 6.0 - 15.0) + 10.0);
+    let n00 = noise_gradient(noise_hash(ix, iy, seed), fx, fy);
+    let n10 = noise_gradient(noise_hash(ix + 1, iy, seed), fx - 1.0, fy);
+    let n01 = noise_gradient(noise_hash(ix, iy + 1, seed), fx, fy - 1.0);
+    let n11 = noise_gradient(noise_hash(ix + 1, iy + 1, seed), fx - 1.0, fy - 1.0);
+    return noise_lerp(noise_lerp(n00, n10, u), noise_lerp(n01, n11, u), v);
+}
+
+fn noise_perlin(x: f32, y: f32, s: f32) -> f32 {
+    return noise_perlin_seeded(x, y, u32(noise_lattice(s)));
+}
+
+fn noise_worley(x: f32, y: f32, s: f32) -> f32 {
+    let seed = u32(noise_lattice(s));
+    let ix = noise_lattice(x);
+    let iy = noise_lattice(y);
+    let fx = x - f32(ix);
+    let fy = y - f32(iy);
+    var nearest: f32 = 8.0;
+    for (var j = -1; j <= 1; j += 1) {
+        for (var i = -1; i <= 1; i += 1) {
+            let h = noise_hash(ix + i, iy + j, seed);
+            let dx = f32(i) + f32(h & 0xffffu) / 65536.0 - fx;
+            let dy = f32(j) + f32(h >> 16u) / 65536.0 - fy;
+            nearest = min(nearest, dx * dx + dy * dy);
+        }
+    }
+    return sqrt(nearest);
+}
+
+fn noise_fbm(x: f32, y: f32, s: f32) -> f32 {
+    let seed = u32(noise_lattice(s));
+    var sum: f32 = 0.0;
+    var amplitude: f32 = 0.5;
+    var frequency: f32 = 1.0;
+    for (var octave = 0u; octave < 4u; octave += 1u) {
+        sum += amplitude * noise_perlin_seeded(x * frequency, y * frequency, seed + octave);
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    return sum / 0.9375;
+}
@@ -0,0 +1,28 @@
+// Generated by pixapprox from: x y / abs sqrt y exp log x sin tanh - * x step y floor + x fract 0.3 mod * x y atan2 y -0.5 + select +
+pub fn pixapprox(x: f32, y: f32) -> f32 {
+    use std::f32::consts::TAU;
+
+    let v0 = x;
+    let v1 = y;
+    let v2 = if v1.abs() < 1e-6_f32 { 1.0_f32 } else { v0 / v1 };
+    let v3 = v2.abs();
+    let v4 = v3.abs().sqrt();
+    let v5 = v1.exp();
+    let v6 = if v5.abs() < 1e-6_f32 { 0.0_f32 } else { v5.abs().ln() };
+    let v7 = (v0 * TAU).sin();
+    let v8 = v7.tanh();
+    let v9 = v6 - v8;
+    let v10 = v9 * v4;
+    let v11 = if v0 < 0.0_f32 { 0.0_f32 } else { 1.0_f32 };
+    let v12 = v1.floor();
+    let v13 = v12 + v11;
+    let v14 = v0 - v0.floor();
+    let v15 = 0.3_f32;
+    let v16 = if v15.abs() < 1e-6_f32 { 0.0_f32 } else { v14 - v15 * (v14 / v15).floor() };
+    let v17 = v16 * v13;
+    let v18 = v0.atan2(v1);
+    let v19 = -0.5_f32;
+    let v20 = v19 + v1;
+    let v21 = if v17 > 0.0_f32 { v18 } else { v20 };
+    v21 + v10
+}
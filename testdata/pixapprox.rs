@@ -0,0 +1,20 @@
+// Generated by pixapprox from: x 3.2 * sin y -0.5 * + y x max atan * x y min cos - x 3.2 * sin +
+pub fn pixapprox(x: f32, y: f32) -> f32 {
+    use std::f32::consts::TAU;
+
+    let v0 = x;
+    let v1 = 3.2_f32;
+    let v2 = v1 * v0;
+    let v3 = (v2 * TAU).sin();
+    let v4 = y;
+    let v5 = -0.5_f32;
+    let v6 = v5 * v4;
+    let v7 = v6 + v3;
+    let v8 = v0.max(v4);
+    let v9 = v8.atan();
+    let v10 = v9 * v7;
+    let v11 = v4.min(v0);
+    let v12 = (v11 * TAU).cos();
+    let v13 = v10 - v12;
+    v13 + v3
+}
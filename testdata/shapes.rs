@@ -0,0 +1,36 @@
+// Generated by pixapprox from: x 0.3 - y 0.1 + 0.4 0.2 rect x y 0.5 disc + x 0.2 + y 0.7 -0.4 linedist -0.3 0.6 y smoothstep * + x sin +
+pub fn pixapprox(x: f32, y: f32) -> f32 {
+    use std::f32::consts::TAU;
+
+    let v0 = x;
+    let v1 = 0.3_f32;
+    let v2 = v0 - v1;
+    let v3 = y;
+    let v4 = 0.1_f32;
+    let v5 = v4 + v3;
+    let v6 = 0.4_f32;
+    let v7 = 0.2_f32;
+    let v8_inside = (v6.abs() - v2.abs()).min(v7.abs() - v5.abs());
+    let v8 = (v8_inside / 0.01_f32 + 0.5_f32).max(0.0_f32).min(1.0_f32);
+    let v9 = 0.5_f32;
+    let v10_inside = v9.abs() - (v0 * v0 + v3 * v3).sqrt();
+    let v10 = (v10_inside / 0.01_f32 + 0.5_f32).max(0.0_f32).min(1.0_f32);
+    let v11 = v10 + v8;
+    let v12 = v7 + v0;
+    let v13 = 0.7_f32;
+    let v14 = -0.4_f32;
+    let v15_length = v13 * v13 + v14 * v14;
+    let v15_t = if v15_length < 1e-6_f32 { 0.0_f32 } else { ((v12 * v13 + v3 * v14) / v15_length).max(0.0_f32).min(1.0_f32) };
+    let v15_u = v12 - v15_t * v13;
+    let v15_v = v3 - v15_t * v14;
+    let v15 = (v15_u * v15_u + v15_v * v15_v).sqrt();
+    let v16 = -0.3_f32;
+    let v17 = 0.6_f32;
+    let v18_d = v17 - v16;
+    let v18_t = if v18_d.abs() < 1e-6_f32 { if v3 < v16 { 0.0_f32 } else { 1.0_f32 } } else { ((v3 - v16) / v18_d).max(0.0_f32).min(1.0_f32) };
+    let v18 = v18_t * v18_t * (3.0_f32 - 2.0_f32 * v18_t);
+    let v19 = v18 * v15;
+    let v20 = v19 + v11;
+    let v21 = (v0 * TAU).sin();
+    v21 + v20
+}